@@ -0,0 +1,180 @@
+use std::{
+    fmt,
+    fs::OpenOptions,
+    io::{self, LineWriter, Write},
+    net::IpAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Instant,
+};
+
+use anyhow::Result;
+use futures_lite::io::{AsyncBufRead, AsyncRead};
+use http_types::{headers::CONTENT_TYPE, Body, Method, Response, Url};
+use serde::Deserialize;
+use time::OffsetDateTime;
+use tracing::error;
+
+#[derive(Deserialize, Clone, Copy, Default, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    Common,
+    Json,
+}
+
+/// the upstream url of a proxied response, set by the handler
+#[derive(Clone)]
+pub struct Upstream(pub Url);
+
+pub struct Entry {
+    pub time: OffsetDateTime,
+    pub start: Instant,
+    pub client_ip: Option<IpAddr>,
+    pub method: Method,
+    pub url: Url,
+    pub upstream: Option<Url>,
+    pub status: Option<u16>,
+    pub bytes: u64,
+}
+
+pub struct AccessLog {
+    format: Format,
+    writer: Mutex<Box<dyn Write + Send>>,
+}
+
+impl fmt::Debug for AccessLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AccessLog")
+            .field("format", &self.format)
+            .finish()
+    }
+}
+
+impl AccessLog {
+    /// log to the file at `path`, or stdout
+    pub fn new(format: Format, path: Option<&str>) -> Result<AccessLog> {
+        let writer: Box<dyn Write + Send> = match path {
+            Some(path) => Box::new(LineWriter::new(
+                OpenOptions::new().create(true).append(true).open(path)?,
+            )),
+            None => Box::new(io::stdout()),
+        };
+        Ok(AccessLog {
+            format,
+            writer: Mutex::new(writer),
+        })
+    }
+
+    /// log the entry once the response body has been sent
+    pub fn record(self: &Arc<Self>, mut entry: Entry, resp: &mut Response) {
+        entry.status = Some(resp.status().into());
+        entry.upstream = resp.ext().get::<Upstream>().map(|i| i.0.clone());
+        let has_content_type = resp.header(CONTENT_TYPE).is_some();
+        let body = resp.take_body();
+        let len = body.len();
+        let counter = Counter {
+            body,
+            entry: Some(entry),
+            log: self.clone(),
+        };
+        resp.set_body(Body::from_reader(counter, len));
+        if !has_content_type {
+            resp.remove_header(CONTENT_TYPE);
+        }
+    }
+
+    pub fn write(&self, entry: &Entry) {
+        let line = match self.format {
+            Format::Common => common(entry),
+            Format::Json => json(entry),
+        };
+        let mut writer = match self.writer.lock() {
+            Ok(writer) => writer,
+            Err(e) => e.into_inner(),
+        };
+        if let Err(e) = writeln!(writer, "{}", line) {
+            error!("can not write access log: {}", e);
+        }
+    }
+}
+
+/// count body bytes and write the log entry when the body is dropped, the
+/// body is read through without another buffer
+struct Counter {
+    body: Body,
+    entry: Option<Entry>,
+    log: Arc<AccessLog>,
+}
+
+impl AsyncRead for Counter {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.body).poll_read(cx, buf);
+        if let Poll::Ready(Ok(n)) = poll {
+            if let Some(entry) = &mut self.entry {
+                entry.bytes += n as u64;
+            }
+        }
+        poll
+    }
+}
+
+impl AsyncBufRead for Counter {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        Pin::new(&mut self.get_mut().body).poll_fill_buf(cx)
+    }
+
+    fn consume(mut self: Pin<&mut Self>, amt: usize) {
+        if let Some(entry) = &mut self.entry {
+            entry.bytes += amt as u64;
+        }
+        Pin::new(&mut self.body).consume(amt)
+    }
+}
+
+impl Drop for Counter {
+    fn drop(&mut self) {
+        if let Some(entry) = self.entry.take() {
+            self.log.write(&entry);
+        }
+    }
+}
+
+fn common(entry: &Entry) -> String {
+    format!(
+        "{} - - [{}] \"{} {}\" {} {} \"{}\" {}ms",
+        entry
+            .client_ip
+            .map(|i| i.to_string())
+            .unwrap_or_else(|| "-".to_string()),
+        entry.time.format("%d/%b/%Y:%H:%M:%S %z"),
+        entry.method,
+        entry.url,
+        entry
+            .status
+            .map(|i| i.to_string())
+            .unwrap_or_else(|| "-".to_string()),
+        entry.bytes,
+        entry.upstream.as_ref().map(|i| i.as_str()).unwrap_or("-"),
+        entry.start.elapsed().as_millis(),
+    )
+}
+
+fn json(entry: &Entry) -> String {
+    serde_json::json!({
+        "time": entry.time.format(time::Format::Rfc3339),
+        "client_ip": entry.client_ip,
+        "method": entry.method.to_string(),
+        "url": entry.url.as_str(),
+        "upstream": entry.upstream.as_ref().map(|i| i.as_str()),
+        "status": entry.status,
+        "duration_ms": entry.start.elapsed().as_millis() as u64,
+        "bytes": entry.bytes,
+    })
+    .to_string()
+}
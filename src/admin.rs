@@ -0,0 +1,223 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Instant,
+};
+
+use http_types::{Method, Request, Response, StatusCode, Url};
+use serde_json::{json, Value};
+use tracing::{error, info};
+
+use crate::{
+    cache,
+    config::Admin,
+    server::{Current, Forward},
+    session,
+};
+
+/// request counters of the mirror listener
+#[derive(Debug)]
+pub struct Stats {
+    start: Instant,
+    requests: AtomicU64,
+    active: AtomicU64,
+    errors: AtomicU64,
+    /// responses by status class, 1xx to 5xx
+    status: [AtomicU64; 5],
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        Stats {
+            start: Instant::now(),
+            requests: AtomicU64::new(0),
+            active: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            status: Default::default(),
+        }
+    }
+
+    /// count a request, which stays active until the guard is dropped
+    pub fn begin(&self) -> Active<'_> {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.active.fetch_add(1, Ordering::Relaxed);
+        Active(self)
+    }
+
+    pub fn response(&self, status: StatusCode) {
+        let class = u16::from(status) as usize / 100;
+        if let Some(i) = self.status.get(class.wrapping_sub(1)) {
+            i.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn to_json(&self) -> Value {
+        let status: serde_json::Map<_, _> = self
+            .status
+            .iter()
+            .enumerate()
+            .map(|(i, n)| (format!("{}xx", i + 1), n.load(Ordering::Relaxed).into()))
+            .collect();
+        json!({
+            "uptime_secs": self.start.elapsed().as_secs(),
+            "requests": self.requests.load(Ordering::Relaxed),
+            "active": self.active.load(Ordering::Relaxed),
+            "errors": self.errors.load(Ordering::Relaxed),
+            "status": status,
+        })
+    }
+}
+
+pub struct Active<'a>(&'a Stats);
+
+impl Drop for Active<'_> {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// requests of the admin listener, authenticated with its own bearer token
+pub async fn handle(admin: &Admin, req: Request, forward: &Forward, current: &Current) -> Response {
+    let config = &forward.config;
+    let db = &forward.db;
+    let cache = forward.cache.as_ref();
+    let maintenance = &forward.maintenance;
+    let token = req
+        .header("Authorization")
+        .and_then(|i| i.as_str().strip_prefix("Bearer "));
+    if !token.is_some_and(|token| secure_eq(token.as_bytes(), admin.token.as_bytes())) {
+        let mut resp = reply(StatusCode::Unauthorized, json!({ "error": "unauthorized" }));
+        resp.insert_header("WWW-Authenticate", "Bearer");
+        return resp;
+    }
+
+    let path = req.url().path().trim_end_matches('/');
+    let result = match (req.method(), path) {
+        (Method::Get, "/stats") => Ok(forward.stats.to_json()),
+        (Method::Get, "/sessions") => session::list(db).map(|sessions| {
+            sessions
+                .into_iter()
+                .map(|(token, user)| json!({ "token": token, "user": user }))
+                .collect()
+        }),
+        (Method::Delete, "/sessions") => match req.url().query_pairs().find(|(k, _)| k == "user") {
+            Some((_, user)) => session::remove(db, None, Some(&user)).map(|removed| {
+                info!("admin removed {} sessions of {}", removed, user);
+                json!({ "removed": removed })
+            }),
+            None => return reply(StatusCode::BadRequest, json!({ "error": "missing user" })),
+        },
+        (Method::Delete, path) if path.starts_with("/sessions/") => {
+            let token = &path["/sessions/".len()..];
+            session::remove(db, Some(token), None).map(|removed| json!({ "removed": removed }))
+        }
+        (Method::Delete, "/cache") => {
+            let cache = match cache {
+                Some(cache) => cache,
+                None => return reply(StatusCode::NotFound, json!({ "error": "cache disabled" })),
+            };
+            let query = |name: &str| {
+                req.url()
+                    .query_pairs()
+                    .find(|(k, _)| k == name)
+                    .map(|(_, v)| v.into_owned())
+            };
+            // cache keys are upstream urls, translated as requests are
+            let key = match query("url").map(|url| Url::parse(&url)) {
+                Some(Ok(mut url)) => {
+                    let host = url.host_str().unwrap_or_default().to_string();
+                    if !config.is_mirror(&host) {
+                        return reply(
+                            StatusCode::BadRequest,
+                            json!({ "error": "no origin is mapped to the host" }),
+                        );
+                    }
+                    match forward.upstream_url(&mut url, None, None) {
+                        Ok(prefix) => Some(cache::key(&url, &host, prefix.as_deref()).to_string()),
+                        Err(e) => {
+                            return reply(StatusCode::BadRequest, json!({ "error": e.to_string() }))
+                        }
+                    }
+                }
+                Some(Err(_)) => {
+                    return reply(StatusCode::BadRequest, json!({ "error": "invalid url" }))
+                }
+                None => None,
+            };
+            let domain = query("domain");
+            if key.is_none() && domain.is_none() {
+                return reply(
+                    StatusCode::BadRequest,
+                    json!({ "error": "missing url or domain" }),
+                );
+            }
+            cache
+                .purge(key.as_deref(), domain.as_deref())
+                .map(|removed| {
+                    info!("admin purged {} cached responses", removed);
+                    json!({ "removed": removed })
+                })
+        }
+        (Method::Post, "/reload") => match current.reload() {
+            Ok(()) => {
+                info!("admin reloaded the config");
+                Ok(json!({ "reloaded": true }))
+            }
+            Err(e) => return reply(StatusCode::BadRequest, json!({ "error": e.to_string() })),
+        },
+        (Method::Get, "/ready") => {
+            let (ok, checks) = forward.readiness().await;
+            let status = if ok {
+                StatusCode::Ok
+            } else {
+                StatusCode::ServiceUnavailable
+            };
+            return reply(status, json!({ "ok": ok, "checks": checks }));
+        }
+        (Method::Get, "/backends") => Ok(forward.balancer.to_json()),
+        (Method::Get, "/traffic") => match &forward.traffic {
+            Some(traffic) => traffic.to_json(db),
+            None => return reply(StatusCode::NotFound, json!({ "error": "quota disabled" })),
+        },
+        (Method::Get, "/maintenance") => Ok(maintenance.to_json()),
+        (method @ (Method::Put | Method::Delete), "/maintenance") => {
+            let domain = req.url().query_pairs().find(|(k, _)| k == "domain");
+            let domain = domain.as_ref().map(|(_, v)| v.as_ref());
+            let on = method == Method::Put;
+            maintenance.set(domain, on);
+            info!(
+                "admin turned maintenance {} for {}",
+                if on { "on" } else { "off" },
+                domain.unwrap_or("all domains")
+            );
+            Ok(maintenance.to_json())
+        }
+        _ => return reply(StatusCode::NotFound, json!({ "error": "not found" })),
+    };
+    match result {
+        Ok(body) => reply(StatusCode::Ok, body),
+        Err(e) => {
+            error!("admin request failed: {}", e);
+            reply(
+                StatusCode::InternalServerError,
+                json!({ "error": e.to_string() }),
+            )
+        }
+    }
+}
+
+fn reply(status: StatusCode, body: Value) -> Response {
+    let mut resp = Response::new(status);
+    resp.set_content_type(http_types::mime::JSON);
+    resp.insert_header("Cache-Control", "no-store");
+    resp.set_body(body);
+    resp
+}
+
+/// compare without returning early, so the token can't be guessed by timing
+fn secure_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
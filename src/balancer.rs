@@ -0,0 +1,266 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex, Weak,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use async_io::{block_on, Timer};
+use futures_lite::FutureExt;
+use http_types::{Request, Url};
+use serde_json::{json, Value};
+use tracing::info;
+
+use crate::{
+    client,
+    config::{Backends, Balance, Config, HealthCheck, PhaseTimeouts, Retry},
+};
+
+/// origins served by several backends, by their host
+#[derive(Debug)]
+pub struct Balancer(HashMap<String, Pool>);
+
+#[derive(Debug)]
+struct Pool {
+    balance: Balance,
+    max_fails: usize,
+    fail_timeout: Duration,
+    health_check: Option<HealthCheck>,
+    next: AtomicUsize,
+    backends: Vec<Arc<Backend>>,
+}
+
+#[derive(Debug)]
+pub struct Backend {
+    address: String,
+    /// requests sent to it and not yet answered completely
+    active: AtomicUsize,
+    /// failures in a row
+    fails: AtomicUsize,
+    down_until: Mutex<Option<Instant>>,
+    /// its last health check failed
+    probed_down: AtomicBool,
+}
+
+impl Backend {
+    fn is_up(&self) -> bool {
+        if self.probed_down.load(Ordering::Relaxed) {
+            return false;
+        }
+        let down_until = match self.down_until.lock() {
+            Ok(down_until) => down_until,
+            Err(e) => e.into_inner(),
+        };
+        down_until.is_none_or(|i| Instant::now() >= i)
+    }
+
+    /// whether a probe of `check` succeeds
+    async fn probe(&self, check: &HealthCheck) -> bool {
+        let timeout = Some(check.timeout_secs.max(1));
+        match &check.url {
+            Some(url) => {
+                let Ok(url) = Url::parse(url) else {
+                    return false;
+                };
+                let timeouts = PhaseTimeouts {
+                    total_secs: timeout,
+                    ..PhaseTimeouts::DEFAULT
+                };
+                let retry = Retry::default();
+                let sent = client::send_to(
+                    Request::get(url),
+                    |_| true,
+                    timeouts,
+                    &retry,
+                    Some(&self.address),
+                );
+                sent.await
+                    .is_ok_and(|resp| resp.status().is_success() || resp.status().is_redirection())
+            }
+            None => {
+                let (host, port) = client::split_address(&self.address, check.port);
+                let timeout = Duration::from_secs(check.timeout_secs.max(1));
+                async { client::connect(host, port, |_| true).await.is_ok() }
+                    .or(async {
+                        Timer::after(timeout).await;
+                        false
+                    })
+                    .await
+            }
+        }
+    }
+
+    fn set_down_until(&self, until: Option<Instant>) {
+        let mut down_until = match self.down_until.lock() {
+            Ok(down_until) => down_until,
+            Err(e) => e.into_inner(),
+        };
+        *down_until = until;
+    }
+}
+
+/// the backend chosen for a request, counted as active while this lives
+#[derive(Debug)]
+pub struct Pick {
+    backend: Arc<Backend>,
+    max_fails: usize,
+    fail_timeout: Duration,
+}
+
+impl Pick {
+    /// `host` or `host:port` connected to instead of the origin
+    pub fn address(&self) -> &str {
+        &self.backend.address
+    }
+
+    pub fn success(&self) {
+        self.backend.fails.store(0, Ordering::Relaxed);
+        self.backend.set_down_until(None);
+    }
+
+    /// after `max_fails` failures in a row, the backend is left out for
+    /// `fail_timeout`
+    pub fn failure(&self) {
+        let fails = self.backend.fails.fetch_add(1, Ordering::Relaxed) + 1;
+        if fails >= self.max_fails {
+            info!(
+                "backend {} down after {} failures",
+                self.backend.address, fails
+            );
+            self.backend
+                .set_down_until(Some(Instant::now() + self.fail_timeout));
+        }
+    }
+}
+
+impl Drop for Pick {
+    fn drop(&mut self) {
+        self.backend.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Balancer {
+    /// pools of `backends`, with a thread probing those with health checks
+    pub fn new(config: &Config) -> Result<Balancer> {
+        let pools: HashMap<_, _> = config
+            .backends
+            .iter()
+            .map(|(origin, config)| (origin.clone(), Pool::new(config)))
+            .collect();
+        for (origin, pool) in &pools {
+            if let Some(check) = &pool.health_check {
+                let check = check.clone();
+                let backends = pool.backends.iter().map(Arc::downgrade).collect();
+                let origin = origin.clone();
+                thread::Builder::new()
+                    .name("health-check".to_string())
+                    .spawn(move || block_on(probe(origin, check, backends)))?;
+            }
+        }
+        Ok(Balancer(pools))
+    }
+
+    /// every backend of `origin`, which has health checks, is down
+    pub fn is_down(&self, origin: &str) -> bool {
+        self.0.get(origin).is_some_and(|pool| {
+            pool.health_check.is_some() && !pool.backends.iter().any(|i| i.is_up())
+        })
+    }
+
+    /// a backend for a request to `origin`, if it has several, those down
+    /// are chosen only if every one is
+    pub fn pick(&self, origin: &str) -> Option<Pick> {
+        let pool = self.0.get(origin)?;
+        let up: Vec<_> = pool.backends.iter().filter(|i| i.is_up()).collect();
+        let candidates = match up.is_empty() {
+            true => pool.backends.iter().collect(),
+            false => up,
+        };
+        let backend = match pool.balance {
+            Balance::RoundRobin => {
+                let next = pool.next.fetch_add(1, Ordering::Relaxed);
+                candidates.get(next % candidates.len().max(1))
+            }
+            Balance::LeastConnections => candidates
+                .iter()
+                .min_by_key(|i| i.active.load(Ordering::Relaxed)),
+        }?;
+        backend.active.fetch_add(1, Ordering::Relaxed);
+        Some(Pick {
+            backend: Arc::clone(backend),
+            max_fails: pool.max_fails,
+            fail_timeout: pool.fail_timeout,
+        })
+    }
+
+    pub fn to_json(&self) -> Value {
+        self.0
+            .iter()
+            .map(|(origin, pool)| {
+                let backends: Vec<_> = pool
+                    .backends
+                    .iter()
+                    .map(|i| {
+                        json!({
+                            "address": i.address,
+                            "up": i.is_up(),
+                            "active": i.active.load(Ordering::Relaxed),
+                            "fails": i.fails.load(Ordering::Relaxed),
+                        })
+                    })
+                    .collect();
+                (origin.clone(), Value::from(backends))
+            })
+            .collect::<serde_json::Map<_, _>>()
+            .into()
+    }
+}
+
+impl Pool {
+    fn new(config: &Backends) -> Pool {
+        Pool {
+            balance: config.balance,
+            max_fails: config.max_fails.max(1),
+            fail_timeout: Duration::from_secs(config.fail_timeout_secs),
+            health_check: config.health_check.clone(),
+            next: AtomicUsize::new(0),
+            backends: config
+                .hosts
+                .iter()
+                .map(|address| {
+                    Arc::new(Backend {
+                        address: address.clone(),
+                        active: AtomicUsize::new(0),
+                        fails: AtomicUsize::new(0),
+                        down_until: Mutex::new(None),
+                        probed_down: AtomicBool::new(false),
+                    })
+                })
+                .collect(),
+        }
+    }
+}
+
+/// probe `backends` of `origin` every `interval_secs` of `check`
+/// until the pool is dropped, on reload
+async fn probe(origin: String, check: HealthCheck, backends: Vec<Weak<Backend>>) {
+    loop {
+        let backends: Vec<_> = backends.iter().filter_map(Weak::upgrade).collect();
+        if backends.is_empty() {
+            return;
+        }
+        for backend in &backends {
+            let up = backend.probe(&check).await;
+            if backend.probed_down.swap(!up, Ordering::Relaxed) == up {
+                let state = if up { "up" } else { "down" };
+                info!("backend {} of {} is {}", backend.address, origin, state);
+            }
+        }
+        drop(backends);
+        Timer::after(Duration::from_secs(check.interval_secs.max(1))).await;
+    }
+}
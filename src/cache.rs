@@ -0,0 +1,697 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Result};
+use futures_lite::io::{AsyncReadExt, BufReader, Cursor};
+use http_types::{
+    cache::{Age, CacheControl, CacheDirective, Expires},
+    conditional::{IfModifiedSince, LastModified},
+    headers::{Headers, CONTENT_TYPE},
+    Body, Method, Request, Response, StatusCode, Url,
+};
+use redb::{Database, ReadableTable, TableDefinition, TableError};
+use serde::{Deserialize, Serialize};
+
+use crate::config;
+
+/// rewritten url to the response metadata and body
+const RESPONSES: TableDefinition<&str, (&str, &[u8])> = TableDefinition::new("responses");
+
+/// headers which describe the connection rather than the response
+const SKIP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "transfer-encoding",
+    "content-length",
+    "age",
+    "x-cache",
+];
+
+/// cache of rewritten responses following the origin's freshness, on disk
+/// and for small assets in memory
+#[derive(Debug)]
+pub struct Cache {
+    disk: Option<Disk>,
+    memory: Option<Memory>,
+    /// seconds a stale response may be served while it is refreshed, if
+    /// the origin doesn't say
+    stale_while_revalidate: u64,
+}
+
+pub enum Hit {
+    Fresh(Response),
+    /// stale but within `stale-while-revalidate`, refresh it in the background
+    Stale(Response),
+}
+
+#[derive(Debug)]
+struct Disk {
+    db: Database,
+    max_body_size: u64,
+}
+
+#[derive(Debug)]
+struct Memory {
+    lru: Mutex<Lru>,
+    max_body_size: u64,
+    content_types: Vec<String>,
+}
+
+/// a cacheable request, kept to match and store its response
+pub struct Lookup {
+    key: String,
+    headers: Headers,
+    no_cache: bool,
+    /// a stale response to revalidate with the origin
+    stale: Option<Arc<Entry>>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Meta {
+    status: u16,
+    headers: Vec<(String, String)>,
+    /// when the origin generated the response, in unix seconds
+    date: u64,
+    expires: u64,
+    /// seconds after `expires` the response may be served while refreshed
+    #[serde(default)]
+    stale_while_revalidate: u64,
+    /// request headers named by `Vary` and their values
+    vary: Vec<(String, String)>,
+}
+
+impl Meta {
+    /// the request has the same values of the `Vary` headers
+    fn matches(&self, lookup: &Lookup) -> bool {
+        self.vary
+            .iter()
+            .all(|(k, v)| header(&lookup.headers, k) == *v)
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// the response has an `ETag` or `Last-Modified` to revalidate with
+    fn revalidatable(&self) -> bool {
+        self.header("etag").is_some() || self.header("last-modified").is_some()
+    }
+
+    fn response(&self, body: Vec<u8>, now: u64) -> Result<Response> {
+        let mut resp =
+            Response::new(StatusCode::try_from(self.status).map_err(|e| anyhow!("{}", e))?);
+        for (k, v) in &self.headers {
+            resp.append_header(k.as_str(), v.as_str());
+        }
+        let has_content_type = resp.header(CONTENT_TYPE).is_some();
+        resp.set_body(body);
+        if !has_content_type {
+            resp.remove_header(CONTENT_TYPE);
+        }
+        Age::from_secs(now.saturating_sub(self.date)).apply(&mut resp);
+        resp.insert_header("X-Cache", "HIT");
+        Ok(resp)
+    }
+
+    fn content_type(&self) -> Option<&str> {
+        self.header("content-type")
+            .map(|i| i.split(';').next().unwrap_or_default().trim())
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    meta: Meta,
+    body: Vec<u8>,
+}
+
+/// least recently used entries are evicted beyond `capacity`
+#[derive(Debug)]
+struct Lru {
+    capacity: usize,
+    tick: u64,
+    entries: HashMap<String, (u64, Arc<Entry>)>,
+    order: BTreeMap<u64, String>,
+}
+
+impl Lru {
+    fn get(&mut self, key: &str) -> Option<Arc<Entry>> {
+        self.tick += 1;
+        let (tick, entry) = self.entries.get_mut(key)?;
+        let key = self.order.remove(tick)?;
+        *tick = self.tick;
+        self.order.insert(self.tick, key);
+        Some(entry.clone())
+    }
+
+    fn insert(&mut self, key: String, entry: Arc<Entry>) {
+        self.tick += 1;
+        if let Some((tick, _)) = self.entries.insert(key.clone(), (self.tick, entry)) {
+            self.order.remove(&tick);
+        }
+        self.order.insert(self.tick, key);
+        while self.entries.len() > self.capacity {
+            match self.order.pop_first() {
+                Some((_, key)) => self.entries.remove(&key),
+                None => break,
+            };
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some((tick, _)) = self.entries.remove(key) {
+            self.order.remove(&tick);
+        }
+    }
+}
+
+impl Cache {
+    pub fn new(
+        data_dir: &str,
+        disk: Option<&config::Cache>,
+        memory: Option<&config::MemoryCache>,
+    ) -> Result<Cache> {
+        let stale_while_revalidate = disk
+            .and_then(|config| config.stale_while_revalidate)
+            .unwrap_or_default();
+        let disk = match disk {
+            Some(config) => Some(Disk {
+                db: Database::create(Path::new(data_dir).join("cache.redb"))?,
+                max_body_size: config.max_body_size,
+            }),
+            None => None,
+        };
+        let memory = memory.map(|config| Memory {
+            lru: Mutex::new(Lru {
+                capacity: config.max_entries,
+                tick: 0,
+                entries: HashMap::new(),
+                order: BTreeMap::new(),
+            }),
+            max_body_size: config.max_body_size,
+            content_types: config.content_types.clone(),
+        });
+        Ok(Cache {
+            disk,
+            memory,
+            stale_while_revalidate,
+        })
+    }
+
+    /// the request with its upstream url, if its response may be cached,
+    /// for the mirror `host` serving it under the path `prefix` if any
+    pub fn lookup(&self, req: &Request, host: &str, prefix: Option<&str>) -> Option<Lookup> {
+        if req.method() != Method::Get || req.header("Range").is_some() {
+            return None;
+        }
+        let directives = directives(req)?;
+        if directives.contains(&CacheDirective::NoStore) {
+            return None;
+        }
+        Some(Lookup {
+            key: key(req.url(), host, prefix).to_string(),
+            headers: req.as_ref().clone(),
+            no_cache: directives.contains(&CacheDirective::NoCache),
+            stale: None,
+        })
+    }
+
+    /// a cached response, or 304 if the client has it already; a stale
+    /// response which can be revalidated is kept in `lookup`
+    pub fn get(&self, lookup: &mut Lookup) -> Result<Option<Hit>> {
+        let entry = match self.entry(&lookup.key)? {
+            Some(entry) if entry.meta.matches(lookup) => entry,
+            _ => return Ok(None),
+        };
+        let now = now();
+        if entry.meta.expires > now && !lookup.no_cache {
+            let resp = entry.meta.response(entry.body.clone(), now)?;
+            return Ok(Some(Hit::Fresh(not_modified(lookup, resp))));
+        }
+        let stale_until = entry.meta.expires + entry.meta.stale_while_revalidate;
+        if entry.meta.revalidatable() {
+            lookup.stale = Some(entry.clone());
+        } else if stale_until <= now {
+            self.remove(&lookup.key)?;
+        }
+        if stale_until > now && !lookup.no_cache {
+            let mut resp = entry.meta.response(entry.body.clone(), now)?;
+            resp.insert_header("X-Cache", "STALE");
+            return Ok(Some(Hit::Stale(not_modified(lookup, resp))));
+        }
+        Ok(None)
+    }
+
+    /// make the upstream request conditional on the stale response
+    pub fn revalidate(&self, lookup: &Lookup, req: &mut Request) {
+        let stale = match &lookup.stale {
+            Some(stale) => stale,
+            None => return,
+        };
+        req.remove_header("If-None-Match");
+        req.remove_header("If-Modified-Since");
+        if let Some(etag) = stale.meta.header("etag") {
+            req.insert_header("If-None-Match", etag);
+        }
+        if let Some(last_modified) = stale.meta.header("last-modified") {
+            req.insert_header("If-Modified-Since", last_modified);
+        }
+    }
+
+    /// the stale response kept in `lookup`, for when the origin is down
+    pub fn stale(&self, lookup: &Lookup) -> Result<Option<Response>> {
+        let Some(stale) = &lookup.stale else {
+            return Ok(None);
+        };
+        let mut resp = stale.meta.response(stale.body.clone(), now())?;
+        resp.insert_header("X-Cache", "STALE");
+        Ok(Some(not_modified(lookup, resp)))
+    }
+
+    /// the stale response updated with the headers of the origin's 304
+    pub fn refresh(&self, lookup: Lookup, resp: &Response) -> Result<Option<Response>> {
+        let stale = match &lookup.stale {
+            Some(stale) => stale,
+            None => return Ok(None),
+        };
+        let mut headers = stale.meta.headers.clone();
+        for (k, v) in resp.iter() {
+            if SKIP_HEADERS.contains(&k.as_str()) || k == "content-type" {
+                continue;
+            }
+            headers.retain(|(i, _)| i != k.as_str());
+            headers.extend(v.iter().map(|v| (k.to_string(), v.to_string())));
+        }
+        let now = now();
+        let meta = Meta {
+            status: stale.meta.status,
+            headers,
+            date: now,
+            expires: now,
+            stale_while_revalidate: 0,
+            vary: stale.meta.vary.clone(),
+        };
+        let resp = meta.response(stale.body.clone(), now)?;
+        match lifetime(&lookup, &resp) {
+            Some(lifetime) => {
+                let meta = self.meta(&resp, lifetime, meta.vary);
+                self.put(&lookup.key, stale.body.clone(), meta)?;
+            }
+            None => self.remove(&lookup.key)?,
+        }
+        Ok(Some(not_modified(&lookup, resp)))
+    }
+
+    fn entry(&self, key: &str) -> Result<Option<Arc<Entry>>> {
+        if let Some(memory) = &self.memory {
+            if let Some(entry) = memory.lock().get(key) {
+                return Ok(Some(entry));
+            }
+        }
+        let disk = match &self.disk {
+            Some(disk) => disk,
+            None => return Ok(None),
+        };
+        let read_txn = disk.db.begin_read()?;
+        let table = match read_txn.open_table(RESPONSES) {
+            Ok(table) => table,
+            Err(TableError::TableDoesNotExist(_)) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let entry = match table.get(key)? {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        let (meta, body) = entry.value();
+        let entry = Arc::new(Entry {
+            meta: serde_json::from_str(meta)?,
+            body: body.to_vec(),
+        });
+        if let Some(memory) = &self.memory {
+            memory.insert(key, entry.clone());
+        }
+        Ok(Some(entry))
+    }
+
+    fn put(&self, key: &str, body: Vec<u8>, meta: Meta) -> Result<()> {
+        if let Some(disk) = &self.disk {
+            if body.len() as u64 <= disk.max_body_size {
+                disk.insert(key, &meta, &body)?;
+            }
+        }
+        if let Some(memory) = &self.memory {
+            memory.insert(key, Arc::new(Entry { meta, body }));
+        }
+        Ok(())
+    }
+
+    /// remove the response of `key`, or all responses of `domain` and its
+    /// subdomains, upstream or mirror ones, returning how many were removed
+    pub fn purge(&self, key: Option<&str>, domain: Option<&str>) -> Result<usize> {
+        let in_domain = |host: &str| {
+            domain.is_some_and(|domain| {
+                host == domain || host.strip_suffix(domain).is_some_and(|i| i.ends_with('.'))
+            })
+        };
+        let matches = |stored: &str| {
+            key.is_some_and(|key| key == stored)
+                || Url::parse(stored).is_ok_and(|stored| {
+                    let mirror = stored.fragment().and_then(|i| i.split('/').next());
+                    stored.host_str().is_some_and(in_domain) || mirror.is_some_and(in_domain)
+                })
+        };
+        let mut removed = Vec::new();
+        if let Some(memory) = &self.memory {
+            let mut lru = memory.lock();
+            let keys: Vec<_> = lru.entries.keys().filter(|i| matches(i)).cloned().collect();
+            for key in keys {
+                lru.remove(&key);
+                removed.push(key);
+            }
+        }
+        if let Some(disk) = &self.disk {
+            let keys = {
+                let read_txn = disk.db.begin_read()?;
+                match read_txn.open_table(RESPONSES) {
+                    Ok(table) => {
+                        let mut keys = Vec::new();
+                        for i in table.iter()? {
+                            let (key, _) = i?;
+                            if matches(key.value()) {
+                                keys.push(key.value().to_string());
+                            }
+                        }
+                        keys
+                    }
+                    Err(TableError::TableDoesNotExist(_)) => Vec::new(),
+                    Err(e) => return Err(e.into()),
+                }
+            };
+            let write_txn = disk.db.begin_write()?;
+            {
+                let mut table = write_txn.open_table(RESPONSES)?;
+                for key in keys {
+                    table.remove(key.as_str())?;
+                    removed.push(key);
+                }
+            }
+            write_txn.commit()?;
+        }
+        removed.sort();
+        removed.dedup();
+        Ok(removed.len())
+    }
+
+    fn meta(&self, resp: &Response, lifetime: Duration, vary: Vec<(String, String)>) -> Meta {
+        let age = Age::from_headers(resp)
+            .ok()
+            .flatten()
+            .map(|i| i.duration().as_secs())
+            .unwrap_or_default();
+        let date = now().saturating_sub(age);
+        let headers = resp
+            .iter()
+            .filter(|(k, _)| !SKIP_HEADERS.contains(&k.as_str()))
+            .flat_map(|(k, v)| v.iter().map(|v| (k.to_string(), v.to_string())))
+            .collect();
+        let directives = directives(resp).unwrap_or_default();
+        // responses which must be revalidated are never served stale
+        let stale_while_revalidate = if directives.iter().any(|i| {
+            matches!(
+                i,
+                CacheDirective::NoCache
+                    | CacheDirective::MustRevalidate
+                    | CacheDirective::ProxyRevalidate
+            )
+        }) {
+            0
+        } else {
+            directives
+                .iter()
+                .find_map(|i| match i {
+                    CacheDirective::StaleWhileRevalidate(i) => Some(i.as_secs()),
+                    _ => None,
+                })
+                .unwrap_or(self.stale_while_revalidate)
+        };
+        Meta {
+            status: resp.status().into(),
+            headers,
+            date,
+            expires: date + lifetime.as_secs(),
+            stale_while_revalidate,
+            vary,
+        }
+    }
+
+    fn remove(&self, key: &str) -> Result<()> {
+        if let Some(memory) = &self.memory {
+            memory.lock().remove(key);
+        }
+        if let Some(disk) = &self.disk {
+            disk.remove(key)?;
+        }
+        Ok(())
+    }
+
+    /// store the response if the origin allows it, its body is buffered
+    /// up to the size limit and passed on unchanged
+    pub async fn store(&self, lookup: Lookup, resp: &mut Response) -> Result<()> {
+        let lifetime = match lifetime(&lookup, resp) {
+            Some(lifetime) => lifetime,
+            None => return Ok(()),
+        };
+        let vary = match resp.header("Vary") {
+            Some(vary) => {
+                let names: Vec<_> = vary
+                    .iter()
+                    .flat_map(|i| i.as_str().split(','))
+                    .map(|i| i.trim().to_lowercase())
+                    .filter(|i| !i.is_empty())
+                    .collect();
+                if names.iter().any(|i| i == "*") {
+                    return Ok(());
+                }
+                names
+                    .into_iter()
+                    .map(|k| {
+                        let v = header(&lookup.headers, &k);
+                        (k, v)
+                    })
+                    .collect()
+            }
+            None => Vec::new(),
+        };
+        let memory = self.memory.as_ref().filter(|memory| {
+            resp.content_type().is_some_and(|i| {
+                memory
+                    .content_types
+                    .iter()
+                    .any(|content_type| content_type == i.essence())
+            })
+        });
+        let max_body_size = self
+            .disk
+            .as_ref()
+            .map(|disk| disk.max_body_size)
+            .into_iter()
+            .chain(memory.map(|memory| memory.max_body_size))
+            .max()
+            .unwrap_or_default();
+
+        // don't buffer what is known to be too large
+        if resp.len().is_some_and(|len| len as u64 > max_body_size) {
+            return Ok(());
+        }
+        let has_content_type = resp.header(CONTENT_TYPE).is_some();
+        let mut body = resp.take_body();
+        let mut buf = Vec::new();
+        (&mut body)
+            .take(max_body_size + 1)
+            .read_to_end(&mut buf)
+            .await?;
+        let len = buf.len() as u64;
+        if len > max_body_size {
+            let rest = Cursor::new(buf).chain(body);
+            resp.set_body(Body::from_reader(BufReader::new(rest), None));
+            if !has_content_type {
+                resp.remove_header(CONTENT_TYPE);
+            }
+            return Ok(());
+        }
+        resp.set_body(buf.clone());
+        if !has_content_type {
+            resp.remove_header(CONTENT_TYPE);
+        }
+
+        let meta = self.meta(resp, lifetime, vary);
+        self.put(&lookup.key, buf, meta)
+    }
+}
+
+impl Disk {
+    fn insert(&self, key: &str, meta: &Meta, body: &[u8]) -> Result<()> {
+        let meta = serde_json::to_string(meta)?;
+        let write_txn = self.db.begin_write()?;
+        {
+            let mut table = write_txn.open_table(RESPONSES)?;
+            table.insert(key, (meta.as_str(), body))?;
+        }
+        write_txn.commit()?;
+        Ok(())
+    }
+
+    fn remove(&self, key: &str) -> Result<()> {
+        let write_txn = self.db.begin_write()?;
+        {
+            let mut table = write_txn.open_table(RESPONSES)?;
+            table.remove(key)?;
+        }
+        write_txn.commit()?;
+        Ok(())
+    }
+}
+
+impl Memory {
+    fn lock(&self) -> std::sync::MutexGuard<'_, Lru> {
+        match self.lru.lock() {
+            Ok(lru) => lru,
+            Err(e) => e.into_inner(),
+        }
+    }
+
+    fn insert(&self, key: &str, entry: Arc<Entry>) {
+        if entry
+            .meta
+            .content_type()
+            .is_some_and(|content_type| self.content_types.iter().any(|i| i == content_type))
+            && entry.body.len() as u64 <= self.max_body_size
+        {
+            self.lock().insert(key.to_string(), entry);
+        }
+    }
+}
+
+/// the cache key of the upstream `url`, responses rewritten for each mirror
+/// `host` and path `prefix` are kept apart by the fragment no request has
+pub fn key(url: &Url, host: &str, prefix: Option<&str>) -> Url {
+    let mut key = url.clone();
+    key.set_fragment(Some(&format!("{}{}", host, prefix.unwrap_or_default())));
+    key
+}
+
+/// 304 instead of `resp` if the client's validators match it
+fn not_modified(lookup: &Lookup, mut resp: Response) -> Response {
+    if resp.status() != StatusCode::Ok {
+        return resp;
+    }
+    let matched = match lookup.headers.get("If-None-Match") {
+        Some(if_none_match) => resp.header("ETag").is_some_and(|etag| {
+            if_none_match
+                .iter()
+                .flat_map(|i| i.as_str().split(','))
+                .any(|i| i.trim() == "*" || weak_eq(i.trim(), etag.as_str()))
+        }),
+        None => match (
+            IfModifiedSince::from_headers(&lookup.headers),
+            LastModified::from_headers(&resp),
+        ) {
+            (Ok(Some(since)), Ok(Some(last_modified))) => {
+                last_modified.modified() <= since.modified()
+            }
+            _ => false,
+        },
+    };
+    if matched {
+        resp.set_status(StatusCode::NotModified);
+        resp.take_body();
+        resp.remove_header(CONTENT_TYPE);
+    }
+    resp
+}
+
+/// compare entity tags ignoring the weak prefix
+fn weak_eq(a: &str, b: &str) -> bool {
+    a.trim_start_matches("W/") == b.trim_start_matches("W/")
+}
+
+/// how long a response stays fresh, `None` if it may not be cached; a
+/// response which must be revalidated on every use is kept if it can be
+fn lifetime(lookup: &Lookup, resp: &Response) -> Option<Duration> {
+    const CACHEABLE: &[u16] = &[200, 203, 204, 300, 301, 404, 405, 410, 414, 501];
+    if !CACHEABLE.contains(&resp.status().into()) || resp.header("Set-Cookie").is_some() {
+        return None;
+    }
+    let directives = directives(resp)?;
+    if directives
+        .iter()
+        .any(|i| matches!(i, CacheDirective::NoStore | CacheDirective::Private))
+    {
+        return None;
+    }
+    // a shared cache may only keep authorized responses the origin marks so
+    if lookup.headers.get("Authorization").is_some()
+        && !directives.iter().any(|i| {
+            matches!(
+                i,
+                CacheDirective::Public
+                    | CacheDirective::SMaxAge(_)
+                    | CacheDirective::MustRevalidate
+            )
+        })
+    {
+        return None;
+    }
+    let s_maxage = directives.iter().find_map(|i| match i {
+        CacheDirective::SMaxAge(i) => Some(*i),
+        _ => None,
+    });
+    let max_age = directives.iter().find_map(|i| match i {
+        CacheDirective::MaxAge(i) => Some(*i),
+        _ => None,
+    });
+    let expires = || {
+        Expires::from_headers(resp).ok().flatten().map(|i| {
+            i.expiration()
+                .duration_since(SystemTime::now())
+                .unwrap_or_default()
+        })
+    };
+    let lifetime = if directives.contains(&CacheDirective::NoCache) {
+        Duration::ZERO
+    } else {
+        s_maxage.or(max_age).or_else(expires).unwrap_or_default()
+    };
+    let revalidatable = resp.header("ETag").is_some() || resp.header("Last-Modified").is_some();
+    (!lifetime.is_zero() || revalidatable).then_some(lifetime)
+}
+
+/// the `Cache-Control` directives, `None` if they can't be parsed
+fn directives(headers: impl AsRef<Headers>) -> Option<Vec<CacheDirective>> {
+    match CacheControl::from_headers(headers) {
+        Ok(Some(cache_control)) => Some(cache_control.into_iter().collect()),
+        Ok(None) => Some(Vec::new()),
+        Err(_) => None,
+    }
+}
+
+fn header(headers: &Headers, name: &str) -> String {
+    headers
+        .get(name)
+        .map(|i| i.iter().map(|i| i.as_str()).collect::<Vec<_>>().join(", "))
+        .unwrap_or_default()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|i| i.as_secs())
+        .unwrap_or_default()
+}
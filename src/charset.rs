@@ -0,0 +1,37 @@
+use encoding_rs::{Encoding, UTF_8};
+use http_types::Mime;
+
+/// how far into an html body a `<meta>` charset is looked for
+const SNIFF_LEN: usize = 1024;
+
+/// encoding of a body, from its byte order mark, the `charset` of its
+/// content type or a `<meta>` tag of html, utf-8 if none is declared
+pub fn detect(content_type: Option<&Mime>, body: &[u8]) -> &'static Encoding {
+    if let Some((encoding, _)) = Encoding::for_bom(body) {
+        return encoding;
+    }
+    let declared = content_type.and_then(|i| i.param("charset"));
+    if let Some(encoding) = declared.and_then(|i| Encoding::for_label(i.as_str().as_bytes())) {
+        return encoding;
+    }
+    if content_type.is_some_and(|i| i.essence() == "text/html") {
+        if let Some(encoding) = meta(&body[..body.len().min(SNIFF_LEN)]) {
+            return encoding;
+        }
+    }
+    UTF_8
+}
+
+/// the `charset=` of `<meta charset="...">` or
+/// `<meta http-equiv="Content-Type" content="text/html; charset=...">`
+fn meta(head: &[u8]) -> Option<&'static Encoding> {
+    let head = head.to_ascii_lowercase();
+    let start = head.windows(8).position(|i| i == b"charset=")? + 8;
+    let label: Vec<_> = head[start..]
+        .iter()
+        .skip_while(|&&i| i == b'"' || i == b'\'')
+        .take_while(|&&i| i.is_ascii_alphanumeric() || b"-_:.".contains(&i))
+        .copied()
+        .collect();
+    Encoding::for_label(&label)
+}
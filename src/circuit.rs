@@ -0,0 +1,87 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use tracing::info;
+
+use crate::config::CircuitBreaker;
+
+/// circuits of origins failing repeatedly, by their host: an open one fails
+/// requests at once for `cool_down_secs`, then lets a single one through,
+/// whose success closes it and failure opens it again
+#[derive(Debug)]
+pub struct Circuits {
+    failures: usize,
+    cool_down: Duration,
+    origins: Mutex<HashMap<String, Circuit>>,
+}
+
+#[derive(Debug, Default)]
+struct Circuit {
+    /// failures in a row
+    fails: usize,
+    open_until: Option<Instant>,
+    /// a request is let through the circuit after its cool-down
+    trial: bool,
+}
+
+impl Circuits {
+    pub fn new(config: &CircuitBreaker) -> Circuits {
+        Circuits {
+            failures: config.failures.max(1),
+            cool_down: Duration::from_secs(config.cool_down_secs),
+            origins: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// whether a request may be sent to `origin`, otherwise the time left
+    /// until it may be
+    pub fn allow(&self, origin: &str) -> Result<(), Duration> {
+        let mut origins = self.lock();
+        let Some(circuit) = origins.get_mut(origin) else {
+            return Ok(());
+        };
+        match circuit.open_until {
+            Some(until) if until > Instant::now() => Err(until - Instant::now()),
+            Some(_) if circuit.trial => Err(self.cool_down),
+            Some(_) => {
+                circuit.trial = true;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    pub fn success(&self, origin: &str) {
+        let mut origins = self.lock();
+        if origins
+            .remove(origin)
+            .is_some_and(|i| i.open_until.is_some())
+        {
+            info!("circuit of {} closed", origin);
+        }
+    }
+
+    pub fn failure(&self, origin: &str) {
+        let mut origins = self.lock();
+        let circuit = origins.entry(origin.to_string()).or_default();
+        circuit.fails += 1;
+        if circuit.trial || circuit.fails >= self.failures {
+            info!(
+                "circuit of {} open for {:?} after {} failures",
+                origin, self.cool_down, circuit.fails
+            );
+            circuit.open_until = Some(Instant::now() + self.cool_down);
+            circuit.trial = false;
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Circuit>> {
+        match self.origins.lock() {
+            Ok(origins) => origins,
+            Err(e) => e.into_inner(),
+        }
+    }
+}
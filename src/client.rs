@@ -0,0 +1,359 @@
+use std::{
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr, TcpStream},
+    pin::Pin,
+    task::{ready, Context, Poll},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use async_io::{Async, Timer};
+use async_net::{resolve, AsyncToSocketAddrs};
+use futures_lite::{
+    io::{AsyncRead, AsyncWrite, BufReader},
+    FutureExt,
+};
+use http_types::{headers::EXPECT, Body, Method, Request, Response, StatusCode};
+use tracing::{info, info_span, Instrument};
+
+use crate::config::{PhaseTimeouts, Retry};
+
+/// send a request to the host in its url, over tls for https
+pub async fn send(req: Request) -> http_types::Result<Response> {
+    send_to(
+        req,
+        |_| true,
+        PhaseTimeouts::DEFAULT,
+        &Retry::default(),
+        None,
+    )
+    .await
+}
+
+/// like [`send`], connecting only to an address `allowed` accepts, which
+/// is checked as resolved, so a name can't be rebound to another, a refused
+/// one is an error with status 403, a phase exceeding its timeout is an
+/// error with status 504, GET and HEAD requests failing or answered with
+/// 502 or 503 are sent again as `retry` allows, to `backend`, `host` or
+/// `host:port`, instead of the host of the url if set
+pub async fn send_to(
+    req: Request,
+    allowed: impl Fn(&IpAddr) -> bool,
+    timeouts: PhaseTimeouts,
+    retry: &Retry,
+    backend: Option<&str>,
+) -> http_types::Result<Response> {
+    let retries = match req.method() {
+        Method::Get | Method::Head => retry.attempts,
+        _ => 0,
+    };
+    let address = |attempt| if retry.other_address { attempt } else { 0 };
+    for attempt in 0..retries {
+        // the body of a clone is empty, as those of GET and HEAD are
+        let sending = send_once(req.clone(), &allowed, timeouts, backend, address(attempt));
+        match sending.await {
+            Ok(resp)
+                if !matches!(
+                    resp.status(),
+                    StatusCode::BadGateway | StatusCode::ServiceUnavailable
+                ) =>
+            {
+                return Ok(resp)
+            }
+            Err(e) if e.status() == StatusCode::Forbidden => return Err(e),
+            Ok(resp) => info!("retrying {}, answered {}", req.url(), resp.status()),
+            Err(e) => info!("retrying {}, failed: {}", req.url(), e),
+        }
+        Timer::after(retry.backoff(attempt)).await;
+    }
+    send_once(req, &allowed, timeouts, backend, address(retries)).await
+}
+
+/// send `req` once, to the allowed address of index `address`
+async fn send_once(
+    req: Request,
+    allowed: &impl Fn(&IpAddr) -> bool,
+    timeouts: PhaseTimeouts,
+    backend: Option<&str>,
+    address: usize,
+) -> http_types::Result<Response> {
+    let start = Instant::now();
+    let total = duration(timeouts.total_secs);
+    let sending = send_within(req, allowed, timeouts, backend, address);
+    let mut resp = timeout(total, "request", sending).await?;
+    let body = resp.take_body();
+    let len = body.len();
+    let mime = body.mime().clone();
+    let mut body = Body::from_reader(
+        BufReader::new(Watchdog {
+            inner: body,
+            idle: duration(timeouts.body_idle_secs),
+            deadline: total.map(|i| start + i),
+            timer: None,
+        }),
+        len,
+    );
+    body.set_mime(mime);
+    resp.swap_body(&mut body);
+    Ok(resp)
+}
+
+async fn send_within(
+    mut req: Request,
+    allowed: &impl Fn(&IpAddr) -> bool,
+    timeouts: PhaseTimeouts,
+    backend: Option<&str>,
+    address: usize,
+) -> http_types::Result<Response> {
+    // the body is sent at once rather than once the origin tells to continue,
+    // which it may still do, as it may send other interim responses
+    req.remove_header(EXPECT);
+    let (host, port) = match (req.url().host_str(), req.url().port_or_known_default()) {
+        // an ipv6 host is connected to without its brackets
+        (Some(host), Some(port)) => (host.trim_matches(['[', ']']), port),
+        _ => return Err(invalid("invalid request")),
+    };
+    let (host, port) = match backend {
+        Some(backend) => split_address(backend, port),
+        None => (host, port),
+    };
+    let span = info_span!("upstream_connect", otel.kind = "client", host, port);
+    let connecting = connect_to(host, port, allowed, address).instrument(span);
+    let stream = timeout(duration(timeouts.connect_secs), "connect", connecting).await?;
+
+    let response_header = duration(timeouts.response_header_secs);
+    match req.url().scheme() {
+        "https" => {
+            let handshake = async { Ok(async_native_tls::connect(req.url(), stream).await?) }
+                .instrument(info_span!("tls_handshake", host));
+            let tls_handshake = duration(timeouts.tls_handshake_secs);
+            let stream = timeout(tls_handshake, "tls handshake", handshake).await?;
+            let sending = async_h1::connect(SkipInterim::new(stream), req)
+                .instrument(info_span!("upstream_request"));
+            timeout(response_header, "response header", sending).await
+        }
+        "http" => {
+            let sending = async_h1::connect(SkipInterim::new(stream), req)
+                .instrument(info_span!("upstream_request"));
+            timeout(response_header, "response header", sending).await
+        }
+        s => Err(invalid(&format!("unsupported scheme: {}", s))),
+    }
+}
+
+/// connect to the first address of `host` which `allowed` accepts, a
+/// refused one is an error with status 403
+pub async fn connect(
+    host: &str,
+    port: u16,
+    allowed: impl Fn(&IpAddr) -> bool,
+) -> http_types::Result<Async<TcpStream>> {
+    connect_to(host, port, &allowed, 0).await
+}
+
+/// like [`connect`], to the allowed address of index `address`, wrapping
+/// around
+async fn connect_to(
+    host: &str,
+    port: u16,
+    allowed: &impl Fn(&IpAddr) -> bool,
+    address: usize,
+) -> http_types::Result<Async<TcpStream>> {
+    let addrs: Vec<_> = resolve((host, port))
+        .await?
+        .into_iter()
+        .filter(|addr| allowed(&addr.ip()))
+        .collect();
+    if addrs.is_empty() {
+        let msg = format!("upstream address of {} not allowed", host);
+        return Err(http_types::Error::from_str(StatusCode::Forbidden, msg));
+    }
+    Ok(Async::<TcpStream>::connect(addrs[address % addrs.len()]).await?)
+}
+
+pub async fn resolve_first<T: AsyncToSocketAddrs>(s: T) -> Result<SocketAddr> {
+    Ok(*resolve(s)
+        .await?
+        .first()
+        .ok_or_else(|| anyhow!("invalid address"))?)
+}
+
+/// the host and port of `host`, `host:port` or `[ipv6]:port`, `port` if
+/// it has none
+pub fn split_address(address: &str, port: u16) -> (&str, u16) {
+    match split_port(address) {
+        (host, Some(p)) => match p.parse() {
+            Ok(p) => (host.trim_matches(['[', ']']), p),
+            Err(_) => (address, port),
+        },
+        (host, None) => (host.trim_matches(['[', ']']), port),
+    }
+}
+
+/// `host`, `host:port` or `[ipv6]:port` split before its port, if it has
+/// one, an ipv6 host keeps its brackets
+pub fn split_port(address: &str) -> (&str, Option<&str>) {
+    match address.rsplit_once(':') {
+        Some((host, port)) if !host.contains(':') || host.ends_with(']') => (host, Some(port)),
+        _ => (address, None),
+    }
+}
+
+/// `secs` of a timeout, 0 for none
+fn duration(secs: Option<u64>) -> Option<Duration> {
+    secs.filter(|i| *i > 0).map(Duration::from_secs)
+}
+
+/// `f`, or an error with status 504 if it takes longer than `limit`
+async fn timeout<T>(
+    limit: Option<Duration>,
+    phase: &str,
+    f: impl Future<Output = http_types::Result<T>>,
+) -> http_types::Result<T> {
+    match limit {
+        Some(limit) => {
+            f.or(async {
+                Timer::after(limit).await;
+                Err(timed_out(phase))
+            })
+            .await
+        }
+        None => f.await,
+    }
+}
+
+fn timed_out(phase: &str) -> http_types::Error {
+    let e = io::Error::new(
+        io::ErrorKind::TimedOut,
+        format!("upstream {} timed out", phase),
+    );
+    http_types::Error::new(StatusCode::GatewayTimeout, e)
+}
+
+/// a response body failing with a timeout once it reads nothing for
+/// `idle`, or reaches `deadline`
+struct Watchdog<R> {
+    inner: R,
+    idle: Option<Duration>,
+    deadline: Option<Instant>,
+    timer: Option<Timer>,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Watchdog<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.deadline.is_some_and(|i| Instant::now() >= i) {
+            return Poll::Ready(Err(body_timed_out()));
+        }
+        if let Poll::Ready(read) = Pin::new(&mut self.inner).poll_read(cx, buf) {
+            self.timer = None;
+            return Poll::Ready(read);
+        }
+        if self.timer.is_none() {
+            let idle = self.idle.map(|i| Instant::now() + i);
+            let Some(at) = [idle, self.deadline].into_iter().flatten().min() else {
+                return Poll::Pending;
+            };
+            self.timer = Some(Timer::at(at));
+        }
+        match self.timer.as_mut().map(|timer| Pin::new(timer).poll(cx)) {
+            Some(Poll::Ready(_)) => Poll::Ready(Err(body_timed_out())),
+            _ => Poll::Pending,
+        }
+    }
+}
+
+/// a connection to an origin whose interim responses, like `100 Continue`
+/// or `103 Early Hints`, are skipped, as they would be taken for the final
+/// one
+struct SkipInterim<S> {
+    inner: S,
+    /// received of the heads of responses
+    head: Vec<u8>,
+    /// whether the final response was received
+    skipped: bool,
+}
+
+impl<S> SkipInterim<S> {
+    fn new(inner: S) -> SkipInterim<S> {
+        SkipInterim {
+            inner,
+            head: Vec::new(),
+            skipped: false,
+        }
+    }
+
+    /// drop the interim responses received, until the start of another one
+    /// tells whether it is interim
+    fn skip(&mut self) {
+        // like `HTTP/1.1 100 `
+        while self.head.len() >= 13 {
+            let status = &self.head[9..12];
+            // switching protocols is final
+            if !self.head.starts_with(b"HTTP/1.") || status[0] != b'1' || status == b"101" {
+                self.skipped = true;
+                return;
+            }
+            match self.head.windows(4).position(|i| i == b"\r\n\r\n") {
+                Some(end) => drop(self.head.drain(..end + 4)),
+                None => return,
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for SkipInterim<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        while !this.skipped {
+            let mut chunk = [0; 1024];
+            let n = ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk))?;
+            this.head.extend_from_slice(&chunk[..n]);
+            if n == 0 {
+                this.skipped = true;
+            }
+            this.skip();
+        }
+        if this.head.is_empty() {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+        let n = buf.len().min(this.head.len());
+        buf[..n].copy_from_slice(&this.head[..n]);
+        this.head.drain(..n);
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for SkipInterim<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+fn body_timed_out() -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, "upstream body timed out")
+}
+
+fn invalid(msg: &str) -> http_types::Error {
+    http_types::Error::from_str(StatusCode::BadRequest, msg.to_string())
+}
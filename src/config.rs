@@ -1,31 +1,622 @@
-use std::{collections::HashMap, fs::File, sync::LazyLock};
+use std::{
+    collections::HashMap,
+    fs::File,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 use anyhow::Result;
-use serde::Deserialize;
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
 
-pub static CONFIG: LazyLock<Config> = LazyLock::new(|| Config::from_env().unwrap());
+use crate::{
+    access_log, client,
+    ip::{is_public, IpNet},
+    oidc,
+};
 
 #[derive(Deserialize, Debug)]
 pub struct Config {
     pub listen_address: String,
     pub domain_name: HashMap<String, String>,
+    /// regex mappings of hosts, for those `domain_name` can't list
+    #[serde(default)]
+    pub domain_rules: Vec<DomainRule>,
+    /// mirror any other origin as a subdomain of this domain, encoding its
+    /// host like `www.example.com` as `www-example-com.{catch_all_domain}`,
+    /// which lets anyone reach any host, so hosts given as addresses, or
+    /// resolving to non-public ones, are refused
+    pub catch_all_domain: Option<String>,
+    /// non-public addresses hosts of the catch-all domain may still reach
+    #[serde(default)]
+    pub catch_all_allow: Vec<IpNet>,
+    /// origins mirrored under a path of a mirror domain, like
+    /// `"m.com/gh" = "github.com"`, for when only one domain is available
+    #[serde(default)]
+    pub path_prefix: HashMap<String, String>,
+    /// answer requests for hosts no mapping has with 421, instead of
+    /// proxying them to wherever they point
+    #[serde(default = "default_true")]
+    pub strict_hosts: bool,
+    #[serde(default)]
+    pub upstream_policy: UpstreamPolicy,
+    /// also act as a forward proxy set in browsers, tunneling `CONNECT`
+    /// requests, plain http ones are mirrored as usual
+    #[serde(default)]
+    pub forward_proxy: bool,
+    /// name of this instance in `Via` headers, instances chained on purpose
+    /// need different ones
+    #[serde(default = "default_via_name")]
+    pub via_name: String,
+    /// requests which passed this instance as often are loops
+    #[serde(default = "default_max_hops")]
+    pub max_hops: usize,
+    #[serde(default)]
+    pub forwarded_headers: ForwardedHeaders,
+    /// proxies in front of the mirror, whose `X-Scheme`, `X-Forwarded-*`
+    /// and `X-Real-IP` headers are believed
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpNet>,
+    /// connections start with a PROXY protocol header, version 1 or 2,
+    /// whose client address is used instead of the peer's
+    #[serde(default)]
+    pub proxy_protocol: bool,
+    /// threads handling connections, the number of cpus if unset
+    pub worker_threads: Option<usize>,
+    /// further connections are answered with 503 and closed
+    pub max_connections: Option<usize>,
+    /// further requests are answered with 503
+    pub max_concurrent_requests: Option<usize>,
+    /// larger request bodies, in bytes, are answered with 413
+    pub max_request_body_size: Option<u64>,
     pub use_https: Option<Vec<String>>,
     pub data_dir: String,
+    /// request and response bodies of these types have their domains replaced
+    #[serde(default = "default_rewrite_content_types")]
+    pub rewrite_content_types: Vec<String>,
+    /// rewrite html by its markup, only urls of attributes, meta refresh and
+    /// inline scripts and styles, instead of replacing domains everywhere
+    #[serde(default)]
+    pub html_rewriter: bool,
+    /// rewrite scripts only inside their string and template literals
+    #[serde(default)]
+    pub js_rewriter: bool,
+    /// attributes holding urls, rewritten by the html rewriter
+    #[serde(default = "default_html_url_attributes")]
+    pub html_url_attributes: Vec<String>,
+    /// headers removed from upstream responses
+    #[serde(default = "default_strip_response_headers")]
+    pub strip_response_headers: Vec<String>,
+    /// larger response bodies are not rewritten, in bytes
+    pub max_rewrite_body_size: Option<u64>,
+    #[serde(default)]
+    pub oversized_body: Oversized,
+    /// encodings the upstream may use, the client's `Accept-Encoding` is
+    /// limited to them, an empty list asks for identity
+    pub upstream_accept_encoding: Option<Vec<String>>,
+    /// mirror domains whose request and response bodies are streamed as
+    /// they are, never rewritten nor cached, for grpc-web and other binary
+    /// protocols over http/1.1
+    #[serde(default)]
+    pub passthrough: Vec<String>,
+    /// rewrite bodies of unknown length as they arrive, only replacing
+    /// domains, instead of once they are read whole
+    #[serde(default)]
+    pub stream_rewrite: bool,
+    /// encode rewritten bodies again, otherwise they are sent uncompressed
+    #[serde(default = "default_true")]
+    pub recompress: bool,
+    /// quality of the encoders, clamped to each algorithm's maximum
+    /// (9 for gzip and deflate, 11 for brotli), their default if omitted
+    pub recompression_level: Option<i32>,
     pub authorization: Authorization,
+    #[serde(default)]
+    pub access_control: AccessControl,
+    pub access_log: Option<AccessLog>,
+    /// mirror domain to how its service workers are handled
+    pub service_worker: Option<HashMap<String, ServiceWorker>>,
+    /// mirror domain to how its sitemaps are served
+    pub sitemap: Option<HashMap<String, Sitemap>>,
+    /// mirror domain to the robots.txt served instead of the origin's
+    pub robots: Option<HashMap<String, Robots>>,
+    /// mirror domain to the request methods it allows, any if not listed
+    pub allowed_methods: Option<HashMap<String, Vec<String>>>,
+    /// mirror domain to the paths it blocks or exclusively allows
+    pub path_rules: Option<HashMap<String, PathRules>>,
+    /// mirror domain to the cross origin requests it allows, overriding the
+    /// origin's
+    pub cors: Option<HashMap<String, Cors>>,
+    /// mirror domain to css selectors of elements removed from its pages,
+    /// like ads or cookie banners
+    pub remove_elements: Option<HashMap<String, Vec<String>>>,
+    /// answered before proxying, the first matching one applies
+    #[serde(default)]
+    pub redirect: Vec<Redirect>,
+    /// redirect requests the client sent over plain http to https with 301,
+    /// their scheme is told by a trusted proxy terminating tls in front
+    #[serde(default)]
+    pub https_redirect: bool,
+    /// alternate mirror domain to the one it is redirected to with 301,
+    /// like `"www.x.com" = "x.com"`, alternates need no mapping
+    #[serde(default)]
+    pub canonical_hosts: HashMap<String, String>,
+    /// local directories served under a path of mirror domains
+    #[serde(default)]
+    pub static_files: Vec<StaticFiles>,
+    /// status code of a failure, 500, 502 or 504, to the html template of
+    /// its page
+    pub error_pages: Option<HashMap<String, String>>,
+    #[serde(default)]
+    pub maintenance: Maintenance,
+    #[serde(default)]
+    pub csp: Csp,
+    #[serde(default)]
+    pub range: Ranges,
+    #[serde(default)]
+    pub timeouts: Timeouts,
+    #[serde(default)]
+    pub request_head: RequestHead,
+    #[serde(default)]
+    pub retry: Retry,
+    pub rate_limit: Option<RateLimit>,
+    #[serde(default)]
+    pub bandwidth: Bandwidth,
+    pub quota: Option<Quota>,
+    pub circuit_breaker: Option<CircuitBreaker>,
+    /// origin host to the backends serving it, connected to instead
+    #[serde(default)]
+    pub backends: HashMap<String, Backends>,
+    /// headers set on every response of the mirror, replacing the origin's
+    pub security_headers: Option<HashMap<String, String>>,
+    /// applied in order after the built-in header rewriting
+    #[serde(default)]
+    pub header_rules: Vec<HeaderRule>,
+    /// find and replace in rewritten bodies, applied in order after domains
+    /// are replaced
+    #[serde(default)]
+    pub substitute: Vec<Substitute>,
+    /// snippets added to the rewritten html pages
+    #[serde(default)]
+    pub inject: Vec<Inject>,
+    /// mirror domain to its settings, replacing the global ones
+    #[serde(default)]
+    pub domains: HashMap<String, Profile>,
+    pub readiness: Option<Readiness>,
+    pub admin: Option<Admin>,
+    pub cache: Option<Cache>,
+    pub memory_cache: Option<MemoryCache>,
+    #[cfg(feature = "otlp")]
+    pub otlp: Option<Otlp>,
+    #[cfg(feature = "lua")]
+    pub lua: Option<Lua>,
+    /// plugins run in order
+    #[cfg(feature = "wasm")]
+    #[serde(default)]
+    pub wasm: Vec<Wasm>,
+    /// the file read, which the admin api reloads
+    #[serde(skip)]
+    pub file: Option<PathBuf>,
 }
 
 impl Config {
+    /// read the file of `CONFIG_FILE`
     pub fn from_env() -> Result<Config> {
-        let file = std::env::var("CONFIG_FILE")?;
+        Config::from_file(std::env::var("CONFIG_FILE")?)
+    }
+
+    pub fn from_file(file: impl AsRef<Path>) -> Result<Config> {
+        let path = file.as_ref().to_path_buf();
         let file = File::open(file)?;
-        let config = std::io::read_to_string(file)?;
-        let config = toml::from_str(&config)?;
+        let mut config = Config::from_toml(&std::io::read_to_string(file)?)?;
+        config.file = Some(path);
         Ok(config)
     }
 
+    pub fn from_toml(config: &str) -> Result<Config> {
+        let mut config: Config = toml::from_str(config)?;
+        config.apply_profiles();
+        config.ascii_domains()?;
+        Ok(config)
+    }
+
+    pub fn worker_threads(&self) -> usize {
+        self.worker_threads
+            .or_else(|| std::thread::available_parallelism().ok().map(|i| i.get()))
+            .unwrap_or(1)
+            .max(1)
+    }
+
+    /// build a config in code instead of a file
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder {
+            table: toml::Table::new(),
+        }
+    }
+
+    /// move the settings of `domains` kept elsewhere per mirror domain there
+    fn apply_profiles(&mut self) {
+        for (domain, profile) in &mut self.domains {
+            if let Some(timeouts) = profile.timeouts {
+                self.timeouts.domain.insert(domain.clone(), timeouts);
+            }
+            for mut inject in profile.inject.drain(..) {
+                inject.domain = Some(domain.clone());
+                self.inject.push(inject);
+            }
+            match profile.authorization {
+                Some(true) => {
+                    let domain_list = self.authorization.domain_list.get_or_insert_default();
+                    domain_list.push(domain.clone());
+                    let policy = self.authorization.policy.as_mut();
+                    if let Some(policy) = policy.and_then(|i| i.get_mut(domain)) {
+                        policy.enabled = true;
+                    }
+                }
+                Some(false) => {
+                    let policy = self.authorization.policy.get_or_insert_default();
+                    policy.entry(domain.clone()).or_default().enabled = false;
+                }
+                None => (),
+            }
+        }
+    }
+
+    /// domains in unicode are kept in their ascii form, as hosts are
+    /// requested, bodies are rewritten in both forms
+    fn ascii_domains(&mut self) -> Result<()> {
+        let origin = |origin: String| -> Result<String> {
+            let host = Origin::parse(&origin)?.host;
+            Ok(origin.replacen(host, &ascii_domain(host)?, 1))
+        };
+        let prefix = |mirror: String| -> Result<String> {
+            match mirror.split_once('/') {
+                Some((host, path)) => Ok(format!("{}/{}", ascii_domain(host)?, path)),
+                None => Ok(mirror),
+            }
+        };
+        self.domain_name = std::mem::take(&mut self.domain_name)
+            .into_iter()
+            .map(|(mirror, o)| Ok((ascii_domain(&mirror)?, origin(o)?)))
+            .collect::<Result<_>>()?;
+        self.path_prefix = std::mem::take(&mut self.path_prefix)
+            .into_iter()
+            .map(|(mirror, o)| Ok((prefix(mirror)?, ascii_domain(&o)?)))
+            .collect::<Result<_>>()?;
+        self.canonical_hosts = std::mem::take(&mut self.canonical_hosts)
+            .into_iter()
+            .map(|(alternate, host)| Ok((ascii_domain(&alternate)?, ascii_domain(&host)?)))
+            .collect::<Result<_>>()?;
+        for domain in self
+            .use_https
+            .iter_mut()
+            .chain(self.authorization.domain_list.iter_mut())
+            .flatten()
+            .chain(self.catch_all_domain.iter_mut())
+            .chain(
+                self.header_rules
+                    .iter_mut()
+                    .filter_map(|i| i.domain.as_mut()),
+            )
+            .chain(self.redirect.iter_mut().filter_map(|i| i.host.as_mut()))
+            .chain(
+                self.static_files
+                    .iter_mut()
+                    .filter_map(|i| i.domain.as_mut()),
+            )
+            .chain(self.substitute.iter_mut().filter_map(|i| i.domain.as_mut()))
+            .chain(self.inject.iter_mut().filter_map(|i| i.domain.as_mut()))
+            .chain(self.maintenance.domains.iter_mut())
+            .chain(self.passthrough.iter_mut())
+        {
+            *domain = ascii_domain(domain)?;
+        }
+        ascii_keys(&mut self.domains)?;
+        ascii_keys(&mut self.csp.domain)?;
+        ascii_keys(&mut self.range.domain)?;
+        ascii_keys(&mut self.timeouts.domain)?;
+        ascii_keys(&mut self.backends)?;
+        ascii_keys(&mut self.bandwidth.domain)?;
+        if let Some(rate_limit) = &mut self.rate_limit {
+            ascii_keys(&mut rate_limit.domain)?;
+        }
+        for map in self.service_worker.iter_mut() {
+            ascii_keys(map)?;
+        }
+        for map in self.sitemap.iter_mut() {
+            ascii_keys(map)?;
+        }
+        for map in self.robots.iter_mut() {
+            ascii_keys(map)?;
+        }
+        for map in self.allowed_methods.iter_mut() {
+            ascii_keys(map)?;
+        }
+        for map in self.path_rules.iter_mut() {
+            ascii_keys(map)?;
+        }
+        for map in self.cors.iter_mut() {
+            ascii_keys(map)?;
+        }
+        for map in self.remove_elements.iter_mut() {
+            ascii_keys(map)?;
+        }
+        for map in self.authorization.policy.iter_mut() {
+            ascii_keys(map)?;
+        }
+        for map in self.access_control.domain.iter_mut() {
+            ascii_keys(map)?;
+        }
+        Ok(())
+    }
+
+    /// settings of the mirror domain `host` replacing the global ones
+    pub fn profile(&self, host: &str) -> Option<&Profile> {
+        self.domains
+            .iter()
+            .filter(|(domain, _)| host.contains(domain.as_str()))
+            .max_by_key(|(domain, _)| domain.len())
+            .map(|(_, profile)| profile)
+    }
+
+    /// whether bodies of the mirror domain `host` are rewritten at all
+    pub fn rewrites_bodies(&self, host: &str) -> bool {
+        self.profile(host).and_then(|i| i.rewrite).unwrap_or(true)
+    }
+
+    /// whether bodies of `content_type` are rewritten for the mirror domain `host`
+    pub fn rewrites(&self, host: &str, content_type: &str) -> bool {
+        if !self.rewrites_bodies(host) {
+            return false;
+        }
+        self.profile(host)
+            .and_then(|i| i.rewrite_content_types.as_ref())
+            .unwrap_or(&self.rewrite_content_types)
+            .iter()
+            .any(|i| i == content_type)
+    }
+
+    /// whether the origin of the mirror domain `host` is requested over https
+    pub fn use_https(&self, host: &str) -> bool {
+        if let Some(https) = self.profile(host).and_then(|i| i.use_https) {
+            return https;
+        }
+        self.use_https
+            .iter()
+            .flatten()
+            .any(|i| i == host || i.strip_prefix('*').is_some_and(|i| host.ends_with(i)))
+    }
+
+    /// whether responses for the mirror domain `host` are cached
+    pub fn caches(&self, host: &str) -> bool {
+        self.profile(host).and_then(|i| i.cache).unwrap_or(true) && !self.passthrough(host)
+    }
+
+    /// whether bodies of the mirror domain `host` are passed through
+    pub fn passthrough(&self, host: &str) -> bool {
+        self.passthrough.iter().any(|i| host.contains(i.as_str()))
+    }
+
+    /// service worker strategy of the mirror domain `host`
+    pub fn service_worker(&self, host: &str) -> ServiceWorker {
+        self.service_worker
+            .as_ref()
+            .and_then(|service_worker| {
+                service_worker
+                    .iter()
+                    .filter(|(domain, _)| host.contains(domain.as_str()))
+                    .max_by_key(|(domain, _)| domain.len())
+            })
+            .map_or_else(ServiceWorker::default, |(_, strategy)| *strategy)
+    }
+
+    /// request methods the mirror domain `host` allows, any if `None`
+    pub fn allowed_methods(&self, host: &str) -> Option<&[String]> {
+        self.allowed_methods.as_ref().and_then(|allowed_methods| {
+            allowed_methods
+                .iter()
+                .filter(|(domain, _)| host.contains(domain.as_str()))
+                .max_by_key(|(domain, _)| domain.len())
+                .map(|(_, methods)| methods.as_slice())
+        })
+    }
+
+    /// path rules of the mirror domain `host`
+    pub fn path_rules(&self, host: &str) -> Option<&PathRules> {
+        self.path_rules.as_ref().and_then(|path_rules| {
+            path_rules
+                .iter()
+                .filter(|(domain, _)| host.contains(domain.as_str()))
+                .max_by_key(|(domain, _)| domain.len())
+                .map(|(_, rules)| rules)
+        })
+    }
+
+    /// cross origin requests the mirror domain `host` allows
+    pub fn cors(&self, host: &str) -> Option<&Cors> {
+        self.cors.as_ref().and_then(|cors| {
+            cors.iter()
+                .filter(|(domain, _)| host.contains(domain.as_str()))
+                .max_by_key(|(domain, _)| domain.len())
+                .map(|(_, cors)| cors)
+        })
+    }
+
+    /// selectors of the elements removed from pages of the mirror domain `host`
+    pub fn remove_elements(&self, host: &str) -> Option<&[String]> {
+        self.remove_elements.as_ref().and_then(|remove_elements| {
+            remove_elements
+                .iter()
+                .filter(|(domain, _)| host.contains(domain.as_str()))
+                .max_by_key(|(domain, _)| domain.len())
+                .map(|(_, selectors)| selectors.as_slice())
+        })
+    }
+
+    /// sitemap handling of the mirror domain `host`
+    pub fn sitemap(&self, host: &str) -> Sitemap {
+        self.sitemap
+            .as_ref()
+            .and_then(|sitemap| {
+                sitemap
+                    .iter()
+                    .filter(|(domain, _)| host.contains(domain.as_str()))
+                    .max_by_key(|(domain, _)| domain.len())
+            })
+            .map_or_else(Sitemap::default, |(_, sitemap)| *sitemap)
+    }
+
+    /// `domain_name` as (mirror, origin) patterns, a wildcard mapping
+    /// `"*.mirror" = "*.origin"` becomes `(".mirror", ".origin")`, so any
+    /// subdomain is carried over by replacing the suffix
+    pub fn domain_patterns(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.domain_name.iter().map(|(mirror, origin)| {
+            let origin = Origin::parse(origin).map_or(origin.as_str(), |i| i.host);
+            match (mirror.strip_prefix('*'), origin.strip_prefix('*')) {
+                (Some(mirror), Some(origin)) => (mirror, origin),
+                _ => (mirror.as_str(), origin),
+            }
+        })
+    }
+
+    /// whether the mirror `host` is mapped to an origin, by a domain, a path
+    /// prefix, a rule or the catch-all domain
+    pub fn is_mirror(&self, host: &str) -> bool {
+        self.is_mapped(host) || self.is_catch_all_subdomain(host)
+    }
+
+    /// whether the mirror `host` is mapped to an origin only by the
+    /// catch-all domain
+    pub fn is_catch_all(&self, host: &str) -> bool {
+        !self.is_mapped(host) && self.is_catch_all_subdomain(host)
+    }
+
+    fn is_mapped(&self, host: &str) -> bool {
+        self.origin(host).is_some()
+            || self
+                .path_prefix
+                .keys()
+                .any(|i| i.split('/').next() == Some(host))
+            || self.domain_rules.iter().any(|i| i.mirror.is_match(host))
+    }
+
+    fn is_catch_all_subdomain(&self, host: &str) -> bool {
+        self.catch_all_domain.as_ref().is_some_and(|domain| {
+            host.strip_suffix(domain.as_str())
+                .is_some_and(|i| i.len() > 1 && i.ends_with('.'))
+        })
+    }
+
+    /// whether a host of the catch-all domain may reach `ip`
+    pub fn catch_all_allows(&self, ip: &IpAddr) -> bool {
+        is_public(ip) || self.catch_all_allow.iter().any(|net| net.contains(ip))
+    }
+
+    /// whether `host` is an origin of a mapping, any host is with the
+    /// catch-all domain
+    pub fn is_origin(&self, host: &str) -> bool {
+        let domain = self
+            .domain_patterns()
+            .any(|(mirror, origin)| match mirror.starts_with('.') {
+                true => host.ends_with(origin),
+                false => host == origin,
+            });
+        domain
+            || self.path_prefix.values().any(|i| i == host)
+            || self.domain_rules.iter().any(|i| i.origin.is_match(host))
+            || self.catch_all_domain.is_some()
+    }
+
+    /// (mirror, origin with its port) patterns of the origins mapped with a
+    /// port, their urls map to the mirror without it
+    pub fn port_patterns(&self) -> Vec<(&str, String)> {
+        self.domain_patterns()
+            .zip(self.domain_name.values())
+            .filter_map(|((mirror, origin), value)| {
+                let port = Origin::parse(value).ok()?.port?;
+                Some((mirror, format!("{}:{}", origin, port)))
+            })
+            .collect()
+    }
+
+    /// the origin `domain_name` maps the mirror `host` to, by the longest
+    /// domain or wildcard matching it
+    pub fn origin(&self, host: &str) -> Option<Origin<'_>> {
+        self.domain_name
+            .iter()
+            .filter(|(mirror, _)| match mirror.strip_prefix('*') {
+                Some(suffix) => host.ends_with(suffix),
+                None => host == mirror.as_str(),
+            })
+            .max_by_key(|(mirror, _)| mirror.len())
+            .and_then(|(_, origin)| Origin::parse(origin).ok())
+    }
+
+    /// the origin mirrored under a path prefix of the mirror domain `host`
+    /// matching `path`, with that prefix, like `/gh`
+    pub fn path_prefix(&self, host: &str, path: &str) -> Option<(&str, &str)> {
+        self.path_prefix
+            .iter()
+            .filter_map(|(mirror, origin)| {
+                let prefix = mirror.strip_prefix(host)?;
+                let rest = path.strip_prefix(prefix)?;
+                (prefix.starts_with('/') && (rest.is_empty() || rest.starts_with('/')))
+                    .then_some((origin.as_str(), prefix))
+            })
+            .max_by_key(|(_, prefix)| prefix.len())
+    }
+
     pub fn check_domain(&self) -> Result<()> {
-        for i in self.domain_name.keys() {
-            for j in self.domain_name.keys() {
+        for mirror in self.path_prefix.keys() {
+            anyhow::ensure!(
+                mirror
+                    .find('/')
+                    .is_some_and(|i| i > 0 && !mirror.ends_with('/')),
+                "path prefix \"{}\" must be a domain followed by a path, like \"m.com/gh\"",
+                mirror
+            );
+        }
+        for redirect in &self.redirect {
+            anyhow::ensure!(
+                [301, 302, 303, 307, 308].contains(&redirect.status),
+                "redirect status must be 301, 302, 303, 307 or 308, not {}",
+                redirect.status
+            );
+        }
+        for selector in self
+            .remove_elements
+            .iter()
+            .flat_map(|i| i.values().flatten())
+        {
+            if let Err(e) = selector.parse::<lol_html::Selector>() {
+                anyhow::bail!("invalid selector \"{}\": {}", selector, e);
+            }
+        }
+        for (origin, backends) in &self.backends {
+            anyhow::ensure!(!backends.hosts.is_empty(), "no backends of {}", origin);
+            if let Some(url) = backends.health_check.as_ref().and_then(|i| i.url.as_ref()) {
+                http_types::Url::parse(url)?;
+            }
+        }
+        if let Some(oidc) = &self.authorization.oidc {
+            let urls = [Some(&oidc.issuer), oidc.token_endpoint.as_ref()];
+            for url in urls.into_iter().flatten() {
+                oidc::check_https(url)?;
+            }
+        }
+        for (mirror, origin) in &self.domain_name {
+            let origin = Origin::parse(origin)?.host;
+            anyhow::ensure!(
+                mirror.starts_with("*.") == origin.starts_with("*."),
+                "wildcard domain \"{}\" must map to a wildcard domain, not \"{}\"",
+                mirror,
+                origin
+            );
+        }
+        // a wildcard only matches subdomains, the longest match wins over it
+        let exact = || self.domain_name.keys().filter(|i| !i.starts_with('*'));
+        for i in exact() {
+            for j in exact() {
                 anyhow::ensure!(
                     !(j != i && j.contains(i)),
                     "conflict two domain \"{}\" and \"{}\"",
@@ -38,11 +629,1186 @@ impl Config {
     }
 }
 
+/// a config built in code, from the settings of the config file, those not
+/// set default as if omitted there
+#[derive(Debug, Clone)]
+pub struct ConfigBuilder {
+    table: toml::Table,
+}
+
+impl ConfigBuilder {
+    pub fn listen(self, address: &str) -> Self {
+        self.set("listen_address", address)
+    }
+
+    pub fn data_dir(self, dir: &str) -> Self {
+        self.set("data_dir", dir)
+    }
+
+    /// mirror `origin` as `mirror`
+    pub fn map_domain(mut self, mirror: &str, origin: &str) -> Self {
+        self.table("domain_name")
+            .insert(mirror.to_string(), origin.into());
+        self
+    }
+
+    /// require login for the mirror `domain`, with an account of `username`
+    pub fn auth(mut self, domain: &str, username: &str, password: &str) -> Self {
+        let authorization = self.table("authorization");
+        authorization.insert("enabled".to_string(), true.into());
+        let mut account = toml::Table::new();
+        account.insert("username".to_string(), username.into());
+        account.insert("password".to_string(), password.into());
+        for (name, value) in [("domain_list", domain.into()), ("account", account.into())] {
+            if let toml::Value::Array(list) = authorization
+                .entry(name)
+                .or_insert_with(|| toml::Value::Array(Vec::new()))
+            {
+                list.push(value);
+            }
+        }
+        self
+    }
+
+    /// any other setting, by its name in the config file, like
+    /// `.set("strict_hosts", false)`
+    pub fn set(mut self, name: &str, value: impl Into<toml::Value>) -> Self {
+        self.table.insert(name.to_string(), value.into());
+        self
+    }
+
+    pub fn build(mut self) -> Result<Config> {
+        self.table("authorization")
+            .entry("enabled")
+            .or_insert(false.into());
+        let mut config: Config = toml::Value::Table(self.table).try_into()?;
+        config.apply_profiles();
+        config.ascii_domains()?;
+        Ok(config)
+    }
+
+    fn table(&mut self, name: &str) -> &mut toml::Table {
+        let value = self
+            .table
+            .entry(name)
+            .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+        if !value.is_table() {
+            *value = toml::Value::Table(toml::Table::new());
+        }
+        value.as_table_mut().unwrap()
+    }
+}
+
+/// an origin of `domain_name`, like `https://origin.com:8443`, whose scheme
+/// and port, if given, are used instead of the mirror request's
+#[derive(Debug)]
+pub struct Origin<'a> {
+    pub scheme: Option<&'a str>,
+    pub host: &'a str,
+    pub port: Option<u16>,
+}
+
+impl<'a> Origin<'a> {
+    pub fn parse(origin: &'a str) -> Result<Origin<'a>> {
+        let (scheme, rest) = match origin.split_once("://") {
+            Some((scheme, rest)) => (Some(scheme), rest),
+            None => (None, origin),
+        };
+        anyhow::ensure!(
+            scheme.is_none_or(|i| i == "http" || i == "https"),
+            "unsupported scheme of origin \"{}\"",
+            origin
+        );
+        let (host, port) = match client::split_port(rest) {
+            (host, Some(port)) => match port.parse() {
+                Ok(port) => (host, Some(port)),
+                Err(_) => anyhow::bail!("invalid port of origin \"{}\"", origin),
+            },
+            (host, None) => (host, None),
+        };
+        Ok(Origin { scheme, host, port })
+    }
+}
+
+/// the ascii form of a domain, like `xn--r8jz45g.com` of `例え.com`,
+/// keeping a wildcard
+fn ascii_domain(domain: &str) -> Result<String> {
+    let (wildcard, name) = match domain.strip_prefix("*.") {
+        Some(name) => ("*.", name),
+        None => ("", domain),
+    };
+    if name.is_ascii() {
+        return Ok(domain.to_string());
+    }
+    match idna::domain_to_ascii(name) {
+        Ok(name) => Ok(format!("{}{}", wildcard, name)),
+        Err(_) => anyhow::bail!("invalid domain \"{}\"", domain),
+    }
+}
+
+fn ascii_keys<V>(map: &mut HashMap<String, V>) -> Result<()> {
+    *map = std::mem::take(map)
+        .into_iter()
+        .map(|(domain, v)| Ok((ascii_domain(&domain)?, v)))
+        .collect::<Result<_>>()?;
+    Ok(())
+}
+
+fn default_rewrite_content_types() -> Vec<String> {
+    [
+        "text/html",
+        "text/plain",
+        "text/css",
+        "text/javascript",
+        "application/json",
+        "application/manifest+json",
+        "application/x-www-form-urlencoded",
+        "multipart/form-data",
+        "application/vnd.apple.mpegurl",
+        "application/x-mpegurl",
+        "audio/mpegurl",
+        "application/dash+xml",
+        "application/rss+xml",
+        "application/atom+xml",
+        "application/xml",
+        "text/xml",
+        "image/svg+xml",
+        "text/event-stream",
+    ]
+    .map(String::from)
+    .to_vec()
+}
+
+fn default_strip_response_headers() -> Vec<String> {
+    ["strict-transport-security", "expect-ct", "alt-svc"]
+        .map(String::from)
+        .to_vec()
+}
+
+fn default_html_url_attributes() -> Vec<String> {
+    [
+        "href",
+        "src",
+        "action",
+        "srcset",
+        "poster",
+        "data-src",
+        "data-srcset",
+    ]
+    .map(String::from)
+    .to_vec()
+}
+
+/// what upstream learns of the client and the mirror from `X-Forwarded-*`
+/// and `Forwarded` headers
+#[derive(Deserialize, Default, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ForwardedHeaders {
+    /// remove them, so upstream only sees the mirror
+    #[default]
+    Strip,
+    /// append the client address and set the scheme and host of the mirror
+    Append,
+}
+
+/// what to do with a response body larger than `max_rewrite_body_size`
+#[derive(Deserialize, Default, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Oversized {
+    /// send it without rewriting
+    #[default]
+    Pass,
+    /// answer 502 instead
+    Reject,
+}
+
+/// service workers cache origin urls, breaking the mirror on later visits
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ServiceWorker {
+    /// rewrite worker scripts whatever their content type
+    #[default]
+    Rewrite,
+    /// answer 404 to worker scripts, so none can be registered
+    Block,
+    /// block them and unregister installed ones from every html page
+    Unregister,
+}
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Sitemap {
+    /// rewrite the urls of the origin's sitemaps
+    #[default]
+    Rewrite,
+    /// serve empty sitemaps, keeping search engines away from the mirror
+    Empty,
+}
+
+/// maps hosts matching `origin` to `to_mirror` and those matching `mirror`
+/// back to `to_origin`, where `$1` or `${name}` refer to groups, patterns
+/// should be anchored with `^` and `$` to match whole hosts
+#[derive(Deserialize, Debug)]
+pub struct DomainRule {
+    #[serde(deserialize_with = "deserialize_regex")]
+    pub origin: Regex,
+    pub to_mirror: String,
+    #[serde(deserialize_with = "deserialize_regex")]
+    pub mirror: Regex,
+    pub to_origin: String,
+}
+
+/// a path pattern where `*` matches any characters, `/` included, like
+/// `/admin/*`
+#[derive(Deserialize, Debug)]
+#[serde(try_from = "String")]
+pub struct PathPattern(Regex);
+
+impl PathPattern {
+    pub fn matches(&self, path: &str) -> bool {
+        self.0.is_match(path)
+    }
+}
+
+impl TryFrom<String> for PathPattern {
+    type Error = regex::Error;
+
+    fn try_from(pattern: String) -> Result<PathPattern, regex::Error> {
+        let parts: Vec<_> = pattern.split('*').map(regex::escape).collect();
+        Ok(PathPattern(Regex::new(&format!("^{}$", parts.join(".*")))?))
+    }
+}
+
+/// paths of a mirror domain answered with 403
+#[derive(Deserialize, Debug)]
+pub struct PathRules {
+    #[serde(default)]
+    pub block: Vec<PathPattern>,
+    /// the only paths allowed, if given
+    pub allow: Option<Vec<PathPattern>>,
+}
+
+impl PathRules {
+    pub fn allows(&self, path: &str) -> bool {
+        !self.block.iter().any(|i| i.matches(path))
+            && self
+                .allow
+                .as_ref()
+                .is_none_or(|allow| allow.iter().any(|i| i.matches(path)))
+    }
+}
+
+/// `Access-Control-Allow-*` headers sent by a mirror domain
+#[derive(Deserialize, Debug)]
+pub struct Cors {
+    /// origins allowed to call it, like `https://app.example.com`, or `*`
+    pub allow_origins: Vec<String>,
+    #[serde(default = "default_cors_methods")]
+    pub allow_methods: Vec<String>,
+    /// request headers allowed, those asked for by preflights if empty
+    #[serde(default)]
+    pub allow_headers: Vec<String>,
+    /// response headers scripts may read
+    #[serde(default)]
+    pub expose_headers: Vec<String>,
+    /// cookies and authorization may be sent
+    #[serde(default)]
+    pub allow_credentials: bool,
+    /// how long browsers may keep the answer of a preflight
+    pub max_age_secs: Option<u64>,
+    /// answer preflights here, instead of passing them upstream
+    #[serde(default = "default_true")]
+    pub preflight: bool,
+}
+
+impl Cors {
+    pub fn allows(&self, origin: &str) -> bool {
+        self.allow_origins
+            .iter()
+            .any(|i| i == "*" || i.eq_ignore_ascii_case(origin))
+    }
+}
+
+/// settings of a mirror domain, those omitted are the global ones
+///
+/// they win over the same settings kept per domain elsewhere: `timeouts`
+/// over `timeouts.domain`, `authorization` over `domain_list` and policies,
+/// and `use_https` over its list, while `inject` adds to the global one and
+/// `passthrough` still turns off `rewrite` and `cache`. The other per domain
+/// settings, like `csp`, `range`, `service_worker` or `cors`, stay in their
+/// own sections
+#[derive(Deserialize, Default, Debug)]
+#[serde(default)]
+pub struct Profile {
+    /// set to false to proxy bodies as they are, neither request nor
+    /// response bodies are rewritten, headers still are
+    pub rewrite: Option<bool>,
+    pub rewrite_content_types: Option<Vec<String>>,
+    /// login is required, or not even if `domain_list` or a policy has it
+    pub authorization: Option<bool>,
+    /// the origin is requested over https, as if in `use_https`
+    pub use_https: Option<bool>,
+    pub timeouts: Option<PhaseTimeouts>,
+    /// responses are cached, if the cache is on
+    pub cache: Option<bool>,
+    /// snippets added to its pages, besides those of `inject`
+    pub inject: Vec<Inject>,
+}
+
+/// a redirect of requests for a mirror domain or path
+#[derive(Deserialize, Debug)]
+pub struct Redirect {
+    /// mirror domain, any if omitted
+    pub host: Option<String>,
+    /// any if omitted
+    pub path: Option<PathPattern>,
+    /// where to, `{path}` is replaced with the path and query requested
+    pub target: String,
+    #[serde(default = "default_redirect_status")]
+    pub status: u16,
+}
+
+impl Redirect {
+    pub fn matches(&self, host: &str, path: &str) -> bool {
+        self.host.as_ref().is_none_or(|i| i == host)
+            && self.path.as_ref().is_none_or(|i| i.matches(path))
+    }
+}
+
+/// a local directory served by the mirror itself
+#[derive(Deserialize, Debug)]
+pub struct StaticFiles {
+    /// mirror domain, every domain if omitted
+    pub domain: Option<String>,
+    /// path the directory is served under, like `/__assets/`
+    pub prefix: String,
+    pub dir: String,
+}
+
+/// change of a request or response header
+#[derive(Deserialize, Debug)]
+pub struct HeaderRule {
+    /// mirror domain the rule applies to, every domain if omitted
+    pub domain: Option<String>,
+    pub target: HeaderTarget,
+    pub name: String,
+    #[serde(flatten)]
+    pub action: HeaderAction,
+}
+
+impl HeaderRule {
+    pub fn applies(&self, target: HeaderTarget, host: &str) -> bool {
+        self.target == target
+            && self
+                .domain
+                .as_ref()
+                .is_none_or(|domain| host.contains(domain.as_str()))
+    }
+}
+
+/// text replaced in bodies, like an api key or a few strings translated
+#[derive(Deserialize, Debug, Clone)]
+pub struct Substitute {
+    /// mirror domain whose bodies it applies to, every domain if omitted
+    pub domain: Option<String>,
+    pub find: String,
+    /// `find` is a regular expression, whose groups `replace` may refer to
+    /// as `$1` or `${name}`
+    #[serde(default)]
+    pub regex: bool,
+    pub replace: String,
+}
+
+impl Substitute {
+    pub fn applies(&self, host: &str) -> bool {
+        self.domain
+            .as_ref()
+            .is_none_or(|domain| host.contains(domain.as_str()))
+    }
+}
+
+/// html added to pages, like a banner, analytics or styles
+#[derive(Deserialize, Debug, Clone)]
+pub struct Inject {
+    /// mirror domain whose pages get it, every domain if omitted
+    pub domain: Option<String>,
+    #[serde(default)]
+    pub position: InjectPosition,
+    pub snippet: Option<String>,
+    /// read at startup, instead of `snippet`
+    pub file: Option<String>,
+}
+
+impl Inject {
+    pub fn applies(&self, host: &str) -> bool {
+        self.domain
+            .as_ref()
+            .is_none_or(|domain| host.contains(domain.as_str()))
+    }
+}
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum InjectPosition {
+    /// before `</head>`
+    #[default]
+    Head,
+    /// before `</body>`
+    Body,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum HeaderTarget {
+    /// requests sent upstream
+    Request,
+    /// responses sent to clients
+    Response,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "action", rename_all = "lowercase")]
+pub enum HeaderAction {
+    /// append a value, keeping existing ones
+    Add {
+        value: String,
+    },
+    /// replace all values
+    Set {
+        value: String,
+    },
+    Remove,
+    /// replace matches of `pattern` in every value, `$1` refers to groups
+    Replace {
+        #[serde(deserialize_with = "deserialize_regex")]
+        pattern: Regex,
+        replacement: String,
+    },
+}
+
+fn deserialize_regex<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Regex, D::Error> {
+    let pattern = String::deserialize(deserializer)?;
+    Regex::new(&pattern).map_err(serde::de::Error::custom)
+}
+
+/// changes to the `Content-Security-Policy` of responses, whose host
+/// sources are always rewritten
+#[derive(Deserialize, Default, Debug)]
+#[serde(default)]
+pub struct Csp {
+    /// remove `report-uri` and `report-to`, reports would name mirror urls
+    pub drop_reports: bool,
+    /// mirror domain to how its policy is changed
+    pub domain: HashMap<String, CspMode>,
+}
+
+impl Csp {
+    pub fn mode(&self, host: &str) -> CspMode {
+        self.domain
+            .iter()
+            .filter(|(domain, _)| host.contains(domain.as_str()))
+            .max_by_key(|(domain, _)| domain.len())
+            .map_or_else(CspMode::default, |(_, mode)| *mode)
+    }
+}
+
+/// how requests for a range of a body are handled, as rewriting a part of
+/// it would not keep its offsets
+#[derive(Deserialize, Default, Debug)]
+#[serde(default)]
+pub struct Ranges {
+    pub mode: RangeMode,
+    /// mirror domain to the mode replacing the one above
+    pub domain: HashMap<String, RangeMode>,
+}
+
+impl Ranges {
+    pub fn mode(&self, host: &str) -> RangeMode {
+        self.domain
+            .iter()
+            .filter(|(domain, _)| host.contains(domain.as_str()))
+            .max_by_key(|(domain, _)| domain.len())
+            .map_or(self.mode, |(_, mode)| *mode)
+    }
+}
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum RangeMode {
+    /// partial responses are sent as is, without rewriting
+    #[default]
+    Pass,
+    /// ranges are removed from requests, so whole bodies are sent and
+    /// rewritten
+    Strip,
+}
+
+/// timeouts of requests to origins, in seconds, 0 for none
+#[derive(Deserialize, Default, Debug)]
+#[serde(default)]
+pub struct Timeouts {
+    #[serde(flatten)]
+    pub phases: PhaseTimeouts,
+    /// mirror domain to the timeouts replacing those above
+    pub domain: HashMap<String, PhaseTimeouts>,
+}
+
+impl Timeouts {
+    /// the timeouts of requests for the mirror domain `host`
+    pub fn get(&self, host: &str) -> PhaseTimeouts {
+        let domain = self
+            .domain
+            .iter()
+            .filter(|(domain, _)| host.contains(domain.as_str()))
+            .max_by_key(|(domain, _)| domain.len())
+            .map(|(_, phases)| *phases)
+            .unwrap_or_default();
+        domain.or(self.phases).or(PhaseTimeouts::DEFAULT)
+    }
+}
+
+/// backends serving the same origin, requests are spread over
+#[derive(Deserialize, PartialEq, Debug)]
+pub struct Backends {
+    /// `host` or `host:port`, the port of the origin if omitted
+    pub hosts: Vec<String>,
+    #[serde(default)]
+    pub balance: Balance,
+    /// a backend failing as often in a row is left out for `fail_timeout_secs`
+    #[serde(default = "default_max_fails")]
+    pub max_fails: usize,
+    #[serde(default = "default_fail_timeout")]
+    pub fail_timeout_secs: u64,
+    /// probes of the backends, if they are all down requests are answered
+    /// at once, from the cache if they can be, or with 503
+    pub health_check: Option<HealthCheck>,
+}
+
+#[derive(Deserialize, Clone, PartialEq, Debug)]
+pub struct HealthCheck {
+    /// requested of each backend, which is up if it answers with 2xx or
+    /// 3xx, otherwise it is up if it accepts connections
+    pub url: Option<String>,
+    /// connected to by connection probes of backends without a port
+    #[serde(default = "default_health_check_port")]
+    pub port: u16,
+    #[serde(default = "default_health_check_interval")]
+    pub interval_secs: u64,
+    #[serde(default = "default_health_check_timeout")]
+    pub timeout_secs: u64,
+}
+
+fn default_health_check_port() -> u16 {
+    80
+}
+
+fn default_health_check_interval() -> u64 {
+    5
+}
+
+fn default_health_check_timeout() -> u64 {
+    2
+}
+
+fn default_max_fails() -> usize {
+    3
+}
+
+fn default_fail_timeout() -> u64 {
+    10
+}
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Balance {
+    #[default]
+    RoundRobin,
+    /// the backend with the fewest requests in progress
+    LeastConnections,
+}
+
+/// requests each client may send, answered with 429 beyond
+#[derive(Deserialize, Clone, PartialEq, Debug)]
+pub struct RateLimit {
+    #[serde(flatten)]
+    pub rate: Rate,
+    /// clients with a session are told apart by it instead of their address
+    #[serde(default)]
+    pub by_session: bool,
+    /// mirror domain to the rate replacing the one above
+    #[serde(default)]
+    pub domain: HashMap<String, Rate>,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+pub struct Rate {
+    /// per second, 0 for no limit
+    pub rate: f64,
+    /// sent at once after a pause, `rate` if unset
+    pub burst: Option<f64>,
+}
+
+/// the bytes of responses sent to each signed in user are recorded, and
+/// once they reach a quota, further requests are answered with 429
+#[derive(Deserialize, Debug)]
+pub struct Quota {
+    pub daily_bytes: Option<u64>,
+    pub monthly_bytes: Option<u64>,
+    /// html template of the 429 page, `{{user}}` and `{{period}}` are replaced
+    pub page: Option<String>,
+}
+
+/// bytes per second responses are sent with, 0 for no limit
+#[derive(Deserialize, Clone, Default, Debug)]
+#[serde(default)]
+pub struct Bandwidth {
+    #[serde(flatten)]
+    pub rates: ByteRates,
+    /// mirror domain to the rates replacing those above
+    pub domain: HashMap<String, ByteRates>,
+}
+
+impl Bandwidth {
+    /// the rates of responses of the mirror domain `host`
+    pub fn get(&self, host: &str) -> ByteRates {
+        let domain = self
+            .domain
+            .iter()
+            .filter(|(domain, _)| host.contains(domain.as_str()))
+            .max_by_key(|(domain, _)| domain.len())
+            .map(|(_, rates)| *rates)
+            .unwrap_or_default();
+        ByteRates {
+            per_client: domain.per_client.or(self.rates.per_client),
+            per_domain: domain.per_domain.or(self.rates.per_domain),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Default, Debug)]
+#[serde(default)]
+pub struct ByteRates {
+    /// of the responses of each client
+    pub per_client: Option<u64>,
+    /// of all the responses of a mirror domain
+    pub per_domain: Option<u64>,
+}
+
+/// failing requests to an origin at once after it failed repeatedly
+#[derive(Deserialize, PartialEq, Debug)]
+pub struct CircuitBreaker {
+    /// failures in a row opening the circuit
+    #[serde(default = "default_circuit_failures")]
+    pub failures: usize,
+    /// before a request is sent to the origin again
+    #[serde(default = "default_cool_down")]
+    pub cool_down_secs: u64,
+}
+
+fn default_circuit_failures() -> usize {
+    5
+}
+
+fn default_cool_down() -> u64 {
+    30
+}
+
+/// limits of the heads of requests received, connections exceeding them
+/// are closed
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct RequestHead {
+    /// seconds a head is received in once waited for, 0 for no limit
+    pub timeout_secs: u64,
+    pub max_headers: usize,
+    /// bytes of a head, the request line included
+    pub max_size: usize,
+    pub max_request_line: usize,
+}
+
+impl Default for RequestHead {
+    fn default() -> RequestHead {
+        RequestHead {
+            timeout_secs: 20,
+            max_headers: 100,
+            max_size: 8192,
+            max_request_line: 4096,
+        }
+    }
+}
+
+impl RequestHead {
+    pub fn timeout(&self) -> Option<Duration> {
+        (self.timeout_secs > 0).then(|| Duration::from_secs(self.timeout_secs))
+    }
+}
+
+/// sending GET and HEAD requests again when the origin fails
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct Retry {
+    /// retries after the first attempt
+    pub attempts: usize,
+    /// before the first retry, doubled before each next one
+    pub backoff_ms: u64,
+    /// retry against the next address the origin's name resolves to
+    pub other_address: bool,
+}
+
+impl Default for Retry {
+    fn default() -> Retry {
+        Retry {
+            attempts: 0,
+            backoff_ms: 100,
+            other_address: true,
+        }
+    }
+}
+
+impl Retry {
+    /// the wait before retry `attempt`, from 0
+    pub fn backoff(&self, attempt: usize) -> Duration {
+        let factor = 1u64 << attempt.min(16);
+        Duration::from_millis(self.backoff_ms.saturating_mul(factor))
+    }
+}
+
+#[derive(Deserialize, Default, Clone, Copy, Debug)]
+#[serde(default)]
+pub struct PhaseTimeouts {
+    pub connect_secs: Option<u64>,
+    pub tls_handshake_secs: Option<u64>,
+    /// until the response headers are read
+    pub response_header_secs: Option<u64>,
+    /// between reads of the response body
+    pub body_idle_secs: Option<u64>,
+    /// of the whole request, its response body included
+    pub total_secs: Option<u64>,
+}
+
+impl PhaseTimeouts {
+    pub const DEFAULT: PhaseTimeouts = PhaseTimeouts {
+        connect_secs: Some(10),
+        tls_handshake_secs: Some(10),
+        response_header_secs: Some(60),
+        body_idle_secs: Some(60),
+        total_secs: Some(0),
+    };
+
+    /// these timeouts, with those unset taken from `other`
+    pub fn or(self, other: PhaseTimeouts) -> PhaseTimeouts {
+        PhaseTimeouts {
+            connect_secs: self.connect_secs.or(other.connect_secs),
+            tls_handshake_secs: self.tls_handshake_secs.or(other.tls_handshake_secs),
+            response_header_secs: self.response_header_secs.or(other.response_header_secs),
+            body_idle_secs: self.body_idle_secs.or(other.body_idle_secs),
+            total_secs: self.total_secs.or(other.total_secs),
+        }
+    }
+}
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum CspMode {
+    #[default]
+    Rewrite,
+    /// also allow inline scripts and styles, as injected snippets are
+    Relax,
+    /// remove the policy
+    Remove,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Robots {
+    /// file to serve, disallowing everything if omitted
+    pub file: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AccessLog {
+    #[serde(default)]
+    pub format: access_log::Format,
+    /// file to append to, stdout if omitted
+    pub path: Option<String>,
+}
+
+#[cfg(feature = "otlp")]
+#[derive(Deserialize, Debug)]
+pub struct Otlp {
+    /// collector base url, spans are posted to `{endpoint}/v1/traces`
+    pub endpoint: String,
+    pub service_name: Option<String>,
+}
+
+#[cfg(feature = "lua")]
+#[derive(Deserialize, Debug)]
+pub struct Lua {
+    /// file of the script defining the hooks, loaded at startup
+    pub script: String,
+}
+
+#[cfg(feature = "wasm")]
+#[derive(Deserialize, Debug, Clone)]
+pub struct Wasm {
+    /// mirror domain it applies to, every domain if omitted
+    pub domain: Option<String>,
+    /// file of the module, binary or text
+    pub module: String,
+}
+
+#[cfg(feature = "wasm")]
+impl Wasm {
+    pub fn applies(&self, host: &str) -> bool {
+        self.domain
+            .as_ref()
+            .is_none_or(|domain| host.contains(domain.as_str()))
+    }
+}
+
+/// private listener for admin operations, kept off the mirror domains
+#[derive(Deserialize, Debug)]
+pub struct Admin {
+    pub listen_address: String,
+    /// required as `Authorization: Bearer <token>` on every request
+    pub token: String,
+}
+
+/// origins are not contacted while on, every request is answered with 503,
+/// turned on and off with the admin api
+#[derive(Deserialize, Debug)]
+pub struct Maintenance {
+    /// html template of the page, `{{domain}}` and `{{retry_after}}` are
+    /// replaced
+    pub page: Option<String>,
+    /// sent as `Retry-After`
+    #[serde(default = "default_retry_after_secs")]
+    pub retry_after_secs: u64,
+    /// on for every mirror domain from startup
+    #[serde(default)]
+    pub all: bool,
+    /// mirror domains on from startup
+    #[serde(default)]
+    pub domains: Vec<String>,
+}
+
+fn default_retry_after_secs() -> u64 {
+    300
+}
+
+impl Default for Maintenance {
+    fn default() -> Self {
+        Maintenance {
+            page: None,
+            retry_after_secs: default_retry_after_secs(),
+            all: false,
+            domains: Vec::new(),
+        }
+    }
+}
+
+/// on-disk cache of rewritten responses, stored in `data_dir`
+#[derive(Deserialize, Debug)]
+pub struct Cache {
+    /// larger responses are passed through without caching
+    #[serde(default = "default_cache_max_body_size")]
+    pub max_body_size: u64,
+    /// seconds an expired response may be served while it is refreshed in
+    /// the background, when the origin sends no `stale-while-revalidate`
+    pub stale_while_revalidate: Option<u64>,
+}
+
+fn default_cache_max_body_size() -> u64 {
+    10 * 1024 * 1024
+}
+
+/// in-memory cache of small rewritten assets, used with or without `cache`
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct MemoryCache {
+    pub max_entries: usize,
+    /// larger responses are not kept in memory
+    pub max_body_size: u64,
+    pub content_types: Vec<String>,
+}
+
+impl Default for MemoryCache {
+    fn default() -> Self {
+        MemoryCache {
+            max_entries: 1024,
+            max_body_size: 256 * 1024,
+            content_types: vec![
+                "text/css".to_string(),
+                "text/javascript".to_string(),
+                "application/javascript".to_string(),
+                "application/x-javascript".to_string(),
+            ],
+        }
+    }
+}
+
+/// extra checks of the readiness endpoint
+#[derive(Deserialize, Debug)]
+pub struct Readiness {
+    /// upstream urls which must respond without a server error
+    #[serde(default)]
+    pub probes: Vec<String>,
+    #[serde(default = "default_probe_timeout")]
+    pub timeout_secs: u64,
+    /// clients, besides `trusted_proxies`, which may ask for the checks
+    #[serde(default)]
+    pub allow: Vec<IpNet>,
+}
+
+fn default_probe_timeout() -> u64 {
+    5
+}
+
+/// client address rules, checked before anything else is answered
+#[derive(Deserialize, Default, Debug)]
+pub struct AccessControl {
+    #[serde(flatten)]
+    pub global: AccessList,
+    /// mirror domain to its own rules, added to the global ones, a deny in
+    /// either list wins over an allow in the other
+    pub domain: Option<HashMap<String, AccessList>>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+pub struct AccessList {
+    /// clients allowed without login
+    pub allow: Option<Vec<IpNet>>,
+    /// clients rejected with 403
+    pub deny: Option<Vec<IpNet>>,
+}
+
+/// where upstream connections may go, checked on the resolved addresses so
+/// a name can't be rebound to a denied one
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct UpstreamPolicy {
+    /// refuse loopback, private, link-local (cloud metadata included) and
+    /// other non-public addresses
+    pub deny_private: bool,
+    /// refuse hosts which are no origin of a mapping
+    pub mapped_only: bool,
+    /// addresses connected to even if they are denied
+    pub allow: Vec<IpNet>,
+    /// addresses refused besides non-public ones
+    pub deny: Vec<IpNet>,
+}
+
+impl Default for UpstreamPolicy {
+    fn default() -> Self {
+        UpstreamPolicy {
+            deny_private: true,
+            mapped_only: true,
+            allow: Vec::new(),
+            deny: Vec::new(),
+        }
+    }
+}
+
+impl UpstreamPolicy {
+    pub fn allows(&self, ip: &IpAddr) -> bool {
+        if self.allow.iter().any(|net| net.contains(ip)) {
+            return true;
+        }
+        !(self.deny.iter().any(|net| net.contains(ip)) || self.deny_private && !is_public(ip))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Access {
+    Allow,
+    Deny,
+    /// no rule matched, authorization applies as usual
+    Default,
+}
+
+impl AccessControl {
+    pub fn check(&self, host: &str, ip: &IpAddr) -> Access {
+        let domain = self.domain.as_ref().and_then(|domain| {
+            domain
+                .iter()
+                .filter(|(domain, _)| host.contains(domain.as_str()))
+                .max_by_key(|(domain, _)| domain.len())
+                .map(|(_, list)| list)
+        });
+        let lists: Vec<_> = domain.into_iter().chain([&self.global]).collect();
+        let matches = |nets: &Option<Vec<IpNet>>| {
+            nets.as_ref()
+                .is_some_and(|nets| nets.iter().any(|net| net.contains(ip)))
+        };
+        if lists.iter().any(|list| matches(&list.deny)) {
+            Access::Deny
+        } else if lists.iter().any(|list| matches(&list.allow)) {
+            Access::Allow
+        } else {
+            Access::Default
+        }
+    }
+}
+
 #[derive(Deserialize, Debug)]
 pub struct Authorization {
     pub enabled: bool,
+    #[serde(default)]
+    pub backend: Backend,
     pub domain_list: Option<Vec<String>>,
     pub account: Option<Vec<Account>>,
+    pub oidc: Option<Oidc>,
+    pub ldap: Option<Ldap>,
+    /// group name to its members
+    pub group: Option<HashMap<String, Vec<String>>>,
+    /// mirror domain to its authorization policy
+    pub policy: Option<HashMap<String, Policy>>,
+    #[serde(default)]
+    pub cookie: SessionCookie,
+}
+
+/// attributes of the session cookie set after login
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct SessionCookie {
+    pub name: String,
+    pub lifetime_days: i64,
+    /// "strict", "lax" or "none", not sent if omitted
+    pub same_site: Option<SameSite>,
+    pub secure: bool,
+    pub http_only: bool,
+}
+
+impl Default for SessionCookie {
+    fn default() -> Self {
+        SessionCookie {
+            name: "__wj_token".to_string(),
+            lifetime_days: 3650,
+            same_site: None,
+            secure: true,
+            http_only: true,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+impl Authorization {
+    /// the domain requiring login for `host` and the policy applied to it,
+    /// `host` is protected if it is in `domain_list` or has a policy
+    pub fn protected_domain(&self, host: &str) -> Option<(&str, Option<&Policy>)> {
+        let policy = self.policy.as_ref().and_then(|policy| {
+            policy
+                .iter()
+                .filter(|(domain, _)| host.contains(domain.as_str()))
+                .max_by_key(|(domain, _)| domain.len())
+        });
+        if let Some((_, policy)) = policy {
+            if !policy.enabled {
+                return None;
+            }
+        }
+        self.domain_list
+            .as_ref()
+            .and_then(|domain_list| domain_list.iter().find(|&i| host.contains(i)))
+            .or_else(|| policy.map(|(domain, _)| domain))
+            .map(|domain| (domain.as_str(), policy.map(|(_, policy)| policy)))
+    }
+
+    /// whether a logged in `user` satisfies the domain policy
+    pub fn allows(&self, policy: Option<&Policy>, user: &str) -> bool {
+        let policy = match policy {
+            Some(policy) => policy,
+            None => return true,
+        };
+        if policy.users.is_none() && policy.groups.is_none() {
+            return true;
+        }
+        if let Some(users) = &policy.users {
+            if users.iter().any(|i| i == user) {
+                return true;
+            }
+        }
+        if let (Some(groups), Some(group)) = (&policy.groups, &self.group) {
+            return groups
+                .iter()
+                .filter_map(|i| group.get(i))
+                .any(|members| members.iter().any(|i| i == user));
+        }
+        false
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Policy {
+    /// set to false to leave the domain open
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    pub users: Option<Vec<String>>,
+    pub groups: Option<Vec<String>>,
+    /// anyone may browse with GET, HEAD and OPTIONS, only other methods,
+    /// which change state, need login
+    #[serde(default)]
+    pub read_only: bool,
+    /// only these paths need login, the rest is public
+    pub paths: Option<Vec<PathPattern>>,
+}
+
+impl Default for Policy {
+    fn default() -> Policy {
+        Policy {
+            enabled: true,
+            users: None,
+            groups: None,
+            read_only: false,
+            paths: None,
+        }
+    }
+}
+
+impl Policy {
+    /// whether `path` is left public by `paths`
+    pub fn is_public(&self, path: &str) -> bool {
+        self.paths
+            .as_ref()
+            .is_some_and(|paths| !paths.iter().any(|i| i.matches(path)))
+    }
+}
+
+fn default_redirect_status() -> u16 {
+    302
+}
+
+fn default_via_name() -> String {
+    "web-jingzi".to_string()
+}
+
+fn default_max_hops() -> usize {
+    1
+}
+
+fn default_cors_methods() -> Vec<String> {
+    ["GET", "HEAD", "POST", "PUT", "PATCH", "DELETE", "OPTIONS"]
+        .map(String::from)
+        .to_vec()
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Deserialize, Default, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    #[default]
+    Account,
+    Oidc,
+    Ldap,
 }
 
 #[derive(Deserialize, PartialEq, Debug)]
@@ -50,3 +1816,118 @@ pub struct Account {
     pub username: String,
     pub password: String,
 }
+
+/// OpenID Connect provider, endpoints are discovered from the issuer if omitted
+#[derive(Deserialize, Debug)]
+pub struct Oidc {
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: String,
+    pub scopes: Option<Vec<String>>,
+    /// verified email (or subject if there is none) of users allowed to login
+    pub allowed_users: Option<Vec<String>>,
+    pub authorization_endpoint: Option<String>,
+    pub token_endpoint: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Ldap {
+    /// `ldap://host[:port]` or `ldaps://host[:port]`
+    pub url: String,
+    /// DN to bind as, `{username}` is replaced with the escaped login name,
+    /// e.g. `uid={username},ou=people,dc=example,dc=com` or `{username}@corp.example.com`
+    pub bind_dn: String,
+    /// for the whole bind, from connecting to the response
+    #[serde(default = "default_ldap_timeout")]
+    pub timeout_secs: u64,
+}
+
+fn default_ldap_timeout() -> u64 {
+    10
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINIMAL: &str = r#"
+        listen_address = "127.0.0.1:3003"
+        data_dir = "/tmp"
+        [domain_name]
+        "x.com" = "www.google.com"
+        "中文.com" = "example.org"
+        [authorization]
+        enabled = false
+    "#;
+
+    #[test]
+    fn from_toml_keeps_domains_in_ascii() {
+        let config = Config::from_toml(MINIMAL).unwrap();
+        assert_eq!(config.listen_address, "127.0.0.1:3003");
+        assert_eq!(config.domain_name["x.com"], "www.google.com");
+        assert_eq!(config.domain_name["xn--fiq228c.com"], "example.org");
+    }
+
+    #[test]
+    fn origin_keeps_ipv6_hosts_whole() {
+        let origin = Origin::parse("https://[2001:db8::1]").unwrap();
+        assert_eq!(
+            (origin.scheme, origin.host, origin.port),
+            (Some("https"), "[2001:db8::1]", None)
+        );
+        let origin = Origin::parse("[::1]:8443").unwrap();
+        assert_eq!(
+            (origin.scheme, origin.host, origin.port),
+            (None, "[::1]", Some(8443))
+        );
+        let origin = Origin::parse("http://origin.com:8080").unwrap();
+        assert_eq!((origin.host, origin.port), ("origin.com", Some(8080)));
+        assert!(Origin::parse("[::1]:port").is_err());
+    }
+
+    #[test]
+    fn builder_sets_what_the_file_would() {
+        let config = Config::builder()
+            .listen("127.0.0.1:3003")
+            .data_dir("/tmp")
+            .map_domain("x.com", "www.google.com")
+            .map_domain("中文.com", "example.org")
+            .auth("x.com", "tony", "123")
+            .auth("y.com", "bob", "456")
+            .set("strict_hosts", false)
+            .build()
+            .unwrap();
+        assert_eq!(config.listen_address, "127.0.0.1:3003");
+        assert_eq!(config.domain_name["xn--fiq228c.com"], "example.org");
+        assert!(!config.strict_hosts);
+        let authorization = &config.authorization;
+        assert!(authorization.enabled);
+        assert_eq!(
+            authorization.domain_list.as_deref().unwrap(),
+            ["x.com", "y.com"]
+        );
+        assert_eq!(authorization.account.as_ref().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn builder_defaults_as_the_file_does() {
+        let built = Config::builder()
+            .listen("127.0.0.1:3003")
+            .data_dir("/tmp")
+            .map_domain("x.com", "www.google.com")
+            .build()
+            .unwrap();
+        let read = Config::from_toml(MINIMAL).unwrap();
+        assert!(!built.authorization.enabled);
+        assert_eq!(built.strict_hosts, read.strict_hosts);
+        assert_eq!(built.max_hops, read.max_hops);
+        assert_eq!(built.via_name, read.via_name);
+        assert!(Config::builder().build().is_err());
+    }
+
+    #[test]
+    fn from_toml_needs_the_required_settings() {
+        let config = MINIMAL.replace("listen_address", "listen");
+        assert!(Config::from_toml(&config).is_err());
+    }
+}
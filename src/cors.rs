@@ -0,0 +1,65 @@
+use http_types::{Method, Request, Response, StatusCode};
+
+use crate::config::Cors;
+
+const ALLOW_ORIGIN: &str = "Access-Control-Allow-Origin";
+const ALLOW_CREDENTIALS: &str = "Access-Control-Allow-Credentials";
+const EXPOSE_HEADERS: &str = "Access-Control-Expose-Headers";
+
+/// the answer to `req`, if it is a preflight answered here, its origin is
+/// allowed by `apply` as any other response
+pub fn preflight(cors: &Cors, req: &Request) -> Option<Response> {
+    if !cors.preflight
+        || req.method() != Method::Options
+        || req.header("Access-Control-Request-Method").is_none()
+    {
+        return None;
+    }
+    let mut resp = Response::new(StatusCode::NoContent);
+    let origin = req.header("Origin").map(|i| i.as_str());
+    if !origin.is_some_and(|i| cors.allows(i)) {
+        return Some(resp);
+    }
+    resp.insert_header(
+        "Access-Control-Allow-Methods",
+        cors.allow_methods.join(", ").to_ascii_uppercase(),
+    );
+    let headers = match cors.allow_headers.is_empty() {
+        true => req
+            .header("Access-Control-Request-Headers")
+            .map(|i| i.as_str().to_string()),
+        false => Some(cors.allow_headers.join(", ")),
+    };
+    if let Some(headers) = headers {
+        resp.insert_header("Access-Control-Allow-Headers", headers);
+    }
+    if let Some(max_age) = cors.max_age_secs {
+        resp.insert_header("Access-Control-Max-Age", max_age.to_string());
+    }
+    Some(resp)
+}
+
+/// replace the `Access-Control-Allow-*` headers of `resp`, answering a
+/// request from `origin`, with those of `cors`
+pub fn apply(cors: &Cors, origin: Option<&str>, resp: &mut Response) {
+    for name in [ALLOW_ORIGIN, ALLOW_CREDENTIALS, EXPOSE_HEADERS] {
+        resp.remove_header(name);
+    }
+    let Some(origin) = origin.filter(|i| cors.allows(i)) else {
+        return;
+    };
+    // credentials are never sent to any origin, so it is echoed instead
+    let any = cors.allow_origins.iter().any(|i| i == "*");
+    if any && !cors.allow_credentials {
+        resp.insert_header(ALLOW_ORIGIN, "*");
+    } else {
+        resp.insert_header(ALLOW_ORIGIN, origin);
+        resp.append_header("Vary", "Origin");
+    }
+    if cors.allow_credentials {
+        resp.insert_header(ALLOW_CREDENTIALS, "true");
+    }
+    if !cors.expose_headers.is_empty() {
+        resp.insert_header(EXPOSE_HEADERS, cors.expose_headers.join(", "));
+    }
+}
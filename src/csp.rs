@@ -0,0 +1,74 @@
+use crate::replace::Replacer;
+
+/// directives of inline scripts and styles, which injected snippets need
+const INLINE_DIRECTIVES: &[&str] = &[
+    "default-src",
+    "script-src",
+    "script-src-elem",
+    "style-src",
+    "style-src-elem",
+];
+
+/// rewrite the host sources of a `Content-Security-Policy` value, possibly
+/// holding several comma separated policies, with `relax` inline scripts
+/// and styles are allowed, with `drop_reports` violations aren't reported
+pub fn rewrite(value: &str, replacer: &Replacer, relax: bool, drop_reports: bool) -> String {
+    value
+        .split(',')
+        .map(|policy| {
+            policy
+                .split(';')
+                .filter_map(|directive| {
+                    let mut tokens = directive.split_ascii_whitespace();
+                    let name = tokens.next()?;
+                    if drop_reports
+                        && (name.eq_ignore_ascii_case("report-uri")
+                            || name.eq_ignore_ascii_case("report-to"))
+                    {
+                        return None;
+                    }
+                    let mut sources: Vec<String> = tokens
+                        .map(|source| {
+                            // keywords like 'self', the rest are host sources
+                            if source.starts_with('\'') {
+                                source.to_string()
+                            } else {
+                                replacer.replace(source)
+                            }
+                        })
+                        .collect();
+                    if relax
+                        && INLINE_DIRECTIVES
+                            .iter()
+                            .any(|i| name.eq_ignore_ascii_case(i))
+                    {
+                        allow_inline(&mut sources);
+                    }
+                    let mut directive = name.to_string();
+                    for source in sources {
+                        directive.push(' ');
+                        directive.push_str(&source);
+                    }
+                    Some(directive)
+                })
+                .collect::<Vec<_>>()
+                .join("; ")
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// `'unsafe-inline'` is ignored if nonces, hashes or `'strict-dynamic'` are
+/// present, so they are removed
+fn allow_inline(sources: &mut Vec<String>) {
+    sources.retain(|source| {
+        let source = source.to_ascii_lowercase();
+        !(source.starts_with("'nonce-")
+            || source.starts_with("'sha")
+            || source == "'strict-dynamic'"
+            || source == "'none'")
+    });
+    if !sources.iter().any(|i| i == "'unsafe-inline'") {
+        sources.push("'unsafe-inline'".to_string());
+    }
+}
@@ -0,0 +1,82 @@
+use std::ops::Range;
+
+use crate::{
+    replace::Replacer,
+    scan::{find, string_end},
+};
+
+/// replace domains only in urls of a stylesheet: `url(...)`, the string of
+/// `@import` and the strings of `image-set(...)`, root relative urls are
+/// put under `prefix`
+pub fn rewrite(css: &str, replacer: &Replacer, prefix: Option<&str>) -> String {
+    replacer.replace_urls(css, urls(css.as_bytes()), prefix)
+}
+
+/// byte ranges of urls, the syntax is all ascii so they are on char boundaries
+fn urls(css: &[u8]) -> Vec<Range<usize>> {
+    let mut ranges = Vec::new();
+    let mut import = false;
+    // depth of parentheses inside `image-set(`
+    let mut image_set = 0usize;
+    let mut i = 0;
+    while i < css.len() {
+        match css[i] {
+            b'/' if css.get(i + 1) == Some(&b'*') => {
+                i = find(css, i + 2, b"*/").map_or(css.len(), |end| end + 2);
+                continue;
+            }
+            quote @ (b'"' | b'\'') => {
+                let end = string_end(css, i + 1, quote);
+                if import || image_set > 0 {
+                    ranges.push(i + 1..end);
+                }
+                import = false;
+                i = end + 1;
+                continue;
+            }
+            b'@' if css[i + 1..]
+                .get(..6)
+                .is_some_and(|s| s.eq_ignore_ascii_case(b"import")) =>
+            {
+                import = true;
+            }
+            b';' | b'{' | b'}' => import = false,
+            b'(' => {
+                let name = function_name(css, i);
+                if name.eq_ignore_ascii_case(b"url") {
+                    let mut start = i + 1;
+                    while css.get(start).is_some_and(u8::is_ascii_whitespace) {
+                        start += 1;
+                    }
+                    let end = match css.get(start) {
+                        Some(&quote @ (b'"' | b'\'')) => {
+                            start += 1;
+                            string_end(css, start, quote)
+                        }
+                        _ => find(css, start, b")").unwrap_or(css.len()),
+                    };
+                    ranges.push(start..end);
+                    import = false;
+                    i = find(css, end, b")").map_or(css.len(), |end| end + 1);
+                    continue;
+                }
+                if image_set > 0 || name.to_ascii_lowercase().ends_with(b"image-set") {
+                    image_set += 1;
+                }
+            }
+            b')' => image_set = image_set.saturating_sub(1),
+            _ => (),
+        }
+        i += 1;
+    }
+    ranges
+}
+
+/// the identifier just before the `(` at `open`
+fn function_name(css: &[u8], open: usize) -> &[u8] {
+    let start = css[..open]
+        .iter()
+        .rposition(|i| !(i.is_ascii_alphanumeric() || *i == b'-' || *i == b'_'))
+        .map_or(0, |i| i + 1);
+    &css[start..open]
+}
@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+use http_types::{Response, StatusCode, Url};
+use tracing::error;
+
+use crate::config::Config;
+
+/// html pages answered for failures, by their status code
+#[derive(Debug)]
+pub struct ErrorPages(HashMap<StatusCode, String>);
+
+impl ErrorPages {
+    /// read the templates of `error_pages`
+    pub fn load(config: &Config) -> Result<ErrorPages> {
+        let mut pages = HashMap::new();
+        for (status, file) in config.error_pages.iter().flatten() {
+            let status = status
+                .parse::<u16>()
+                .ok()
+                .and_then(|i| StatusCode::try_from(i).ok())
+                .ok_or_else(|| anyhow!("invalid status of error page: {}", status))?;
+            pages.insert(status, std::fs::read_to_string(file)?);
+        }
+        Ok(ErrorPages(pages))
+    }
+
+    /// a response with `status` for a request of `url` failed for `reason`,
+    /// which is logged with a new request id the page shows, so a visitor's
+    /// report can be found
+    pub fn response(&self, status: StatusCode, reason: &str, url: &Url) -> Response {
+        let request_id = uuid::Uuid::new_v4().to_string();
+        error!("request {} of {} failed: {}", request_id, url, reason);
+        let mut resp = Response::new(status);
+        match self.0.get(&status) {
+            Some(template) => {
+                let code = u16::from(status).to_string();
+                resp.set_content_type(http_types::mime::HTML);
+                resp.set_body(render(
+                    template,
+                    &[
+                        ("status", &code),
+                        ("reason", reason),
+                        ("request_id", &request_id),
+                        ("url", url.as_str()),
+                    ],
+                ));
+            }
+            None => {
+                resp.set_content_type(http_types::mime::PLAIN);
+                resp.set_body(format!("{}\nrequest id: {}", reason, request_id));
+            }
+        }
+        resp.insert_header("Cache-Control", "no-store");
+        resp
+    }
+}
+
+/// replace the `{{name}}` placeholders of `template` with the html escaped
+/// values of `vars`, unknown ones are kept
+pub fn render(template: &str, vars: &[(&str, &str)]) -> String {
+    let mut html = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        html.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let value = after.find("}}").and_then(|end| {
+            let name = after[..end].trim();
+            let (_, value) = vars.iter().find(|(i, _)| *i == name)?;
+            Some((value, end))
+        });
+        match value {
+            Some((value, end)) => {
+                html.push_str(&escape(value));
+                rest = &after[end + 2..];
+            }
+            None => {
+                html.push_str("{{");
+                rest = after;
+            }
+        }
+    }
+    html.push_str(rest);
+    html
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,210 @@
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use async_io::Timer;
+use futures_lite::{
+    io::{AsyncRead, AsyncWrite},
+    FutureExt,
+};
+
+use crate::config::RequestHead;
+
+/// a connection failing once the head of a request it sends is too large,
+/// or not received in time, which closes it
+pub struct HeadGuard<S> {
+    inner: S,
+    limits: RequestHead,
+    state: State,
+    /// of the head being received, set once it is waited for
+    timer: Option<Timer>,
+    /// of the head being received
+    size: usize,
+    /// the request line and headers received
+    lines: usize,
+    line: Vec<u8>,
+    content_length: Option<u64>,
+    /// the end of the body is not told by its length
+    unframed: bool,
+}
+
+enum State {
+    Head,
+    /// bytes left of the body
+    Body(u64),
+    /// chunked bodies, upgrades and tunnels are not followed
+    Passthrough,
+}
+
+impl<S> HeadGuard<S> {
+    pub fn new(inner: S, limits: RequestHead) -> HeadGuard<S> {
+        HeadGuard {
+            inner,
+            limits,
+            state: State::Head,
+            timer: None,
+            size: 0,
+            lines: 0,
+            line: Vec::new(),
+            content_length: None,
+            unframed: false,
+        }
+    }
+
+    /// follow the bytes read from the connection
+    fn inspect(&mut self, mut bytes: &[u8]) -> io::Result<()> {
+        while !bytes.is_empty() {
+            match self.state {
+                State::Passthrough => return Ok(()),
+                State::Body(left) => {
+                    let n = left.min(bytes.len() as u64);
+                    bytes = &bytes[n as usize..];
+                    self.state = match left - n {
+                        0 => State::Head,
+                        left => State::Body(left),
+                    };
+                }
+                State::Head => {
+                    self.head_byte(bytes[0])?;
+                    bytes = &bytes[1..];
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn head_byte(&mut self, byte: u8) -> io::Result<()> {
+        self.size += 1;
+        if self.size > self.limits.max_size {
+            return Err(invalid(format!(
+                "request head over {} bytes",
+                self.limits.max_size
+            )));
+        }
+        if byte != b'\n' {
+            self.line.push(byte);
+            if self.lines == 0 && self.line.len() > self.limits.max_request_line {
+                return Err(invalid(format!(
+                    "request line over {} bytes",
+                    self.limits.max_request_line
+                )));
+            }
+            return Ok(());
+        }
+        let line = std::mem::take(&mut self.line);
+        let line = line.strip_suffix(b"\r").unwrap_or(&line);
+        match (self.lines, line.is_empty()) {
+            // empty lines before the request line are ignored
+            (0, true) => (),
+            (0, false) => {
+                self.lines = 1;
+                self.unframed = line.starts_with(b"CONNECT ");
+            }
+            (_, false) => {
+                self.lines += 1;
+                if self.lines > self.limits.max_headers + 1 {
+                    return Err(invalid(format!(
+                        "request over {} headers",
+                        self.limits.max_headers
+                    )));
+                }
+                self.header(line);
+            }
+            (_, true) => self.end_head(),
+        }
+        Ok(())
+    }
+
+    fn header(&mut self, line: &[u8]) {
+        let Some(colon) = line.iter().position(|i| *i == b':') else {
+            return;
+        };
+        let (name, value) = (&line[..colon], line[colon + 1..].trim_ascii());
+        if name.eq_ignore_ascii_case(b"content-length") {
+            self.content_length = std::str::from_utf8(value).ok().and_then(|i| i.parse().ok());
+            self.unframed |= self.content_length.is_none();
+        } else if name.eq_ignore_ascii_case(b"transfer-encoding")
+            || name.eq_ignore_ascii_case(b"upgrade")
+        {
+            self.unframed = true;
+        }
+    }
+
+    fn end_head(&mut self) {
+        self.state = match (self.unframed, self.content_length) {
+            (true, _) => State::Passthrough,
+            (false, Some(length)) if length > 0 => State::Body(length),
+            _ => State::Head,
+        };
+        self.timer = None;
+        self.size = 0;
+        self.lines = 0;
+        self.content_length = None;
+        self.unframed = false;
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for HeadGuard<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if matches!(this.state, State::Head) {
+            if let Some(timeout) = this.limits.timeout() {
+                let timer = this.timer.get_or_insert_with(|| Timer::after(timeout));
+                if Pin::new(timer).poll(cx).is_ready() {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("request head not received in {:?}", timeout),
+                    )));
+                }
+            }
+        }
+        let n = ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        this.inspect(&buf[..n])?;
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for HeadGuard<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+/// the output of `f`, unless `timeout` passes first
+pub async fn within<T>(timeout: Option<Duration>, f: impl Future<Output = T>) -> Option<T> {
+    match timeout {
+        Some(timeout) => {
+            async { Some(f.await) }
+                .or(async {
+                    Timer::after(timeout).await;
+                    None
+                })
+                .await
+        }
+        None => Some(f.await),
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
@@ -0,0 +1,168 @@
+use std::{borrow::Cow, cell::RefCell, ops::Range};
+
+use anyhow::Result;
+use lol_html::{
+    element,
+    html_content::{ContentType, Element, TextChunk},
+    text, ElementContentHandlers, RewriteStrSettings, Selector,
+};
+
+use crate::{css, js, replace::Replacer};
+
+const INTEGRITY_SELECTOR: &str = "script[integrity], link[integrity]";
+
+/// replace domains only in `attributes`, meta refresh and the contents of
+/// `<script>` and `<style>`, leaving the rest of the markup untouched,
+/// scripts are rewritten by their literals with `js_literals`, `mirrors`
+/// matches mirror domains, as for [`strip_integrity`], root relative urls of
+/// attributes and styles are put under `prefix`, elements matching `remove`
+/// are dropped
+pub fn rewrite(
+    html: &str,
+    replacer: &Replacer,
+    mirrors: &Replacer,
+    attributes: &[String],
+    js_literals: bool,
+    prefix: Option<&str>,
+    remove: &[String],
+) -> Result<String> {
+    let script = RefCell::new(String::new());
+    let style = RefCell::new(String::new());
+    let html = lol_html::rewrite_str(
+        html,
+        RewriteStrSettings {
+            element_content_handlers: removals(remove)
+                .chain([
+                    element!("*", |el| {
+                        for name in attributes {
+                            if let Some(value) = el.get_attribute(name) {
+                                if replacer.is_match(&value) || prefix.is_some() {
+                                    let urls = attribute_urls(name, &value);
+                                    el.set_attribute(
+                                        name,
+                                        &replacer.replace_urls(&value, urls, prefix),
+                                    )?;
+                                }
+                            }
+                        }
+                        Ok(())
+                    }),
+                    element!(INTEGRITY_SELECTOR, |el| {
+                        remove_integrity(el, mirrors);
+                        Ok(())
+                    }),
+                    element!("meta[http-equiv][content]", |el| {
+                        let refresh = el
+                            .get_attribute("http-equiv")
+                            .is_some_and(|i| i.eq_ignore_ascii_case("refresh"));
+                        if let Some(value) = el.get_attribute("content").filter(|_| refresh) {
+                            el.set_attribute("content", &replacer.replace(&value))?;
+                        }
+                        Ok(())
+                    }),
+                    text!("script", |chunk| {
+                        whole_text(&script, chunk, |text| {
+                            if js_literals {
+                                js::rewrite(text, replacer)
+                            } else {
+                                replacer.replace(text)
+                            }
+                        });
+                        Ok(())
+                    }),
+                    text!("style", |chunk| {
+                        whole_text(&style, chunk, |text| css::rewrite(text, replacer, prefix));
+                        Ok(())
+                    }),
+                ])
+                .collect(),
+            ..RewriteStrSettings::new()
+        },
+    )?;
+    Ok(html)
+}
+
+/// byte ranges of the urls of an attribute, each candidate of a `srcset`
+/// or the whole value
+fn attribute_urls(name: &str, value: &str) -> Vec<Range<usize>> {
+    let mut urls = Vec::new();
+    if !name.to_ascii_lowercase().ends_with("srcset") {
+        urls.push(0..value.len());
+        return urls;
+    }
+    let mut start = 0;
+    for candidate in value.split(',') {
+        let trimmed = candidate.trim_start();
+        let url_start = start + candidate.len() - trimmed.len();
+        let url_len = trimmed.find(char::is_whitespace).unwrap_or(trimmed.len());
+        urls.push(url_start..url_start + url_len);
+        start += candidate.len() + 1;
+    }
+    urls
+}
+
+/// drop the elements matching the css selectors of `remove`, checked when
+/// the config is loaded
+pub fn remove(html: &str, remove: &[String]) -> Result<String> {
+    let html = lol_html::rewrite_str(
+        html,
+        RewriteStrSettings {
+            element_content_handlers: removals(remove).collect(),
+            ..RewriteStrSettings::new()
+        },
+    )?;
+    Ok(html)
+}
+
+fn removals<'a>(
+    remove: &'a [String],
+) -> impl Iterator<Item = (Cow<'a, Selector>, ElementContentHandlers<'a>)> {
+    remove.iter().map(|selector| {
+        element!(selector, |el| {
+            el.remove();
+            Ok(())
+        })
+    })
+}
+
+/// remove the `integrity` of scripts and stylesheets loaded through the
+/// mirror, whose hashes no longer match once they are rewritten, `mirrors`
+/// matches mirror domains
+pub fn strip_integrity(html: &str, mirrors: &Replacer) -> Result<String> {
+    let html = lol_html::rewrite_str(
+        html,
+        RewriteStrSettings {
+            element_content_handlers: vec![element!(INTEGRITY_SELECTOR, |el| {
+                remove_integrity(el, mirrors);
+                Ok(())
+            })],
+            ..RewriteStrSettings::new()
+        },
+    )?;
+    Ok(html)
+}
+
+/// a relative url or one of a mirror domain is loaded through the mirror
+fn remove_integrity(el: &mut Element, mirrors: &Replacer) {
+    let url = el
+        .get_attribute("src")
+        .or_else(|| el.get_attribute("href"))
+        .unwrap_or_default();
+    let relative = !url.starts_with("//") && !url.contains("://");
+    if relative || mirrors.is_match(&url) {
+        el.remove_attribute("integrity");
+    }
+}
+
+/// text of an element may arrive in several chunks, split anywhere, so they
+/// are collected and replaced by `f` of the whole text with the last one
+fn whole_text(pending: &RefCell<String>, chunk: &mut TextChunk, f: impl Fn(&str) -> String) {
+    let mut pending = pending.borrow_mut();
+    pending.push_str(chunk.as_str());
+    if chunk.last_in_text_node() {
+        chunk.replace(&f(&pending), ContentType::Html);
+        pending.clear();
+    } else {
+        chunk.remove();
+    }
+}
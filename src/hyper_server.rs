@@ -0,0 +1,214 @@
+use std::{convert::Infallible, net::SocketAddr};
+
+use anyhow::{bail, Result};
+use bytes::Bytes;
+use futures_lite::{io::AsyncReadExt, stream, FutureExt};
+use http_body_util::{
+    combinators::UnsyncBoxBody, BodyExt, Full, LengthLimitError, Limited, StreamBody,
+};
+use http_types::{Method, Request, Response, StatusCode, Url};
+use hyper::{
+    body::{Frame, Incoming},
+    header::{HeaderMap, HeaderName, HeaderValue},
+    server::conn::http1,
+    service::service_fn,
+};
+use hyper_util::rt::{TokioIo, TokioTimer};
+use tokio::{io::AsyncWriteExt, net::TcpListener};
+use tracing::{error, info, info_span, Instrument};
+
+use crate::server::{payload_too_large, Current, Proxy, OVERLOADED};
+
+type HyperBody = UnsyncBoxBody<Bytes, std::io::Error>;
+
+impl Proxy {
+    /// like `serve`, but on the tokio runtime it is called in, with hyper,
+    /// request bodies are read whole before they are forwarded, PROXY
+    /// protocol headers, CONNECT tunnels and a separate limit of request
+    /// lines are not supported
+    pub async fn serve_tokio(&self, listener: TcpListener) -> Result<()> {
+        let config = self.config();
+        if config.proxy_protocol {
+            bail!("proxy_protocol is not supported on tokio");
+        }
+        match &config.admin {
+            Some(admin) => {
+                let admin_address: SocketAddr = admin.listen_address.parse()?;
+                let admin_listener = TcpListener::bind(admin_address).await?;
+                info!("admin api listening on {}", admin_address);
+                listen(admin_listener, self.current.clone(), true)
+                    .or(listen(listener, self.current.clone(), false))
+                    .await
+            }
+            None => listen(listener, self.current.clone(), false).await,
+        }
+    }
+}
+
+async fn listen(listener: TcpListener, current: Current, admin: bool) -> Result<()> {
+    loop {
+        let (stream, peer_addr) = listener.accept().await?;
+        let current = current.clone();
+        let forward = current.get();
+        tokio::spawn(
+            async move {
+                let _permit = match (&forward.connections, admin) {
+                    (Some(connections), false) => match connections.try_acquire_arc() {
+                        Some(permit) => Some(permit),
+                        None => {
+                            let mut stream = stream;
+                            stream.write_all(OVERLOADED).await.ok();
+                            return;
+                        }
+                    },
+                    _ => None,
+                };
+                let service = service_fn(|req| {
+                    let current = current.clone();
+                    async move {
+                        Ok::<_, Infallible>(answer(&current, req, peer_addr, admin).await)
+                    }
+                });
+                let limits = &forward.config.request_head;
+                if let Err(err) = http1::Builder::new()
+                    .timer(TokioTimer::new())
+                    .header_read_timeout(limits.timeout())
+                    .max_headers(limits.max_headers)
+                    .max_header_size(limits.max_size)
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await
+                {
+                    error!("Connection error: {:#?}", err);
+                }
+            }
+            .instrument(info_span!("accept", peer = %peer_addr, admin)),
+        );
+    }
+}
+
+async fn answer(
+    current: &Current,
+    req: hyper::Request<Incoming>,
+    peer_addr: SocketAddr,
+    admin: bool,
+) -> hyper::Response<HyperBody> {
+    let limit = current.get().config.max_request_body_size;
+    let resp = match request(req, limit).await {
+        Ok(mut req) => {
+            req.set_peer_addr(Some(peer_addr));
+            current.answer(req, admin).await
+        }
+        Err(e) if e.is::<LengthLimitError>() => payload_too_large(),
+        Err(e) => {
+            let mut resp = Response::new(StatusCode::BadRequest);
+            resp.set_body(e.to_string());
+            resp
+        }
+    };
+    response(resp)
+}
+
+/// the http-types request of a hyper one, whose body is at most `limit`
+/// bytes
+async fn request(req: hyper::Request<Incoming>, limit: Option<u64>) -> Result<Request> {
+    let (parts, body) = req.into_parts();
+    let url = match parts.uri.scheme() {
+        Some(_) => Url::parse(&parts.uri.to_string())?,
+        None => {
+            let Some(host) = parts.headers.get(hyper::header::HOST) else {
+                bail!("no host in request");
+            };
+            let path = parts.uri.path_and_query().map_or("/", |i| i.as_str());
+            Url::parse(&format!("http://{}{}", host.to_str()?, path))?
+        }
+    };
+    let method: Method = parts
+        .method
+        .as_str()
+        .parse()
+        .map_err(|e: http_types::Error| anyhow::anyhow!("{}", e))?;
+    let mut req = Request::new(method, url);
+    req.set_version(Some(match parts.version {
+        hyper::Version::HTTP_10 => http_types::Version::Http1_0,
+        _ => http_types::Version::Http1_1,
+    }));
+    for (name, value) in &parts.headers {
+        req.append_header(name.as_str(), value.to_str()?);
+    }
+    let body = match limit {
+        Some(limit) => Limited::new(body, limit.try_into().unwrap_or(usize::MAX))
+            .collect()
+            .await
+            .map_err(anyhow::Error::from_boxed)?
+            .to_bytes(),
+        None => body.collect().await?.to_bytes(),
+    };
+    if !body.is_empty() {
+        req.set_body(body.to_vec());
+    }
+    Ok(req)
+}
+
+/// the hyper response of an http-types one, streaming its body, whose
+/// length, as with async-h1, is that of the body rather than its headers
+fn response(mut resp: Response) -> hyper::Response<HyperBody> {
+    let mut builder = hyper::Response::builder().status(u16::from(resp.status()));
+    for (name, values) in resp.iter() {
+        let Ok(name) = HeaderName::from_bytes(name.as_str().as_bytes()) else {
+            continue;
+        };
+        if name == hyper::header::CONTENT_LENGTH || name == hyper::header::TRANSFER_ENCODING {
+            continue;
+        }
+        for value in values {
+            if let Ok(value) = HeaderValue::from_str(value.as_str()) {
+                builder = builder.header(name.clone(), value);
+            }
+        }
+    }
+    if let Some(len) = resp.len() {
+        builder = builder.header(hyper::header::CONTENT_LENGTH, len);
+    }
+    let trailers = match resp.has_trailers() {
+        true => Some(resp.recv_trailers()),
+        false => None,
+    };
+    let state = (resp.take_body(), trailers);
+    let body = stream::unfold(state, |(mut body, trailers)| async move {
+        let mut buf = vec![0; 16 * 1024];
+        match body.read(&mut buf).await {
+            // trailers are sent only if the body is chunked
+            Ok(0) => {
+                let trailers = trailers?.await?;
+                let mut map = HeaderMap::new();
+                for (name, values) in trailers.iter() {
+                    let Ok(name) = HeaderName::from_bytes(name.as_str().as_bytes()) else {
+                        continue;
+                    };
+                    for value in values {
+                        if let Ok(value) = HeaderValue::from_str(value.as_str()) {
+                            map.append(name.clone(), value);
+                        }
+                    }
+                }
+                Some((Ok(Frame::trailers(map)), (body, None)))
+            }
+            Ok(n) => {
+                buf.truncate(n);
+                Some((Ok(Frame::data(Bytes::from(buf))), (body, trailers)))
+            }
+            Err(e) => Some((Err(e), (body, trailers))),
+        }
+    });
+    builder
+        .body(StreamBody::new(body).boxed_unsync())
+        .unwrap_or_else(|e| {
+            error!("invalid response: {}", e);
+            let body = Full::new(Bytes::new())
+                .map_err(|e| match e {})
+                .boxed_unsync();
+            let mut resp = hyper::Response::new(body);
+            *resp.status_mut() = hyper::StatusCode::BAD_GATEWAY;
+            resp
+        })
+}
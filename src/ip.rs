@@ -0,0 +1,161 @@
+use std::{fmt, net::IpAddr, str::FromStr};
+
+use anyhow::{anyhow, ensure, Error, Result};
+use serde::Deserialize;
+
+/// an address range in CIDR notation, a bare address is a single host
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNet {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_eq(&net.octets(), &ip.octets(), self.prefix)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_eq(&net.octets(), &ip.octets(), self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// whether an address is globally reachable, not loopback, private,
+/// link-local, shared, reserved, multicast or for documentation
+pub fn is_public(ip: &IpAddr) -> bool {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                || a == 0
+                // shared address space, benchmarking, reserved
+                || (a == 100 && b & 0xc0 == 64)
+                || (a == 198 && b & 0xfe == 18)
+                || a >= 240
+                || ip.octets()[..3] == [192, 0, 0])
+        }
+        IpAddr::V6(ip) => {
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_multicast()
+                || ip.is_unique_local()
+                || ip.is_unicast_link_local()
+                || ip.segments()[..2] == [0x2001, 0xdb8])
+        }
+    }
+}
+
+fn prefix_eq(a: &[u8], b: &[u8], prefix: u8) -> bool {
+    let bytes = (prefix / 8) as usize;
+    if a[..bytes] != b[..bytes] {
+        return false;
+    }
+    let bits = prefix % 8;
+    if bits == 0 {
+        return true;
+    }
+    let mask = 0xffu8 << (8 - bits);
+    a[bytes] & mask == b[bytes] & mask
+}
+
+impl FromStr for IpNet {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<IpNet> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .trim()
+            .parse()
+            .map_err(|_| anyhow!("invalid ip address: {}", s))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix: u8 = match prefix {
+            Some(prefix) => prefix
+                .trim()
+                .parse()
+                .map_err(|_| anyhow!("invalid prefix length: {}", s))?,
+            None => max,
+        };
+        ensure!(prefix <= max, "invalid prefix length: {}", s);
+        // compare v4-mapped v6 ranges as plain v4, like the addresses in `contains`
+        Ok(match addr.to_canonical() {
+            IpAddr::V4(v4) if addr.is_ipv6() => IpNet {
+                addr: IpAddr::V4(v4),
+                prefix: prefix.saturating_sub(96),
+            },
+            addr => IpNet { addr, prefix },
+        })
+    }
+}
+
+impl TryFrom<String> for IpNet {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<IpNet> {
+        s.parse()
+    }
+}
+
+impl fmt::Debug for IpNet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn net(s: &str) -> IpNet {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn contains_addresses_of_the_prefix() {
+        assert!(net("10.0.0.0/8").contains(&ip("10.255.1.2")));
+        assert!(!net("10.0.0.0/8").contains(&ip("11.0.0.1")));
+        assert!(net("172.16.0.0/12").contains(&ip("172.31.255.255")));
+        assert!(!net("172.16.0.0/12").contains(&ip("172.32.0.0")));
+        assert!(net("0.0.0.0/0").contains(&ip("203.0.113.9")));
+        assert!(net("2001:db8::/32").contains(&ip("2001:db8:1::1")));
+        assert!(!net("2001:db8::/32").contains(&ip("2001:db9::1")));
+    }
+
+    #[test]
+    fn contains_a_bare_address_only() {
+        assert!(net("192.168.1.1").contains(&ip("192.168.1.1")));
+        assert!(!net("192.168.1.1").contains(&ip("192.168.1.2")));
+        assert!(net("::1").contains(&ip("::1")));
+    }
+
+    #[test]
+    fn contains_v4_mapped_addresses_as_v4() {
+        assert!(net("127.0.0.0/8").contains(&ip("::ffff:127.0.0.1")));
+        assert!(net("::ffff:10.0.0.0/104").contains(&ip("10.1.2.3")));
+        assert!(!net("10.0.0.0/8").contains(&ip("::a01:203")));
+    }
+
+    #[test]
+    fn rejects_invalid_ranges() {
+        for s in ["10.0.0.0/33", "::/129", "10.0.0/8", "10.0.0.0/x", ""] {
+            assert!(s.parse::<IpNet>().is_err(), "{}", s);
+        }
+    }
+}
@@ -0,0 +1,157 @@
+use std::ops::Range;
+
+use crate::{
+    replace::Replacer,
+    scan::{find, string_end},
+};
+
+/// keywords after which a `/` starts a regular expression, not a division
+const KEYWORDS: &[&[u8]] = &[
+    b"return",
+    b"typeof",
+    b"instanceof",
+    b"in",
+    b"of",
+    b"new",
+    b"delete",
+    b"void",
+    b"throw",
+    b"case",
+    b"do",
+    b"else",
+    b"yield",
+    b"await",
+];
+
+/// replace domains only inside string and template literals of a script,
+/// leaving code, comments and regular expressions untouched
+pub fn rewrite(js: &str, replacer: &Replacer) -> String {
+    replacer.replace_ranges(js, literals(js.as_bytes()))
+}
+
+/// byte ranges of the contents of string literals and the text parts of
+/// template literals
+fn literals(js: &[u8]) -> Vec<Range<usize>> {
+    let mut ranges = Vec::new();
+    // brace depth at each `${` of the templates being scanned
+    let mut templates = Vec::new();
+    let mut depth = 0usize;
+    let mut regex_allowed = true;
+    let mut i = 0;
+    while i < js.len() {
+        match js[i] {
+            b'/' if js.get(i + 1) == Some(&b'/') => {
+                i = find(js, i, b"\n").unwrap_or(js.len());
+                continue;
+            }
+            b'/' if js.get(i + 1) == Some(&b'*') => {
+                i = find(js, i + 2, b"*/").map_or(js.len(), |end| end + 2);
+                continue;
+            }
+            b'/' if regex_allowed => {
+                i = regex_end(js, i + 1);
+                regex_allowed = false;
+                continue;
+            }
+            quote @ (b'"' | b'\'') => {
+                let end = string_end(js, i + 1, quote);
+                ranges.push(i + 1..end);
+                regex_allowed = false;
+                i = end + 1;
+                continue;
+            }
+            b'`' => {
+                (i, regex_allowed) = template(js, i + 1, &mut ranges, &mut templates, &mut depth);
+                continue;
+            }
+            b'{' => {
+                depth += 1;
+                regex_allowed = true;
+            }
+            b'}' => {
+                depth = depth.saturating_sub(1);
+                if templates.last() == Some(&depth) {
+                    templates.pop();
+                    (i, regex_allowed) =
+                        template(js, i + 1, &mut ranges, &mut templates, &mut depth);
+                    continue;
+                }
+                regex_allowed = true;
+            }
+            b')' | b']' => regex_allowed = false,
+            c if is_word(c) => {
+                let start = i;
+                while i < js.len() && is_word(js[i]) {
+                    i += 1;
+                }
+                regex_allowed = KEYWORDS.contains(&&js[start..i]);
+                continue;
+            }
+            c if c.is_ascii_whitespace() => (),
+            _ => regex_allowed = true,
+        }
+        i += 1;
+    }
+    ranges
+}
+
+/// scan the text part of a template literal starting at `start`, returning
+/// where scanning continues, after its closing backtick or inside a `${`,
+/// and whether it is in an expression there
+fn template(
+    js: &[u8],
+    start: usize,
+    ranges: &mut Vec<Range<usize>>,
+    templates: &mut Vec<usize>,
+    depth: &mut usize,
+) -> (usize, bool) {
+    let mut i = start;
+    while i < js.len() {
+        match js[i] {
+            b'\\' => i += 1,
+            b'`' => {
+                ranges.push(start..i);
+                return (i + 1, false);
+            }
+            b'$' if js.get(i + 1) == Some(&b'{') => {
+                ranges.push(start..i);
+                templates.push(*depth);
+                *depth += 1;
+                return (i + 2, true);
+            }
+            _ => (),
+        }
+        i += 1;
+    }
+    ranges.push(start..js.len());
+    (js.len(), false)
+}
+
+/// index after a regular expression whose body starts at `start`, with its flags
+fn regex_end(js: &[u8], start: usize) -> usize {
+    let mut class = false;
+    let mut i = start;
+    while i < js.len() {
+        match js[i] {
+            b'\\' => i += 1,
+            b'[' => class = true,
+            b']' => class = false,
+            b'/' if !class => {
+                i += 1;
+                while i < js.len() && is_word(js[i]) {
+                    i += 1;
+                }
+                return i;
+            }
+            b'\n' => return i,
+            _ => (),
+        }
+        i += 1;
+    }
+    js.len()
+}
+
+/// part of an identifier, keyword or number
+fn is_word(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'_' || c == b'$' || c >= 0x80
+}
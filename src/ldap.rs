@@ -0,0 +1,170 @@
+use std::{net::TcpStream, time::Duration};
+
+use anyhow::{anyhow, bail, ensure, Result};
+use async_io::Async;
+use futures_lite::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use http_types::Url;
+
+use crate::{client::resolve_first, config::Ldap, head_guard::within};
+
+const MESSAGE_ID: u8 = 1;
+const RESULT_SUCCESS: u8 = 0;
+const RESULT_INVALID_CREDENTIALS: u8 = 49;
+/// longest message read, a bind response is far shorter
+const MAX_MESSAGE_LEN: usize = 1 << 20;
+
+/// check a username and password with an LDAP simple bind
+pub async fn bind(ldap: &Ldap, username: &str, password: &str) -> Result<bool> {
+    // an empty password is an unauthenticated bind, which servers accept
+    if username.is_empty() || password.is_empty() {
+        return Ok(false);
+    }
+    let dn = ldap
+        .bind_dn
+        .replace("{username}", &escape_dn_value(username));
+    let timeout = Duration::from_secs(ldap.timeout_secs);
+    match within(Some(timeout), connect_and_bind(ldap, &dn, password)).await {
+        Some(result) => result,
+        None => bail!("ldap server timed out"),
+    }
+}
+
+async fn connect_and_bind(ldap: &Ldap, dn: &str, password: &str) -> Result<bool> {
+    let url = Url::parse(&ldap.url)?;
+    let host = url
+        .host_str()
+        .ok_or_else(|| anyhow!("missing host in ldap url"))?;
+    let tls = match url.scheme() {
+        "ldap" => false,
+        "ldaps" => true,
+        s => bail!("unsupported ldap scheme: {}", s),
+    };
+    let port = url.port().unwrap_or(if tls { 636 } else { 389 });
+    let stream = Async::<TcpStream>::connect(resolve_first((host, port)).await?).await?;
+    if tls {
+        let stream = async_native_tls::connect(host, stream).await?;
+        simple_bind(stream, dn, password).await
+    } else {
+        simple_bind(stream, dn, password).await
+    }
+}
+
+async fn simple_bind<S>(mut stream: S, dn: &str, password: &str) -> Result<bool>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // BindRequest ::= [APPLICATION 0] SEQUENCE { version, name, simple [0] }
+    let mut bind = tlv(0x02, &[3]);
+    bind.extend(tlv(0x04, dn.as_bytes()));
+    bind.extend(tlv(0x80, password.as_bytes()));
+    stream.write_all(&message(0x60, &bind)).await?;
+    stream.flush().await?;
+
+    let (tag, response) = read_tlv(&mut stream).await?;
+    ensure!(tag == 0x30, "invalid ldap message");
+    let (tag, _, rest) = parse_tlv(&response)?;
+    ensure!(tag == 0x02, "invalid ldap message id");
+    let (tag, bind_response, _) = parse_tlv(rest)?;
+    ensure!(tag == 0x61, "unexpected ldap response");
+    let (tag, result_code, _) = parse_tlv(bind_response)?;
+    ensure!(tag == 0x0a && result_code.len() == 1, "invalid ldap result");
+
+    // UnbindRequest ::= [APPLICATION 2] NULL
+    let _ = stream.write_all(&message(0x42, &[])).await;
+
+    match result_code[0] {
+        RESULT_SUCCESS => Ok(true),
+        RESULT_INVALID_CREDENTIALS => Ok(false),
+        code => bail!("ldap bind failed with result code {}", code),
+    }
+}
+
+/// LDAPMessage ::= SEQUENCE { messageID, protocolOp }
+fn message(op_tag: u8, op: &[u8]) -> Vec<u8> {
+    let mut content = tlv(0x02, &[MESSAGE_ID]);
+    content.extend(tlv(op_tag, op));
+    tlv(0x30, &content)
+}
+
+fn tlv(tag: u8, value: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = value.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes: Vec<u8> = len
+            .to_be_bytes()
+            .into_iter()
+            .skip_while(|&b| b == 0)
+            .collect();
+        out.push(0x80 | bytes.len() as u8);
+        out.extend(bytes);
+    }
+    out.extend_from_slice(value);
+    out
+}
+
+fn parse_len(data: &[u8]) -> Result<(usize, usize)> {
+    let first = *data
+        .first()
+        .ok_or_else(|| anyhow!("truncated ldap message"))?;
+    if first < 0x80 {
+        return Ok((first as usize, 1));
+    }
+    let n = (first & 0x7f) as usize;
+    ensure!(n > 0 && n <= 4 && data.len() > n, "invalid ldap length");
+    let len = data[1..=n]
+        .iter()
+        .fold(0usize, |acc, &b| (acc << 8) | b as usize);
+    Ok((len, n + 1))
+}
+
+/// split one element off the front, returning its tag, value and the rest
+fn parse_tlv(data: &[u8]) -> Result<(u8, &[u8], &[u8])> {
+    ensure!(!data.is_empty(), "truncated ldap message");
+    let (len, len_size) = parse_len(&data[1..])?;
+    let start = 1 + len_size;
+    ensure!(data.len() >= start + len, "truncated ldap message");
+    Ok((data[0], &data[start..start + len], &data[start + len..]))
+}
+
+async fn read_tlv<S: AsyncRead + Unpin>(stream: &mut S) -> Result<(u8, Vec<u8>)> {
+    let mut head = [0u8; 2];
+    stream.read_exact(&mut head).await?;
+    let len = if head[1] < 0x80 {
+        head[1] as usize
+    } else {
+        let mut len = vec![0u8; (head[1] & 0x7f) as usize];
+        ensure!(len.len() <= 4, "invalid ldap length");
+        stream.read_exact(&mut len).await?;
+        len.iter().fold(0usize, |acc, &b| (acc << 8) | b as usize)
+    };
+    ensure!(len <= MAX_MESSAGE_LEN, "ldap message too large");
+    let mut value = vec![0u8; len];
+    stream.read_exact(&mut value).await?;
+    Ok((head[0], value))
+}
+
+/// escape a value for use in a DN (RFC 4514)
+fn escape_dn_value(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for (i, c) in value.chars().enumerate() {
+        match c {
+            ',' | '+' | '"' | '\\' | '<' | '>' | ';' | '=' => {
+                out.push('\\');
+                out.push(c);
+            }
+            '#' | ' ' if i == 0 => {
+                out.push('\\');
+                out.push(c);
+            }
+            '\0' => out.push_str("\\00"),
+            _ => out.push(c),
+        }
+    }
+    if value.len() > 1 && value.ends_with(' ') {
+        out.pop();
+        out.push_str("\\ ");
+    }
+    out
+}
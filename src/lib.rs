@@ -1,2 +1,47 @@
+mod access_log;
+mod admin;
+mod balancer;
+mod cache;
+mod charset;
+mod circuit;
+mod client;
 mod config;
+mod cors;
+mod csp;
+mod css;
+mod error_page;
+mod head_guard;
+mod html;
+#[cfg(feature = "tokio")]
+mod hyper_server;
+mod ip;
+mod js;
+mod ldap;
+#[cfg(feature = "lua")]
+mod lua;
+mod m3u8;
+mod maintenance;
+mod manifest;
+mod middleware;
+mod multipart;
+mod oidc;
+#[cfg(feature = "otlp")]
+mod otlp;
+mod proxy_protocol;
+mod rate_limit;
+mod replace;
+mod scan;
 pub mod server;
+mod session;
+mod streaming;
+mod throttle;
+mod traffic;
+mod trailers;
+mod tunnel;
+#[cfg(feature = "wasm")]
+mod wasm;
+mod xml;
+
+pub use config::{Config, ConfigBuilder};
+pub use middleware::{BoxFuture, Middleware, Next, Stage};
+pub use server::Proxy;
@@ -0,0 +1,159 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use http_types::{headers::Headers, Request, Response, StatusCode, Url};
+use mlua::{Function, Lua as State, Table, Value};
+
+use crate::config::Lua;
+
+/// the hooks a script defines as global functions, each optional:
+///
+/// - `on_request(req)`, before the request is sent upstream, may change the
+///   `method`, `url` and `headers` of `req`, or return a table with
+///   `status`, `headers` and `body` answered instead
+/// - `on_response_headers(resp)`, may change the `status` and `headers` of
+///   the response, `resp.url` is the upstream one
+/// - `on_body_chunk(chunk, ctx)`, returns the replacement of a chunk of a
+///   rewritten body, bodies are rewritten whole so it is the only chunk,
+///   `ctx` has `host` and `content_type`
+///
+/// headers are tables of lowercase names to a value, or a list of values
+/// for repeated ones
+pub struct Hooks {
+    state: State,
+}
+
+impl std::fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Hooks").finish_non_exhaustive()
+    }
+}
+
+impl Hooks {
+    pub fn load(config: &Lua) -> Result<Hooks> {
+        let state = State::new();
+        let script = std::fs::read_to_string(&config.script)?;
+        state
+            .load(&script)
+            .set_name(config.script.as_str())
+            .exec()
+            .map_err(|e| anyhow!("can not load lua script: {}", e))?;
+        Ok(Hooks { state })
+    }
+
+    /// run `on_request`, returning the response it answers with, if any
+    pub fn on_request(&self, req: &mut Request) -> Result<Option<Response>> {
+        let Some(hook) = self.hook("on_request")? else {
+            return Ok(None);
+        };
+        let table = self.state.create_table()?;
+        table.set("method", req.method().to_string())?;
+        table.set("url", req.url().as_str())?;
+        table.set("headers", self.headers(req.as_ref())?)?;
+        let answer: Option<Table> = hook.call(&table).map_err(lua_error)?;
+
+        let method: String = table.get("method")?;
+        req.set_method(http_types::Method::from_str(&method).map_err(|e| anyhow!("{}", e))?);
+        let url: String = table.get("url")?;
+        if url != req.url().as_str() {
+            *req.url_mut() = Url::parse(&url)?;
+        }
+        set_headers(req.as_mut(), table.get("headers")?)?;
+
+        let Some(answer) = answer else {
+            return Ok(None);
+        };
+        let status: Option<u16> = answer.get("status")?;
+        let mut resp = Response::new(status_code(status.unwrap_or(200))?);
+        if let Some(headers) = answer.get::<Option<Table>>("headers")? {
+            set_headers(resp.as_mut(), headers)?;
+        }
+        if let Some(body) = answer.get::<Option<mlua::LuaString>>("body")? {
+            resp.set_body(body.as_bytes().to_vec());
+        }
+        Ok(Some(resp))
+    }
+
+    /// run `on_response_headers`
+    pub fn on_response_headers(&self, resp: &mut Response, url: &Url) -> Result<()> {
+        let Some(hook) = self.hook("on_response_headers")? else {
+            return Ok(());
+        };
+        let table = self.state.create_table()?;
+        table.set("status", u16::from(resp.status()))?;
+        table.set("url", url.as_str())?;
+        table.set("headers", self.headers(resp.as_ref())?)?;
+        hook.call::<()>(&table).map_err(lua_error)?;
+
+        let status: u16 = table.get("status")?;
+        resp.set_status(status_code(status)?);
+        set_headers(resp.as_mut(), table.get("headers")?)
+    }
+
+    /// run `on_body_chunk`, keeping the chunk if it returns nothing
+    pub fn on_body_chunk(&self, chunk: String, host: &str, content_type: &str) -> Result<String> {
+        let Some(hook) = self.hook("on_body_chunk")? else {
+            return Ok(chunk);
+        };
+        let ctx = self.state.create_table()?;
+        ctx.set("host", host)?;
+        ctx.set("content_type", content_type)?;
+        let replaced: Option<String> = hook.call((chunk.as_str(), ctx)).map_err(lua_error)?;
+        Ok(replaced.unwrap_or(chunk))
+    }
+
+    pub fn has_body_hook(&self) -> bool {
+        self.hook("on_body_chunk").is_ok_and(|i| i.is_some())
+    }
+
+    fn hook(&self, name: &str) -> Result<Option<Function>> {
+        Ok(self.state.globals().get(name)?)
+    }
+
+    fn headers(&self, headers: &Headers) -> Result<Table> {
+        let table = self.state.create_table()?;
+        for (name, values) in headers {
+            let name = name.as_str().to_ascii_lowercase();
+            let values: Vec<_> = values.iter().map(|i| i.as_str()).collect();
+            match values.as_slice() {
+                [value] => table.set(name, *value)?,
+                _ => table.set(name, values)?,
+            }
+        }
+        Ok(table)
+    }
+}
+
+/// replace `headers` with those of a table, removed ones are dropped
+fn set_headers(headers: &mut Headers, table: Table) -> Result<()> {
+    let names: Vec<_> = headers.iter().map(|(name, _)| name.clone()).collect();
+    for name in names {
+        headers.remove(name);
+    }
+    for pair in table.pairs::<String, Value>() {
+        let (name, value) = pair?;
+        match value {
+            Value::Table(values) => {
+                for value in values.sequence_values::<String>() {
+                    headers.append(name.as_str(), value?);
+                }
+            }
+            Value::Nil | Value::Boolean(false) => (),
+            value => {
+                let value = value
+                    .to_string()
+                    .map_err(|e| anyhow!("invalid header {}: {}", name, e))?;
+                headers.append(name.as_str(), value);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn status_code(status: u16) -> Result<StatusCode> {
+    StatusCode::try_from(status).map_err(|_| anyhow!("invalid status {}", status))
+}
+
+fn lua_error(e: mlua::Error) -> anyhow::Error {
+    anyhow!("lua hook failed: {}", e)
+}
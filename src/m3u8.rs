@@ -0,0 +1,32 @@
+use std::ops::Range;
+
+use crate::{replace::Replacer, scan::find};
+
+/// replace domains only in the uris of an HLS playlist: the lines of media
+/// segments and variant playlists, and the `URI` attributes of tags such as
+/// `EXT-X-KEY`, `EXT-X-MAP` and `EXT-X-MEDIA`
+pub fn rewrite(playlist: &str, replacer: &Replacer) -> String {
+    replacer.replace_ranges(playlist, uris(playlist))
+}
+
+fn uris(playlist: &str) -> Vec<Range<usize>> {
+    let bytes = playlist.as_bytes();
+    let mut ranges = Vec::new();
+    let mut start = 0;
+    for line in playlist.split_inclusive('\n') {
+        let end = start + line.len();
+        if line.starts_with('#') {
+            let mut i = start;
+            while let Some(at) = find(&bytes[..end], i, b"URI=\"") {
+                let value = at + 5;
+                let close = find(&bytes[..end], value, b"\"").unwrap_or(end);
+                ranges.push(value..close);
+                i = close;
+            }
+        } else if !line.trim().is_empty() {
+            ranges.push(start..end);
+        }
+        start = end;
+    }
+    ranges
+}
@@ -0,0 +1,124 @@
+use std::{
+    collections::BTreeSet,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        RwLock,
+    },
+};
+
+use anyhow::Result;
+use http_types::{Response, StatusCode};
+use serde_json::{json, Value};
+
+use crate::{
+    config::{self, Config},
+    error_page,
+};
+
+/// mirror domains under maintenance, answered with 503 without contacting
+/// their origins
+#[derive(Debug)]
+pub struct Maintenance {
+    all: AtomicBool,
+    domains: RwLock<BTreeSet<String>>,
+    page: Option<String>,
+    retry_after_secs: u64,
+}
+
+impl Maintenance {
+    /// as configured at startup
+    pub fn new(config: &Config) -> Result<Maintenance> {
+        let config = &config.maintenance;
+        let page = match &config.page {
+            Some(file) => Some(std::fs::read_to_string(file)?),
+            None => None,
+        };
+        Ok(Maintenance {
+            all: AtomicBool::new(config.all),
+            domains: RwLock::new(config.domains.iter().cloned().collect()),
+            page,
+            retry_after_secs: config.retry_after_secs,
+        })
+    }
+
+    /// turn domains on or off as in `old`, which was configured with `was`,
+    /// except where the config changed to `now`, as this was built from it
+    pub fn keep(&self, old: &Maintenance, was: &config::Maintenance, now: &config::Maintenance) {
+        if was.all == now.all {
+            self.all
+                .store(old.all.load(Ordering::Relaxed), Ordering::Relaxed);
+        }
+        let old = match old.domains.read() {
+            Ok(domains) => domains,
+            Err(e) => e.into_inner(),
+        };
+        let mut domains = match self.domains.write() {
+            Ok(domains) => domains,
+            Err(e) => e.into_inner(),
+        };
+        let changed = |i: &String| was.domains.contains(i) != now.domains.contains(i);
+        let kept: Vec<_> = old.iter().filter(|i| !changed(i)).cloned().collect();
+        domains.retain(|i| changed(i));
+        domains.extend(kept);
+    }
+
+    /// turn maintenance on or off for `domain`, for every domain without one
+    pub fn set(&self, domain: Option<&str>, on: bool) {
+        match domain {
+            Some(domain) => {
+                let mut domains = match self.domains.write() {
+                    Ok(domains) => domains,
+                    Err(e) => e.into_inner(),
+                };
+                if on {
+                    domains.insert(domain.to_string());
+                } else {
+                    domains.remove(domain);
+                }
+            }
+            None => self.all.store(on, Ordering::Relaxed),
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        let domains = match self.domains.read() {
+            Ok(domains) => domains,
+            Err(e) => e.into_inner(),
+        };
+        json!({
+            "all": self.all.load(Ordering::Relaxed),
+            "domains": *domains,
+        })
+    }
+
+    /// the 503 answered for the mirror `host`, if it is under maintenance
+    pub fn response(&self, host: &str) -> Option<Response> {
+        if !self.all.load(Ordering::Relaxed) {
+            let domains = match self.domains.read() {
+                Ok(domains) => domains,
+                Err(e) => e.into_inner(),
+            };
+            if !domains.iter().any(|i| host.contains(i.as_str())) {
+                return None;
+            }
+        }
+        let retry_after = self.retry_after_secs.to_string();
+        let mut resp = Response::new(StatusCode::ServiceUnavailable);
+        resp.insert_header("Retry-After", retry_after.as_str());
+        resp.insert_header("Cache-Control", "no-store");
+        match &self.page {
+            Some(template) => {
+                resp.set_content_type(http_types::mime::HTML);
+                resp.set_body(error_page::render(
+                    template,
+                    &[("domain", host), ("retry_after", &retry_after)],
+                ));
+            }
+            None => {
+                resp.set_content_type(http_types::mime::PLAIN);
+                resp.set_body("under maintenance");
+            }
+        }
+        Some(resp)
+    }
+}
@@ -0,0 +1,37 @@
+use anyhow::Result;
+use serde_json::Value;
+
+use crate::replace::Replacer;
+
+/// members of a web app manifest holding urls, at any depth, which covers
+/// `icons[].src`, `shortcuts[].url`, `share_target.action` and the like
+const URL_MEMBERS: &[&str] = &["start_url", "scope", "id", "src", "url", "action"];
+
+/// replace domains only in the url members of a web app manifest, leaving
+/// names and descriptions untouched
+pub fn rewrite(manifest: &str, replacer: &Replacer) -> Result<String> {
+    let mut manifest: Value = serde_json::from_str(manifest)?;
+    rewrite_value(&mut manifest, replacer);
+    Ok(serde_json::to_string(&manifest)?)
+}
+
+fn rewrite_value(value: &mut Value, replacer: &Replacer) {
+    match value {
+        Value::Object(members) => {
+            for (name, value) in members {
+                match value {
+                    Value::String(url) if URL_MEMBERS.contains(&name.as_str()) => {
+                        *url = replacer.replace(url);
+                    }
+                    value => rewrite_value(value, replacer),
+                }
+            }
+        }
+        Value::Array(values) => {
+            for value in values {
+                rewrite_value(value, replacer);
+            }
+        }
+        _ => (),
+    }
+}
@@ -0,0 +1,97 @@
+use std::{future::Future, pin::Pin, sync::Arc};
+
+use http_types::{Request, Response};
+
+use crate::server::Forward;
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// a layer of the request pipeline, which answers a request itself, or
+/// passes it, possibly changed, on to the rest of the pipeline with `next`
+/// and may change the response it returns
+pub trait Middleware: Send + Sync + 'static {
+    fn handle<'a>(
+        &'a self,
+        req: Request,
+        next: Next<'a>,
+    ) -> BoxFuture<'a, http_types::Result<Response>>;
+}
+
+/// the built-in layers, in the order requests pass them
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Stage {
+    /// health checks, redirects, unknown hosts, maintenance, methods, path
+    /// rules and static files
+    Guard,
+    /// access control and login
+    Auth,
+    /// requests passing through the mirror again
+    LoopDetection,
+    /// the request is turned into the origin's
+    Rewrite,
+    /// answered from the cache if it can be
+    Cache,
+    /// sent to the origin, whose response is rewritten for the mirror
+    Upstream,
+}
+
+impl Stage {
+    pub const ALL: [Stage; 6] = [
+        Stage::Guard,
+        Stage::Auth,
+        Stage::LoopDetection,
+        Stage::Rewrite,
+        Stage::Cache,
+        Stage::Upstream,
+    ];
+}
+
+#[derive(Clone)]
+pub(crate) enum Layer {
+    Stage(Stage),
+    Middleware(Arc<dyn Middleware>),
+}
+
+impl std::fmt::Debug for Layer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Layer::Stage(stage) => stage.fmt(f),
+            Layer::Middleware(_) => f.write_str("Middleware"),
+        }
+    }
+}
+
+/// the built-in stages with each of `middleware` before its stage
+pub(crate) fn layers(middleware: Vec<(Stage, Arc<dyn Middleware>)>) -> Vec<Layer> {
+    let mut layers = Vec::new();
+    for stage in Stage::ALL {
+        for (_, middleware) in middleware.iter().filter(|(i, _)| *i == stage) {
+            layers.push(Layer::Middleware(middleware.clone()));
+        }
+        layers.push(Layer::Stage(stage));
+    }
+    layers
+}
+
+/// the rest of the pipeline
+pub struct Next<'a> {
+    pub(crate) forward: &'a Arc<Forward>,
+    pub(crate) layers: &'a [Layer],
+}
+
+impl<'a> Next<'a> {
+    pub fn run(self, req: Request) -> BoxFuture<'a, http_types::Result<Response>> {
+        let Some((layer, layers)) = self.layers.split_first() else {
+            // the upstream stage answers every request reaching it
+            unreachable!("request passed the upstream stage")
+        };
+        let next = Next {
+            forward: self.forward,
+            layers,
+        };
+        match layer {
+            Layer::Middleware(middleware) => middleware.handle(req, next),
+            Layer::Stage(stage) => Box::pin(self.forward.stage(*stage, req, next)),
+        }
+    }
+}
@@ -0,0 +1,188 @@
+use std::{
+    io,
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+};
+
+use futures_lite::io::{AsyncRead, BufReader};
+use http_types::{headers::CONTENT_LENGTH, Body, Request};
+
+use crate::replace::Replacer;
+
+/// larger heads of parts, or text fields, are passed through as they are
+const MAX_HEAD: usize = 16 * 1024;
+const MAX_FIELD: usize = 1024 * 1024;
+
+/// replace domains in the text fields of the `multipart/form-data` body of
+/// `req` as it is sent, files are passed through untouched
+pub fn rewrite(req: &mut Request, replacer: Arc<Replacer>) {
+    let Some(boundary) = req
+        .content_type()
+        .and_then(|i| i.param("boundary").map(|i| i.to_string()))
+    else {
+        return;
+    };
+    let body = req.take_body();
+    let mime = body.mime().clone();
+    let reader = Parts {
+        inner: body,
+        replacer,
+        delimiter: format!("\r\n--{}", boundary).into_bytes(),
+        state: State::Data { text: false },
+        // the first delimiter is at the start of the body, without a line
+        // break before it
+        received: b"\r\n".to_vec(),
+        skip: 2,
+        out: Vec::new(),
+        sent: 0,
+        eof: false,
+    };
+    let mut body = Body::from_reader(BufReader::new(reader), None);
+    body.set_mime(mime);
+    req.remove_header(CONTENT_LENGTH);
+    req.swap_body(&mut body);
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    /// the data of a part, or the preamble
+    Data {
+        text: bool,
+    },
+    /// what follows a delimiter, the end of the body or a line break
+    Delimited,
+    Head,
+    /// after the last part, or what can't be parsed
+    Rest,
+}
+
+struct Parts<R> {
+    inner: R,
+    replacer: Arc<Replacer>,
+    delimiter: Vec<u8>,
+    state: State,
+    /// received but not yet parsed
+    received: Vec<u8>,
+    /// bytes not to send, which were not received
+    skip: usize,
+    /// parsed, and the bytes of it sent
+    out: Vec<u8>,
+    sent: usize,
+    eof: bool,
+}
+
+impl<R> Parts<R> {
+    /// move what can be parsed of the received bytes to those to send
+    fn parse(&mut self) {
+        loop {
+            match self.state {
+                State::Rest => return self.emit(self.received.len(), false),
+                State::Data { text } => match find(&self.received, &self.delimiter) {
+                    Some(at) => {
+                        self.emit(at, text);
+                        self.emit(self.delimiter.len(), false);
+                        self.state = State::Delimited;
+                    }
+                    None if self.eof => return self.emit(self.received.len(), text),
+                    None if text && self.received.len() <= MAX_FIELD => return,
+                    None => {
+                        // the end may be the start of a delimiter
+                        let kept = self.delimiter.len() - 1;
+                        self.emit(self.received.len().saturating_sub(kept), false);
+                        self.state = State::Data { text: false };
+                        return;
+                    }
+                },
+                State::Delimited => {
+                    if self.received.len() < 2 {
+                        if self.eof {
+                            self.state = State::Rest;
+                            continue;
+                        }
+                        return;
+                    }
+                    self.state = match &self.received[..2] {
+                        b"\r\n" => State::Head,
+                        _ => State::Rest,
+                    };
+                    self.emit(2, false);
+                }
+                State::Head => match find(&self.received, b"\r\n\r\n") {
+                    Some(at) => {
+                        let text = is_text(&self.received[..at]);
+                        self.emit(at + 4, false);
+                        self.state = State::Data { text };
+                    }
+                    None if self.eof || self.received.len() > MAX_HEAD => {
+                        self.state = State::Rest;
+                    }
+                    None => return,
+                },
+            }
+        }
+    }
+
+    /// send the first `len` received bytes, with domains replaced if `text`
+    fn emit(&mut self, len: usize, text: bool) {
+        let bytes: Vec<u8> = self.received.drain(..len).collect();
+        let bytes = match std::str::from_utf8(&bytes) {
+            Ok(field) if text => self.replacer.replace(field).into_bytes(),
+            _ => bytes,
+        };
+        let skipped = self.skip.min(bytes.len());
+        self.skip -= skipped;
+        self.out.extend_from_slice(&bytes[skipped..]);
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Parts<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        loop {
+            if this.sent < this.out.len() {
+                let n = buf.len().min(this.out.len() - this.sent);
+                buf[..n].copy_from_slice(&this.out[this.sent..this.sent + n]);
+                this.sent += n;
+                if this.sent == this.out.len() {
+                    this.out.clear();
+                    this.sent = 0;
+                }
+                return Poll::Ready(Ok(n));
+            }
+            if this.eof {
+                return Poll::Ready(Ok(0));
+            }
+            let mut chunk = [0; 8192];
+            let n = ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk))?;
+            this.eof = n == 0;
+            this.received.extend_from_slice(&chunk[..n]);
+            this.parse();
+        }
+    }
+}
+
+/// whether the part of `head` is a field rather than a file, and of text
+fn is_text(head: &[u8]) -> bool {
+    let head = String::from_utf8_lossy(head).to_ascii_lowercase();
+    let mut text = true;
+    for line in head.split("\r\n") {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        match name.trim() {
+            "content-disposition" => text &= !value.contains("filename"),
+            "content-type" => text &= value.trim().starts_with("text/"),
+            _ => (),
+        }
+    }
+    text
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|i| i == needle)
+}
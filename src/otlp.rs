@@ -0,0 +1,265 @@
+use std::{
+    fmt::Debug,
+    io,
+    pin::Pin,
+    sync::mpsc::{self, Receiver, RecvTimeoutError, Sender},
+    task::{self, Poll},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Result};
+use async_io::block_on;
+use futures_lite::io::{AsyncBufRead, AsyncRead};
+use http_types::{headers::CONTENT_TYPE, Body, Method, Request, Response, Url};
+use serde_json::{json, Value};
+use tracing::{
+    error,
+    field::{Field, Visit},
+    span::{Attributes, Id, Record},
+    subscriber::NoSubscriber,
+    Span, Subscriber,
+};
+use tracing_subscriber::{layer::Context, prelude::*, registry::LookupSpan, Layer};
+use uuid::Uuid;
+
+use crate::{client, config::Otlp};
+
+const BATCH_SIZE: usize = 512;
+const BATCH_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// export spans to an OTLP/HTTP collector, e.g. Jaeger or Tempo
+pub fn init(config: &Otlp) -> Result<()> {
+    let endpoint = Url::parse(&format!(
+        "{}/v1/traces",
+        config.endpoint.trim_end_matches('/')
+    ))?;
+    let service_name = config
+        .service_name
+        .clone()
+        .unwrap_or_else(|| env!("CARGO_PKG_NAME").to_string());
+    let (tx, rx) = mpsc::channel();
+    thread::Builder::new()
+        .name("otlp".to_string())
+        .spawn(move || export(rx, endpoint, service_name))?;
+    // not `try_init`, which would also take over `log` from env_logger
+    let subscriber = tracing_subscriber::registry().with(OtlpLayer { tx });
+    tracing::subscriber::set_global_default(subscriber)
+        .map_err(|e| anyhow!("can not install otlp layer: {}", e))
+}
+
+struct SpanData {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    parent_span_id: Option<[u8; 8]>,
+    name: &'static str,
+    kind: u8,
+    start: u128,
+    end: u128,
+    attributes: Vec<Value>,
+}
+
+struct OtlpLayer {
+    tx: Sender<SpanData>,
+}
+
+impl<S> Layer<S> for OtlpLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let span = match ctx.span(id) {
+            Some(span) => span,
+            None => return,
+        };
+        let parent = span.parent().and_then(|parent| {
+            parent
+                .extensions()
+                .get::<SpanData>()
+                .map(|data| (data.trace_id, data.span_id))
+        });
+        let id = Uuid::new_v4();
+        let mut data = SpanData {
+            trace_id: match parent {
+                Some((trace_id, _)) => trace_id,
+                None => *Uuid::new_v4().as_bytes(),
+            },
+            span_id: id.as_bytes()[..8].try_into().unwrap_or_default(),
+            parent_span_id: parent.map(|(_, span_id)| span_id),
+            name: attrs.metadata().name(),
+            kind: 1,
+            start: now(),
+            end: 0,
+            attributes: Vec::new(),
+        };
+        attrs.record(&mut data);
+        span.extensions_mut().insert(data);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(data) = span.extensions_mut().get_mut::<SpanData>() {
+                values.record(data);
+            }
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(&id) {
+            if let Some(mut data) = span.extensions_mut().remove::<SpanData>() {
+                data.end = now();
+                let _ = self.tx.send(data);
+            }
+        }
+    }
+}
+
+impl Visit for SpanData {
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.record_str(field, &format!("{:?}", value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "otel.kind" {
+            // SPAN_KIND_SERVER and SPAN_KIND_CLIENT
+            self.kind = match value {
+                "server" => 2,
+                "client" => 3,
+                _ => 1,
+            };
+            return;
+        }
+        self.attributes
+            .push(json!({ "key": field.name(), "value": { "stringValue": value } }));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.attributes
+            .push(json!({ "key": field.name(), "value": { "intValue": value.to_string() } }));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.attributes
+            .push(json!({ "key": field.name(), "value": { "intValue": value.to_string() } }));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.attributes
+            .push(json!({ "key": field.name(), "value": { "boolValue": value } }));
+    }
+}
+
+/// keep `span` open until the response body has been sent
+pub fn trace_body(resp: &mut Response, span: Span) {
+    let has_content_type = resp.header(CONTENT_TYPE).is_some();
+    let body = resp.take_body();
+    let len = body.len();
+    resp.set_body(Body::from_reader(TracedBody { body, _span: span }, len));
+    if !has_content_type {
+        resp.remove_header(CONTENT_TYPE);
+    }
+}
+
+struct TracedBody {
+    body: Body,
+    _span: Span,
+}
+
+impl AsyncRead for TracedBody {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.body).poll_read(cx, buf)
+    }
+}
+
+impl AsyncBufRead for TracedBody {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<&[u8]>> {
+        Pin::new(&mut self.get_mut().body).poll_fill_buf(cx)
+    }
+
+    fn consume(mut self: Pin<&mut Self>, amt: usize) {
+        Pin::new(&mut self.body).consume(amt)
+    }
+}
+
+fn now() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|i| i.as_nanos())
+        .unwrap_or_default()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn export(rx: Receiver<SpanData>, endpoint: Url, service_name: String) {
+    while let Ok(first) = rx.recv() {
+        let mut batch = vec![first];
+        let deadline = Instant::now() + BATCH_TIMEOUT;
+        let mut closed = false;
+        while batch.len() < BATCH_SIZE {
+            match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(data) => batch.push(data),
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => {
+                    closed = true;
+                    break;
+                }
+            }
+        }
+        // the export requests must not produce spans themselves
+        let result = tracing::subscriber::with_default(NoSubscriber::default(), || {
+            block_on(send(&endpoint, &service_name, batch))
+        });
+        if let Err(e) = result {
+            error!("can not export spans: {}", e);
+        }
+        if closed {
+            return;
+        }
+    }
+}
+
+async fn send(endpoint: &Url, service_name: &str, batch: Vec<SpanData>) -> Result<()> {
+    let spans: Vec<_> = batch
+        .into_iter()
+        .map(|data| {
+            json!({
+                "traceId": hex(&data.trace_id),
+                "spanId": hex(&data.span_id),
+                "parentSpanId": data.parent_span_id.map(|i| hex(&i)).unwrap_or_default(),
+                "name": data.name,
+                "kind": data.kind,
+                "startTimeUnixNano": data.start.to_string(),
+                "endTimeUnixNano": data.end.to_string(),
+                "attributes": data.attributes,
+            })
+        })
+        .collect();
+    let body = json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [
+                    { "key": "service.name", "value": { "stringValue": service_name } }
+                ]
+            },
+            "scopeSpans": [{
+                "scope": { "name": env!("CARGO_PKG_NAME"), "version": env!("CARGO_PKG_VERSION") },
+                "spans": spans,
+            }]
+        }]
+    });
+    let mut req = Request::new(Method::Post, endpoint.clone());
+    req.set_body(Body::from_json(&body).map_err(|e| anyhow!("{}", e))?);
+    let resp = client::send(req).await.map_err(|e| anyhow!("{}", e))?;
+    anyhow::ensure!(
+        resp.status().is_success(),
+        "collector responded {}",
+        resp.status()
+    );
+    Ok(())
+}
@@ -0,0 +1,74 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use anyhow::{anyhow, bail, ensure, Result};
+use futures_lite::io::{AsyncRead, AsyncReadExt};
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+/// longest line of version 1, with its CRLF
+const V1_MAX_SIZE: usize = 107;
+
+/// read the PROXY protocol header, version 1 or 2, a balancer in front sends
+/// before the request, returning the address of the client it tells,
+/// `None` for its own connections, like health checks
+pub async fn read<R: AsyncRead + Unpin>(stream: &mut R) -> Result<Option<SocketAddr>> {
+    let mut head = [0; 16];
+    stream.read_exact(&mut head[..6]).await?;
+    if &head[..6] == b"PROXY " {
+        return read_v1(stream).await;
+    }
+    stream.read_exact(&mut head[6..]).await?;
+    ensure!(
+        head[..12] == V2_SIGNATURE[..],
+        "missing PROXY protocol header"
+    );
+    ensure!(head[12] >> 4 == 2, "unsupported PROXY protocol version");
+    let len = u16::from_be_bytes([head[14], head[15]]) as usize;
+    let mut addresses = vec![0; len];
+    stream.read_exact(&mut addresses).await?;
+    // LOCAL, the balancer's own connection
+    if head[12] & 0x0f == 0 {
+        return Ok(None);
+    }
+    let a = &addresses;
+    match head[13] >> 4 {
+        1 if len >= 12 => {
+            let ip = Ipv4Addr::new(a[0], a[1], a[2], a[3]);
+            let port = u16::from_be_bytes([a[8], a[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)))
+        }
+        2 if len >= 36 => {
+            let ip: [u8; 16] = a[..16].try_into()?;
+            let port = u16::from_be_bytes([a[32], a[33]]);
+            Ok(Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(ip)), port)))
+        }
+        // unix sockets or unspecified
+        _ => Ok(None),
+    }
+}
+
+/// the rest of a `PROXY TCP4 <src> <dst> <src port> <dst port>\r\n` line
+async fn read_v1<R: AsyncRead + Unpin>(stream: &mut R) -> Result<Option<SocketAddr>> {
+    let mut line = Vec::new();
+    let mut byte = [0];
+    while !line.ends_with(b"\r\n") {
+        ensure!(line.len() < V1_MAX_SIZE, "PROXY protocol header too long");
+        stream.read_exact(&mut byte).await?;
+        line.push(byte[0]);
+    }
+    let line = std::str::from_utf8(&line)?;
+    let mut fields = line.split_ascii_whitespace();
+    match fields.next() {
+        Some("TCP4" | "TCP6") => (),
+        Some("UNKNOWN") => return Ok(None),
+        _ => bail!("invalid PROXY protocol header: {}", line.trim_end()),
+    }
+    let ip: IpAddr = fields
+        .next()
+        .and_then(|i| i.parse().ok())
+        .ok_or_else(|| anyhow!("invalid PROXY protocol source: {}", line.trim_end()))?;
+    let port: u16 = fields
+        .nth(1)
+        .and_then(|i| i.parse().ok())
+        .ok_or_else(|| anyhow!("invalid PROXY protocol port: {}", line.trim_end()))?;
+    Ok(Some(SocketAddr::new(ip, port)))
+}
@@ -0,0 +1,87 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::config::{Rate, RateLimit};
+
+/// buckets kept before full ones, of clients gone quiet, are dropped
+const MAX_BUCKETS: usize = 100_000;
+
+/// token buckets of clients, by the domain their rate is configured for
+/// and their address or session
+#[derive(Debug)]
+pub struct RateLimiter {
+    config: RateLimit,
+    buckets: Mutex<HashMap<(String, String), Bucket>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimit) -> RateLimiter {
+        RateLimiter {
+            config,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn by_session(&self) -> bool {
+        self.config.by_session
+    }
+
+    /// take a token of the bucket of `client` for the mirror `host`,
+    /// otherwise the time until there is one
+    pub fn check(&self, host: &str, client: &str) -> Result<(), Duration> {
+        let (domain, rate) = self.rate(host);
+        if rate.rate <= 0.0 {
+            return Ok(());
+        }
+        let burst = rate.burst.unwrap_or(rate.rate).max(1.0);
+        let now = Instant::now();
+        let mut buckets = match self.buckets.lock() {
+            Ok(buckets) => buckets,
+            Err(e) => e.into_inner(),
+        };
+        if buckets.len() >= MAX_BUCKETS {
+            buckets.retain(|_, i| i.tokens + refill(i, now, rate.rate) < burst);
+        }
+        let bucket = buckets
+            .entry((domain.to_string(), client.to_string()))
+            .or_insert(Bucket {
+                tokens: burst,
+                updated: now,
+            });
+        bucket.tokens = (bucket.tokens + refill(bucket, now, rate.rate)).min(burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / rate.rate))
+        }
+    }
+
+    /// the rate of the mirror `host` and the domain it is configured for,
+    /// empty for the default one
+    fn rate(&self, host: &str) -> (&str, Rate) {
+        self.config
+            .domain
+            .iter()
+            .filter(|(domain, _)| host.contains(domain.as_str()))
+            .max_by_key(|(domain, _)| domain.len())
+            .map_or(("", self.config.rate), |(domain, rate)| {
+                (domain.as_str(), *rate)
+            })
+    }
+}
+
+/// tokens added to `bucket` since it was last updated
+fn refill(bucket: &Bucket, now: Instant, rate: f64) -> f64 {
+    now.duration_since(bucket.updated).as_secs_f64() * rate
+}
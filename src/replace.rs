@@ -0,0 +1,268 @@
+use std::{borrow::Cow, ops::Range};
+
+use aho_corasick::{AhoCorasick, MatchKind};
+use anyhow::Result;
+use regex::Regex;
+
+/// forms of a domain escaped in urls, json or scripts, as replacements of
+/// its characters
+const ESCAPES: &[&[(char, &str)]] = &[
+    &[('.', "%2E"), (':', "%3A"), ('/', "%2F")],
+    &[('.', "%2e"), (':', "%3a"), ('/', "%2f")],
+    &[('/', "\\/")],
+    &[('.', "\\u002e"), ('/', "\\u002f")],
+];
+
+/// replace all patterns in a single pass over the text, the longest
+/// pattern wins where several match at the same position
+#[derive(Debug)]
+pub struct Replacer {
+    automaton: AhoCorasick,
+    replacements: Vec<String>,
+    /// applied to hosts before the patterns
+    hosts: Vec<HostRule>,
+}
+
+/// how a host is replaced
+#[derive(Debug)]
+pub enum HostRule {
+    /// hosts matching the regex, the replacement may refer to its groups as
+    /// `$1` or `${name}`
+    Regex(Regex, String),
+    /// hosts of urls, encoded as a subdomain of the mirror domain, like
+    /// `www.example.com` as `www-example-com.mirror.example`
+    Encode(String),
+    /// subdomains of the mirror domain, decoded to the host they encode
+    Decode(String),
+}
+
+impl HostRule {
+    fn apply(&self, host: &str, in_url: bool) -> Option<String> {
+        match self {
+            HostRule::Regex(regex, replacement) => regex
+                .is_match(host)
+                .then(|| regex.replace(host, replacement.as_str()).into_owned()),
+            HostRule::Encode(domain) => {
+                let mirrored = host == domain || host.ends_with(&format!(".{}", domain));
+                (in_url && !mirrored)
+                    .then(|| format!("{}.{}", host.replace('-', "--").replace('.', "-"), domain))
+            }
+            HostRule::Decode(domain) => {
+                let encoded = host.strip_suffix(domain)?.strip_suffix('.')?;
+                Some(
+                    encoded
+                        .split("--")
+                        .map(|i| i.replace('-', "."))
+                        .collect::<Vec<_>>()
+                        .join("-"),
+                )
+            }
+        }
+    }
+}
+
+impl Replacer {
+    /// from (pattern, replacement) pairs
+    pub fn new<'a, I>(pairs: I) -> Result<Replacer>
+    where
+        I: IntoIterator<Item = (&'a str, &'a str)>,
+    {
+        let (patterns, replacements): (Vec<_>, Vec<_>) = pairs
+            .into_iter()
+            .map(|(pattern, replacement)| (pattern, replacement.to_string()))
+            .unzip();
+        let automaton = AhoCorasick::builder()
+            .match_kind(MatchKind::LeftmostLongest)
+            .build(patterns)?;
+        Ok(Replacer {
+            automaton,
+            replacements,
+            hosts: Vec::new(),
+        })
+    }
+
+    /// also replace hosts by the first rule applying to them, hosts the
+    /// patterns match are left to them
+    pub fn with_hosts(mut self, hosts: Vec<HostRule>) -> Replacer {
+        self.hosts = hosts;
+        self
+    }
+
+    /// domain pairs, also replaced where they are percent-encoded or escaped,
+    /// and in their unicode form for internationalized domains
+    pub fn domains<'a, I>(pairs: I) -> Result<Replacer>
+    where
+        I: IntoIterator<Item = (&'a str, &'a str)>,
+    {
+        let mut unicode_pairs = Vec::new();
+        let mut expanded = Vec::new();
+        for (pattern, replacement) in pairs {
+            if let Some(unicode_pattern) = unicode(pattern) {
+                let unicode_replacement = unicode(replacement);
+                let unicode_replacement = unicode_replacement.as_deref().unwrap_or(replacement);
+                unicode_pairs.push((unicode_pattern, unicode_replacement.to_string()));
+            }
+            expanded.push((pattern.to_string(), replacement.to_string()));
+            for escape in ESCAPES {
+                let escaped = escape_with(pattern, escape);
+                if escaped != pattern {
+                    expanded.push((escaped, escape_with(replacement, escape)));
+                }
+            }
+        }
+        expanded.extend(unicode_pairs);
+        Replacer::new(expanded.iter().map(|(p, r)| (p.as_str(), r.as_str())))
+    }
+
+    /// whether any pattern occurs in the text, or a host to replace
+    pub fn is_match(&self, text: &str) -> bool {
+        self.automaton.is_match(text)
+            || hosts(text).any(|(start, host)| self.replace_host(text, start, host).is_some())
+    }
+
+    pub fn replace(&self, text: &str) -> String {
+        let text = self.replace_hosts(text);
+        self.automaton.replace_all(&text, &self.replacements)
+    }
+
+    fn replace_host(&self, text: &str, start: usize, host: &str) -> Option<String> {
+        if self.automaton.is_match(host) {
+            return None;
+        }
+        let in_url = text[..start].ends_with("//");
+        self.hosts.iter().find_map(|i| i.apply(host, in_url))
+    }
+
+    fn replace_hosts<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        if self.hosts.is_empty() {
+            return Cow::Borrowed(text);
+        }
+        let mut replaced = String::new();
+        let mut copied = 0;
+        for (start, host) in hosts(text) {
+            if let Some(host_replaced) = self.replace_host(text, start, host) {
+                replaced.push_str(&text[copied..start]);
+                replaced.push_str(&host_replaced);
+                copied = start + host.len();
+            }
+        }
+        if copied == 0 {
+            return Cow::Borrowed(text);
+        }
+        replaced.push_str(&text[copied..]);
+        Cow::Owned(replaced)
+    }
+
+    /// replace only within the ordered, non-overlapping byte ranges of the text
+    pub fn replace_ranges<I>(&self, text: &str, ranges: I) -> String
+    where
+        I: IntoIterator<Item = Range<usize>>,
+    {
+        self.replace_urls(text, ranges, None)
+    }
+
+    /// like [`Replacer::replace_ranges`] for ranges that are urls, a root
+    /// relative one, like `/a.png`, is put under `prefix`, the path the
+    /// mirror serves its origin at
+    pub fn replace_urls<I>(&self, text: &str, urls: I, prefix: Option<&str>) -> String
+    where
+        I: IntoIterator<Item = Range<usize>>,
+    {
+        let mut out = String::with_capacity(text.len());
+        let mut copied = 0;
+        for range in urls {
+            out.push_str(&text[copied..range.start]);
+            let url = &text[range.clone()];
+            if let Some(prefix) = prefix.filter(|_| url.starts_with('/') && !url.starts_with("//"))
+            {
+                out.push_str(prefix);
+            }
+            out.push_str(&self.replace(url));
+            copied = range.end;
+        }
+        out.push_str(&text[copied..]);
+        out
+    }
+}
+
+/// the unicode form of a domain pattern with `xn--` labels, keeping a
+/// leading dot and what follows the domain, like a port or a path
+fn unicode(pattern: &str) -> Option<String> {
+    let end = pattern.find([':', '/']).unwrap_or(pattern.len());
+    let (domain, rest) = pattern.split_at(end);
+    if !domain.contains("xn--") {
+        return None;
+    }
+    let (dot, name) = match domain.strip_prefix('.') {
+        Some(name) => (".", name),
+        None => ("", domain),
+    };
+    match idna::domain_to_unicode(name) {
+        (name, Ok(())) => Some(format!("{}{}{}", dot, name, rest)),
+        _ => None,
+    }
+}
+
+fn escape_with(text: &str, escape: &[(char, &str)]) -> String {
+    text.chars().fold(String::new(), |mut escaped, c| {
+        match escape.iter().find(|(from, _)| *from == c) {
+            Some((_, to)) => escaped.push_str(to),
+            None => escaped.push(c),
+        }
+        escaped
+    })
+}
+
+/// words that may be hosts, with their byte offsets
+fn hosts(text: &str) -> impl Iterator<Item = (usize, &str)> {
+    let is_host = |c: char| c.is_ascii_alphanumeric() || c == '.' || c == '-';
+    let mut start = None;
+    text.char_indices()
+        .chain([(text.len(), ' ')])
+        .filter_map(move |(i, c)| match (is_host(c), start) {
+            (true, None) => {
+                start = Some(i);
+                None
+            }
+            (false, Some(s)) => {
+                start = None;
+                // a sentence may end right after a host
+                let host = text[s..i].trim_end_matches('.');
+                host.contains('.').then_some((s, host))
+            }
+            _ => None,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encoded_hosts_decode_back() {
+        let encode = HostRule::Encode("m.test".to_string());
+        let decode = HostRule::Decode("m.test".to_string());
+        for host in [
+            "www.example.com",
+            "my-site.example.com",
+            "a--b.example.com",
+            "xn--fiq228c.com",
+        ] {
+            let encoded = encode.apply(host, true).unwrap();
+            assert!(encoded.ends_with(".m.test"), "{}", encoded);
+            assert!(!encoded[..encoded.len() - 7].contains('.'), "{}", encoded);
+            assert_eq!(decode.apply(&encoded, true).as_deref(), Some(host));
+        }
+    }
+
+    #[test]
+    fn only_hosts_of_urls_off_the_mirror_are_encoded() {
+        let encode = HostRule::Encode("m.test".to_string());
+        assert_eq!(encode.apply("www.example.com", false), None);
+        assert_eq!(encode.apply("m.test", true), None);
+        assert_eq!(encode.apply("www-example-com.m.test", true), None);
+        let decode = HostRule::Decode("m.test".to_string());
+        assert_eq!(decode.apply("www.example.com", true), None);
+        assert_eq!(decode.apply("m.test", true), None);
+    }
+}
@@ -0,0 +1,21 @@
+/// index of the closing quote of a string starting at `start`, or of the
+/// line end if it is unterminated
+pub fn string_end(text: &[u8], start: usize, quote: u8) -> usize {
+    let mut i = start;
+    while i < text.len() {
+        match text[i] {
+            b'\\' => i += 1,
+            c if c == quote || c == b'\n' => return i,
+            _ => (),
+        }
+        i += 1;
+    }
+    text.len()
+}
+
+pub fn find(text: &[u8], start: usize, needle: &[u8]) -> Option<usize> {
+    text.get(start..)?
+        .windows(needle.len())
+        .position(|i| i == needle)
+        .map(|i| i + start)
+}
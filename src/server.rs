@@ -1,270 +1,1504 @@
 use std::{
     borrow::Cow,
-    net::{SocketAddr, TcpListener, TcpStream},
+    collections::HashMap,
+    io, iter,
+    net::{IpAddr, SocketAddr, TcpListener},
     path::Path,
-    sync::Arc,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    task::{ready, Context, Poll},
+    time::{Duration, Instant},
 };
 
-use anyhow::{anyhow, Result};
+use anyhow::Result;
 use async_executor::Executor;
-use async_io::{block_on, Async};
-use async_net::{resolve, AsyncToSocketAddrs};
-use futures_lite::io::{AsyncRead, BufReader};
+use async_io::{block_on, Async, Timer};
+use async_lock::Semaphore;
+use futures_lite::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader, Cursor},
+    FutureExt,
+};
 use http_types::{
-    headers::{HeaderValue, CONTENT_LENGTH},
-    Body, Cookie, Request, Response, StatusCode,
+    cookies,
+    headers::{HeaderValue, Headers, CONTENT_LENGTH},
+    url::Host,
+    Body, Cookie, Method, Request, Response, StatusCode, Url,
+};
+use percent_encoding::percent_decode_str;
+use redb::Database;
+use regex::{NoExpand, Regex};
+use serde::Deserialize;
+use time::OffsetDateTime;
+use tracing::{error, info, info_span, Instrument};
+
+#[cfg(feature = "lua")]
+use crate::lua;
+#[cfg(feature = "otlp")]
+use crate::otlp;
+#[cfg(feature = "wasm")]
+use crate::wasm;
+use crate::{
+    access_log::{AccessLog, Entry, Upstream},
+    admin::{self, Stats},
+    balancer::Balancer,
+    cache::{Cache, Hit, Lookup},
+    charset,
+    circuit::Circuits,
+    client,
+    config::{
+        Access, Account, Backend, Config, CspMode, ForwardedHeaders, HeaderAction, HeaderTarget,
+        Inject, InjectPosition, Oversized, Policy, RangeMode, SameSite, ServiceWorker, Sitemap,
+        Substitute,
+    },
+    cors, csp, css,
+    error_page::ErrorPages,
+    head_guard::{within, HeadGuard},
+    html, js, ldap, m3u8,
+    maintenance::Maintenance,
+    manifest,
+    middleware::{self, Layer, Middleware, Next, Stage},
+    multipart, oidc, proxy_protocol,
+    rate_limit::RateLimiter,
+    replace::{HostRule, Replacer},
+    session, streaming,
+    throttle::Throttle,
+    traffic::Traffic,
+    trailers::{self, TrailerWriter},
+    tunnel, xml,
 };
-use redb::{Database, TableDefinition};
-use regex::Regex;
-use tracing::error;
 
-use crate::config::{Account, CONFIG};
+static EXECUTOR: Executor = Executor::new();
 
-const COOKIE_NAME: &str = "__wj_token";
 const LOGIN_URL_PATH: &str = "/__wj__login";
-const TOKENS: TableDefinition<String, ()> = TableDefinition::new("tokens");
+const CSRF_COOKIE_NAME: &str = "__wj_csrf";
+const HEALTH_URL_PATH: &str = "/__wj__health";
+const READY_URL_PATH: &str = "/__wj__ready";
+/// how long the result of the readiness checks is reused
+const READY_CACHE: Duration = Duration::from_secs(5);
+const DISALLOW_ALL: &str = "User-agent: *\nDisallow: /\n";
+const EMPTY_SITEMAP: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<urlset xmlns="http://www.sitemaps.org/schemas/sitemap/0.9"></urlset>
+"#;
+const UNREGISTER_SERVICE_WORKERS: &str = "<script>navigator.serviceWorker&&navigator.serviceWorker\
+    .getRegistrations().then(function(r){r.forEach(function(i){i.unregister()})})</script>";
+
+/// the mirroring engine, for embedding it in another application
+#[derive(Debug, Clone)]
+pub struct Proxy {
+    pub(crate) current: Current,
+}
+
+impl Proxy {
+    pub fn new(config: Config) -> Result<Proxy> {
+        Proxy::with_middleware(config, Vec::new())
+    }
+
+    /// a proxy running each of `middleware` before its stage, in order
+    pub fn with_middleware(
+        config: Config,
+        middleware: Vec<(Stage, Arc<dyn Middleware>)>,
+    ) -> Result<Proxy> {
+        config.check_domain()?;
+        let mut forward = Forward::new(Arc::new(config))?;
+        forward.layers = middleware::layers(middleware);
+        Ok(Proxy {
+            current: Current(Arc::new(RwLock::new(Arc::new(forward)))),
+        })
+    }
+
+    /// the config in use, which changes when it is reloaded
+    pub fn config(&self) -> Arc<Config> {
+        self.current.get().config.clone()
+    }
+
+    /// answer a request for a mirror domain, its peer address, if set, is
+    /// the client's
+    pub async fn handle(&self, req: Request) -> Response {
+        self.current.get().handle(req).await
+    }
+
+    /// accept connections of `listener` until it fails, also answering the
+    /// admin api on its own address if configured, connections are handled
+    /// by `worker_threads` threads, this one included
+    pub async fn serve(&self, listener: TcpListener) -> Result<()> {
+        let listener = Async::new(listener)?;
+        let config = self.config();
+        // workers stop once this is dropped, when serving ends
+        let (_stop, stopped) = async_channel::bounded::<()>(1);
+        for i in 1..config.worker_threads() {
+            let stopped = stopped.clone();
+            std::thread::Builder::new()
+                .name(format!("worker-{}", i))
+                .spawn(move || block_on(EXECUTOR.run(stopped.recv())))?;
+        }
+        EXECUTOR
+            .run(async {
+                match &config.admin {
+                    Some(config) => {
+                        let admin_address: SocketAddr = config.listen_address.parse()?;
+                        let admin_listener = Async::<TcpListener>::bind(admin_address)?;
+                        info!("admin api listening on {}", admin_address);
+                        listen(admin_listener, self.current.clone(), true)
+                            .or(listen(listener, self.current.clone(), false))
+                            .await
+                    }
+                    None => listen(listener, self.current.clone(), false).await,
+                }
+            })
+            .await
+    }
+}
+
+/// the forward requests are answered by, replaced when the config is reloaded
+#[derive(Clone, Debug)]
+pub(crate) struct Current(Arc<RwLock<Arc<Forward>>>);
+
+impl Current {
+    pub(crate) fn get(&self) -> Arc<Forward> {
+        match self.0.read() {
+            Ok(forward) => forward.clone(),
+            Err(e) => e.into_inner().clone(),
+        }
+    }
+
+    /// read the config file again and answer further requests with it,
+    /// those being answered finish with the previous one
+    pub(crate) fn reload(&self) -> Result<()> {
+        let forward = self.get();
+        let Some(file) = &forward.config.file else {
+            anyhow::bail!("the config was not read from a file");
+        };
+        let config = Config::from_file(file)?;
+        if config.listen_address != forward.config.listen_address
+            || config.admin.as_ref().map(|i| &i.listen_address)
+                != forward.config.admin.as_ref().map(|i| &i.listen_address)
+        {
+            info!("listening addresses change only on restart");
+        }
+        let reloaded = Arc::new(forward.reload(config)?);
+        match self.0.write() {
+            Ok(mut forward) => *forward = reloaded,
+            Err(e) => *e.into_inner() = reloaded,
+        }
+        Ok(())
+    }
+
+    /// answer a request accepted on the admin address, if `admin`, or the
+    /// mirror's
+    pub(crate) async fn answer(&self, req: Request, admin: bool) -> Response {
+        let forward = self.get();
+        match (&forward.config.admin, admin) {
+            (Some(config), true) => admin::handle(config, req, &forward, self).await,
+            _ => forward.handle(req).await,
+        }
+    }
+}
 
 #[derive(Debug)]
-struct Forward {
-    replace_domain: Vec<(Regex, String)>,
-    restore_domain: Vec<(Regex, String)>,
-    db: Database,
+pub(crate) struct Forward {
+    pub(crate) config: Arc<Config>,
+    /// the pipeline requests pass
+    layers: Vec<Layer>,
+    replace_domain: Arc<Replacer>,
+    restore_domain: Arc<Replacer>,
+    pub(crate) db: Arc<Database>,
+    oidc: oidc::Client,
+    access_log: Option<Arc<AccessLog>>,
+    pub(crate) stats: Arc<Stats>,
+    pub(crate) cache: Option<Arc<Cache>>,
+    /// mirror domain to its robots.txt
+    robots: HashMap<String, String>,
+    error_pages: ErrorPages,
+    pub(crate) maintenance: Maintenance,
+    pub(crate) balancer: Arc<Balancer>,
+    circuits: Option<Arc<Circuits>>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    throttle: Throttle,
+    pub(crate) traffic: Option<Traffic>,
+    /// permits of `max_connections`
+    pub(crate) connections: Option<Arc<Semaphore>>,
+    /// permits of `max_concurrent_requests`
+    requests: Option<Arc<Semaphore>>,
+    /// `substitute` with their compiled patterns
+    substitutions: Vec<(Substitute, Regex)>,
+    /// `inject` with their snippets
+    injections: Vec<(Inject, String)>,
+    /// the last result of the readiness checks and when they ran
+    readiness: Mutex<Option<(Instant, bool, serde_json::Value)>>,
+    #[cfg(feature = "lua")]
+    lua: Option<lua::Hooks>,
+    #[cfg(feature = "wasm")]
+    plugins: Vec<wasm::Plugin>,
+}
+
+/// where a request rewritten for upstream came from, the mirror host and
+/// the path prefix of its origin
+#[derive(Clone)]
+struct Routing {
+    host: String,
+    prefix: Option<String>,
 }
 
 impl Forward {
-    fn new() -> Result<Forward> {
-        let mut replace_domain = Vec::new();
-        for (k, v) in &CONFIG.domain_name {
-            let i = (Regex::new(&v.replace('.', "\\."))?, k.to_string());
-            replace_domain.push(i);
+    fn new(config: Arc<Config>) -> Result<Forward> {
+        let db_filename = Path::new(&config.data_dir).join("db.redb");
+        let db = Database::create(db_filename)?;
+        let cache = match (&config.cache, &config.memory_cache) {
+            (None, None) => None,
+            (disk, memory) => Some(Cache::new(
+                &config.data_dir,
+                disk.as_ref(),
+                memory.as_ref(),
+            )?),
+        };
+        Forward::build(config, Arc::new(db), cache.map(Arc::new))
+    }
+
+    /// a forward for `config`, keeping the database, the cache, the counters,
+    /// maintenance turned on or off since, and the limits, circuits and
+    /// backend health whose settings are left as they were, of this one; the
+    /// data directory and the cache are not changed
+    fn reload(&self, config: Config) -> Result<Forward> {
+        config.check_domain()?;
+        let config = Arc::new(config);
+        let mut forward = Forward::build(config.clone(), self.db.clone(), self.cache.clone())?;
+        forward.layers = self.layers.clone();
+        forward.stats = self.stats.clone();
+        forward.maintenance.keep(
+            &self.maintenance,
+            &self.config.maintenance,
+            &config.maintenance,
+        );
+        if config.backends == self.config.backends {
+            forward.balancer = self.balancer.clone();
+        }
+        if config.circuit_breaker == self.config.circuit_breaker {
+            forward.circuits = self.circuits.clone();
+        }
+        if config.rate_limit == self.config.rate_limit {
+            forward.rate_limiter = self.rate_limiter.clone();
+        }
+        // connections and requests under way hold permits of these
+        if config.max_connections == self.config.max_connections {
+            forward.connections = self.connections.clone();
+        }
+        if config.max_concurrent_requests == self.config.max_concurrent_requests {
+            forward.requests = self.requests.clone();
+        }
+        if let (Some(traffic), Some(quota)) = (&self.traffic, &config.quota) {
+            forward.traffic = Some(traffic.reload(quota)?);
         }
-        let mut restore_domain = Vec::new();
-        for (k, v) in &CONFIG.domain_name {
-            let i = (Regex::new(&k.replace('.', "\\."))?, v.to_string());
-            restore_domain.push(i);
+        Ok(forward)
+    }
+
+    fn build(config: Arc<Config>, db: Arc<Database>, cache: Option<Arc<Cache>>) -> Result<Forward> {
+        let mut replace_hosts: Vec<_> = config
+            .domain_rules
+            .iter()
+            .map(|i| HostRule::Regex(i.origin.clone(), i.to_mirror.clone()))
+            .collect();
+        let mut restore_hosts: Vec<_> = config
+            .domain_rules
+            .iter()
+            .map(|i| HostRule::Regex(i.mirror.clone(), i.to_origin.clone()))
+            .collect();
+        if let Some(domain) = &config.catch_all_domain {
+            replace_hosts.push(HostRule::Encode(domain.clone()));
+            restore_hosts.push(HostRule::Decode(domain.clone()));
         }
+        let patterns = || {
+            let path_prefix = config.path_prefix.iter();
+            config
+                .domain_patterns()
+                .chain(path_prefix.map(|(k, v)| (k.as_str(), v.as_str())))
+        };
+        let ports = config.port_patterns();
+        let ports = ports.iter().map(|(k, v)| (v.as_str(), *k));
+        let replace_domain = Replacer::domains(patterns().map(|(k, v)| (v, k)).chain(ports))?
+            .with_hosts(replace_hosts);
+        let restore_domain = Replacer::domains(patterns())?.with_hosts(restore_hosts);
 
-        let db_filename = Path::new(&CONFIG.data_dir).join("db.redb");
-        let db = Database::create(db_filename)?;
+        let access_log = match &config.access_log {
+            Some(config) => Some(Arc::new(AccessLog::new(
+                config.format,
+                config.path.as_deref(),
+            )?)),
+            None => None,
+        };
+        let mut robots = HashMap::new();
+        for (domain, config) in config.robots.iter().flatten() {
+            let content = match &config.file {
+                Some(file) => std::fs::read_to_string(file)?,
+                None => DISALLOW_ALL.to_string(),
+            };
+            robots.insert(domain.clone(), content);
+        }
+
+        let mut substitutions = Vec::new();
+        for substitute in &config.substitute {
+            let pattern = if substitute.regex {
+                Regex::new(&substitute.find)?
+            } else {
+                Regex::new(&regex::escape(&substitute.find))?
+            };
+            substitutions.push((substitute.clone(), pattern));
+        }
+        let mut injections = Vec::new();
+        for inject in &config.inject {
+            let snippet = match (&inject.snippet, &inject.file) {
+                (Some(snippet), None) => snippet.clone(),
+                (None, Some(file)) => std::fs::read_to_string(file)?,
+                _ => anyhow::bail!("inject needs either a snippet or a file"),
+            };
+            injections.push((inject.clone(), snippet));
+        }
 
         Ok(Forward {
-            replace_domain,
-            restore_domain,
+            config: config.clone(),
+            layers: middleware::layers(Vec::new()),
+            replace_domain: Arc::new(replace_domain),
+            restore_domain: Arc::new(restore_domain),
             db,
+            oidc: oidc::Client::new(),
+            access_log,
+            stats: Arc::new(Stats::new()),
+            cache,
+            robots,
+            error_pages: ErrorPages::load(&config)?,
+            maintenance: Maintenance::new(&config)?,
+            balancer: Arc::new(Balancer::new(&config)?),
+            circuits: config
+                .circuit_breaker
+                .as_ref()
+                .map(|i| Arc::new(Circuits::new(i))),
+            rate_limiter: config
+                .rate_limit
+                .clone()
+                .map(|i| Arc::new(RateLimiter::new(i))),
+            throttle: Throttle::new(config.bandwidth.clone()),
+            traffic: config.quota.as_ref().map(Traffic::new).transpose()?,
+            connections: config.max_connections.map(|i| Arc::new(Semaphore::new(i))),
+            requests: config
+                .max_concurrent_requests
+                .map(|i| Arc::new(Semaphore::new(i))),
+            substitutions,
+            injections,
+            readiness: Mutex::new(None),
+            #[cfg(feature = "lua")]
+            lua: config.lua.as_ref().map(lua::Hooks::load).transpose()?,
+            #[cfg(feature = "wasm")]
+            plugins: config
+                .wasm
+                .iter()
+                .map(wasm::Plugin::load)
+                .collect::<Result<_>>()?,
         })
     }
 
-    async fn forward(&self, mut req: Request) -> http_types::Result<Response> {
-        if CONFIG.authorization.enabled {
-            if let Some(domain_list) = &CONFIG.authorization.domain_list {
-                if let Some(d) = req.url().domain() {
-                    if let Some(domain) = domain_list.iter().find(|&i| d.contains(i)) {
-                        if req.url().path() == LOGIN_URL_PATH {
-                            return self.login(req, domain).await;
-                        } else if !self.authorization(&req)? {
-                            return Self::show_login_page();
+    async fn handle(self: &Arc<Self>, req: Request) -> Response {
+        let _active = self.stats.begin();
+        let url = req.url().clone();
+        let client = client_ip(&self.config, &req);
+        let origin = req.header("Origin").map(|i| i.as_str().to_string());
+        let entry = self.access_log.as_ref().map(|_| Entry {
+            time: OffsetDateTime::now_utc(),
+            start: Instant::now(),
+            client_ip: client,
+            method: req.method(),
+            url: url.clone(),
+            upstream: None,
+            status: None,
+            bytes: 0,
+        });
+        let span = info_span!(
+            "request",
+            otel.kind = "server",
+            method = %req.method(),
+            url = %req.url(),
+            status = tracing::field::Empty,
+        );
+        let next = Next {
+            forward: self,
+            layers: &self.layers,
+        };
+        let permit = self.requests.as_ref().map(|i| i.try_acquire());
+        let result = match permit {
+            Some(None) => Ok(overloaded()),
+            _ => next.run(req).instrument(span.clone()).await,
+        };
+        drop(permit);
+        let _entered = span.enter();
+        let mut resp = match result {
+            Ok(resp) => resp,
+            Err(e) => {
+                self.stats.error();
+                let status = match e.status() {
+                    StatusCode::BadGateway | StatusCode::GatewayTimeout => e.status(),
+                    _ => StatusCode::InternalServerError,
+                };
+                self.error_pages.response(status, &e.to_string(), &url)
+            }
+        };
+        for (name, value) in self.config.security_headers.iter().flatten() {
+            resp.insert_header(name.as_str(), value.as_str());
+        }
+        self.stats.response(resp.status());
+        span.record("status", u16::from(resp.status()));
+        let host = url.host_str().unwrap_or_default();
+        if let Some(cors) = self.config.cors(host) {
+            cors::apply(cors, origin.as_deref(), &mut resp);
+        }
+        self.throttle.apply(&mut resp, host, client);
+        #[cfg(feature = "otlp")]
+        otlp::trace_body(&mut resp, info_span!(parent: &span, "body"));
+        if let (Some(access_log), Some(entry)) = (&self.access_log, entry) {
+            access_log.record(entry, &mut resp);
+        }
+        resp
+    }
+
+    /// run `stage` of the pipeline for `req`, passing it on to `next`
+    pub(crate) async fn stage(
+        self: &Arc<Self>,
+        stage: Stage,
+        mut req: Request,
+        next: Next<'_>,
+    ) -> http_types::Result<Response> {
+        match stage {
+            Stage::Guard => self.guard(req, next).await,
+            Stage::Auth => self.auth(req, next).await,
+            Stage::LoopDetection => self.detect_loop(req, next).await,
+            Stage::Rewrite => self.rewrite_request(req, next).await,
+            Stage::Cache => self.cached(req, next).await,
+            Stage::Upstream => {
+                let Some(Routing { host, prefix }) = req.ext_mut().remove::<Routing>() else {
+                    return Self::http_error("request was not rewritten");
+                };
+                let lookup = req.ext_mut().remove::<Lookup>();
+                self.fetch(req, lookup, &host, prefix.as_deref()).await
+            }
+        }
+    }
+
+    async fn guard(&self, mut req: Request, next: Next<'_>) -> http_types::Result<Response> {
+        // denied clients get nothing, not even redirects or static files
+        let access = match (req.url().domain(), client_ip(&self.config, &req)) {
+            (Some(d), Some(ip)) => self.config.access_control.check(d, &ip),
+            _ => Access::Default,
+        };
+        if access == Access::Deny {
+            return Self::forbidden();
+        }
+        req.ext_mut().insert(access);
+        match req.url().path() {
+            HEALTH_URL_PATH => return Self::health(true, serde_json::json!({})),
+            // probing upstream is too costly to answer everyone
+            READY_URL_PATH if self.may_check_ready(&req) => {
+                let (ok, checks) = self.readiness().await;
+                return Self::health(ok, checks);
+            }
+            _ => (),
+        }
+        if let Some(url) = canonical_url(&self.config, &req) {
+            let mut resp = Response::new(StatusCode::MovedPermanently);
+            resp.insert_header("Location", url.as_str());
+            return Ok(resp);
+        }
+        let host = req.url().host_str().unwrap_or_default();
+        // before the host is checked, so unmapped ones can be sent elsewhere
+        let path = req.url().path();
+        if let Some(redirect) = self.config.redirect.iter().find(|i| i.matches(host, path)) {
+            let path = match req.url().query() {
+                Some(query) => format!("{}?{}", path, query),
+                None => path.to_string(),
+            };
+            let mut resp = Response::new(StatusCode::try_from(redirect.status)?);
+            resp.insert_header("Location", redirect.target.replace("{path}", &path));
+            return Ok(resp);
+        }
+        if self.config.strict_hosts && !self.config.is_mirror(host) {
+            info!("request for unmapped host {} rejected", host);
+            let mut resp = Response::new(StatusCode::MisdirectedRequest);
+            resp.set_content_type(http_types::mime::PLAIN);
+            resp.set_body("unknown host");
+            return Ok(resp);
+        }
+        if let Some(resp) = self.maintenance.response(host) {
+            return Ok(resp);
+        }
+        // before the origin, and before methods or paths are refused
+        if let Some(resp) = self
+            .config
+            .cors(host)
+            .and_then(|i| cors::preflight(i, &req))
+        {
+            return Ok(resp);
+        }
+        if let Some(limiter) = &self.rate_limiter {
+            // only valid sessions, made up tokens would escape the limit
+            let session = cookie(&req, &self.config.authorization.cookie.name)
+                .filter(|_| limiter.by_session())
+                .filter(|token| session::user(&self.db, token).is_ok_and(|i| i.is_some()));
+            let client = match session {
+                Some(token) => Some(format!("session {}", token)),
+                None => client_ip(&self.config, &req).map(|i| i.to_string()),
+            };
+            if let Some(Err(wait)) = client.map(|client| limiter.check(host, &client)) {
+                return Ok(too_many_requests(wait));
+            }
+        }
+        if let Some(methods) = self.config.allowed_methods(host) {
+            let method = req.method().to_string();
+            if !methods.iter().any(|i| i.eq_ignore_ascii_case(&method)) {
+                let mut resp = Response::new(StatusCode::MethodNotAllowed);
+                resp.insert_header("Allow", methods.join(", ").to_ascii_uppercase());
+                resp.set_content_type(http_types::mime::PLAIN);
+                resp.set_body("method not allowed");
+                return Ok(resp);
+            }
+        }
+        if let Some(rules) = self.config.path_rules(host) {
+            if !rules.allows(req.url().path()) {
+                info!("path {} of {} blocked", req.url().path(), host);
+                return Self::forbidden();
+            }
+        }
+
+        // public, login page assets included
+        if let Some(resp) = static_file(&self.config, &req, host).await {
+            return Ok(resp);
+        }
+
+        let cut_off = match self.config.max_request_body_size {
+            Some(limit) if req.len().is_some_and(|i| i as u64 > limit) => {
+                return Ok(payload_too_large());
+            }
+            Some(limit) if req.len().is_none() => Some(limit_body(&mut req, limit)),
+            _ => None,
+        };
+
+        let resp = next.run(req).await;
+        match cut_off {
+            Some(cut_off) if cut_off.load(Ordering::Relaxed) => Ok(payload_too_large()),
+            _ => resp,
+        }
+    }
+
+    async fn auth(&self, req: Request, next: Next<'_>) -> http_types::Result<Response> {
+        let span = info_span!("auth").entered();
+        let access = req
+            .ext()
+            .get::<Access>()
+            .copied()
+            .unwrap_or(Access::Default);
+        let mut account = None;
+        if self.config.authorization.enabled && access != Access::Allow {
+            if let Some(d) = req.url().domain() {
+                if let Some((domain, policy)) = self.config.authorization.protected_domain(d) {
+                    if req.url().path() == LOGIN_URL_PATH {
+                        let span = span.exit();
+                        return self.login(req, domain, policy).instrument(span).await;
+                    }
+                    let safe = matches!(req.method(), Method::Get | Method::Head | Method::Options);
+                    let anonymous = policy
+                        .is_some_and(|i| (safe && i.read_only) || i.is_public(req.url().path()));
+                    match self.authorization(&req)? {
+                        Some(user) if self.config.authorization.allows(policy, &user) => {
+                            account = Some(user)
                         }
+                        _ if anonymous => (),
+                        Some(_) => return Self::forbidden(),
+                        None => return Self::redirect(&login_url(&req)),
                     }
                 }
             }
         }
+        span.exit();
 
-        match req.header("X-Web-Jingzi") {
-            Some(_) => return Self::http_error("may be circular request"),
-            None => req.insert_header("X-Web-Jingzi", "true"),
+        // sessions from before users were recorded have no user
+        let (Some(traffic), Some(user)) = (&self.traffic, account.filter(|i| !i.is_empty())) else {
+            return next.run(req).await;
         };
+        if let Some(resp) = traffic.exceeded(&self.db, &user)? {
+            return Ok(resp);
+        }
+        let mut resp = next.run(req).await?;
+        traffic.count(&mut resp, &user);
+        Ok(resp)
+    }
 
-        let query: Vec<_> = req
-            .url()
-            .query_pairs()
-            .map(|(q, v)| {
-                let s = self.replace_domain(v, false);
-                format!("{}={}", q, s)
-            })
-            .collect();
-        let query = query.join("&");
-        let scheme = match req.url().domain() {
-            Some(domain) => CONFIG
-                .use_https
-                .as_ref()
-                .and_then(|use_https| {
-                    if use_https.iter().any(|i| i == domain) {
-                        Some("https".to_string())
-                    } else {
-                        None
-                    }
+    async fn detect_loop(&self, mut req: Request, next: Next<'_>) -> http_types::Result<Response> {
+        // a request passing through this instance again is a loop, unless
+        // chaining it is allowed by `max_hops`
+        let hops = req.header("via").map_or(0, |via| {
+            via.iter()
+                .flat_map(|i| i.as_str().split(','))
+                .filter(|i| {
+                    i.split_ascii_whitespace().nth(1) == Some(self.config.via_name.as_str())
                 })
-                .or_else(|| req.header("X-Scheme").map(|i| i.as_str().to_string())),
-            None => return Self::http_error("missing domain in request"),
-        };
-        let path = req.url().path();
-        let path = self.replace_domain(path.into(), false);
-        let url = req.url_mut();
-        if !query.is_empty() {
-            url.set_query(Some(&query));
+                .count()
+        });
+        if hops >= self.config.max_hops {
+            let mut resp = Response::new(StatusCode::LoopDetected);
+            resp.set_content_type(http_types::mime::PLAIN);
+            resp.set_body("may be circular request");
+            return Ok(resp);
         }
-        if let Some(scheme) = scheme {
-            if url.set_scheme(&scheme).is_err() {
-                return Self::http_error("invalid request");
+        req.append_header("via", format!("1.1 {}", self.config.via_name));
+        // meant for the mirror as a forward proxy, not for upstream
+        if self.config.forward_proxy {
+            req.remove_header("proxy-connection");
+            req.remove_header("proxy-authorization");
+        }
+
+        next.run(req).await
+    }
+
+    /// turn the request into the origin's, recording its mirror host and
+    /// path prefix for the later stages
+    async fn rewrite_request(
+        &self,
+        mut req: Request,
+        next: Next<'_>,
+    ) -> http_types::Result<Response> {
+        let host = req.url().host_str().unwrap_or_default().to_string();
+        let service_worker = self.config.service_worker(&host);
+        if service_worker != ServiceWorker::Rewrite && is_service_worker(&req) {
+            info!("service worker blocked on {}", host);
+            let mut resp = Response::new(StatusCode::NotFound);
+            resp.set_content_type(http_types::mime::PLAIN);
+            resp.set_body("service workers are disabled");
+            return Ok(resp);
+        }
+
+        if req.url().path() == "/robots.txt" {
+            let robots = self
+                .robots
+                .iter()
+                .filter(|(domain, _)| host.contains(domain.as_str()))
+                .max_by_key(|(domain, _)| domain.len());
+            if let Some((_, robots)) = robots {
+                let mut resp = Response::new(StatusCode::Ok);
+                resp.set_content_type(http_types::mime::PLAIN);
+                resp.set_body(robots.as_str());
+                return Ok(resp);
             }
         }
-        url.set_path(&path);
-        if let Some(host) = url.host_str() {
-            let host = self.replace_domain(host.into(), false);
-            url.set_host(Some(&host))?;
-            req.insert_header("host", host);
+        if self.config.sitemap(&host) == Sitemap::Empty && is_sitemap(req.url().path()) {
+            let mut resp = Response::new(StatusCode::Ok);
+            resp.set_content_type(http_types::mime::XML);
+            resp.set_body(EMPTY_SITEMAP);
+            return Ok(resp);
+        }
+
+        let span = info_span!("rewrite_request");
+        let entered = span.enter();
+        let mirror_host = req.header("host").map(|i| i.as_str().to_string());
+        let mirror_scheme =
+            forwarded_scheme(&self.config, &req).unwrap_or_else(|| req.url().scheme().to_string());
+        let referer = req
+            .header("referer")
+            .and_then(|i| Url::parse(i.as_str()).ok())
+            .filter(|i| i.host_str() == Some(&host));
+        let forwarded = forwarded_scheme(&self.config, &req);
+        let prefix = match self.upstream_url(req.url_mut(), referer.as_ref(), forwarded) {
+            Ok(prefix) => prefix,
+            Err(e) => return Self::http_error(&e.to_string()),
+        };
+        if let Some(upstream) = req.url().host_str() {
+            let upstream = match req.url().port() {
+                Some(port) => format!("{}:{}", upstream, port),
+                None => upstream.to_string(),
+            };
+            req.insert_header("host", upstream);
         }
         self.restore_header(&mut req);
-        if let Some(content_type) = req.content_type() {
-            match content_type.essence() {
-                "text/html"
-                | "text/plain"
-                | "text/javascript"
-                | "application/json"
-                | "application/manifest+json"
-                | "application/x-www-form-urlencoded" => match req.body_string().await {
-                    Ok(body) => {
-                        let body = self.replace_domain(body.into(), false);
-                        req.set_body(body);
-                    }
-                    Err(_) => error!("can not convert body to utf-8 string"),
-                },
-                _ => (),
+        forwarded_headers(
+            &self.config,
+            &mut req,
+            &mirror_scheme,
+            mirror_host.as_deref(),
+        );
+        limit_accept_encoding(&self.config, &mut req);
+        apply_header_rules(&self.config, &mut req, HeaderTarget::Request, &host);
+        let content_type = req
+            .content_type()
+            .filter(|_| !self.config.passthrough(&host));
+        let rewrite_body = if content_type.as_ref().is_some_and(|i| {
+            i.essence() == "multipart/form-data" && self.config.rewrites(&host, i.essence())
+        }) {
+            // files may be large, so only fields are rewritten, as they are sent
+            multipart::rewrite(&mut req, self.restore_domain.clone());
+            false
+        } else {
+            content_type.is_some_and(|i| self.config.rewrites(&host, i.essence()))
+        };
+        drop(entered);
+        if rewrite_body {
+            match req.body_string().instrument(span.clone()).await {
+                Ok(body) => {
+                    let body = self.replace_domain(body.into(), false);
+                    req.set_body(body);
+                }
+                // cut off by `max_request_body_size`
+                Err(e)
+                    if e.downcast_ref::<io::Error>()
+                        .is_some_and(|i| i.kind() == io::ErrorKind::FileTooLarge) =>
+                {
+                    return Err(e)
+                }
+                Err(_) => error!("can not convert body to utf-8 string"),
             }
         }
+        drop(span);
 
-        let host = match req.host() {
-            Some(host) => host,
-            None => return Self::http_error("invalid request"),
-        };
-        let port = match req.url().port_or_known_default() {
-            Some(port) => port,
-            None => return Self::http_error("invalid request"),
+        if req.host().is_none() || req.url().port_or_known_default().is_none() {
+            return Self::http_error("invalid request");
+        }
+        req.ext_mut().insert(Routing { host, prefix });
+
+        next.run(req).await
+    }
+
+    async fn cached(
+        self: &Arc<Self>,
+        mut req: Request,
+        next: Next<'_>,
+    ) -> http_types::Result<Response> {
+        let Some(Routing { host, prefix }) = req.ext().get::<Routing>().cloned() else {
+            return Self::http_error("request was not rewritten");
         };
-        let stream = Async::<TcpStream>::connect(Self::resolve((host, port)).await?).await?;
+        let mut lookup = self
+            .cache
+            .as_ref()
+            .filter(|_| self.config.caches(&host))
+            .and_then(|cache| cache.lookup(&req, &host, prefix.as_deref()));
+        if let (Some(cache), Some(l)) = (&self.cache, &mut lookup) {
+            match cache.get(l) {
+                Ok(Some(Hit::Fresh(mut resp))) => {
+                    resp.ext_mut().insert(Upstream(req.url().clone()));
+                    return Ok(resp);
+                }
+                Ok(Some(Hit::Stale(mut resp))) => {
+                    resp.ext_mut().insert(Upstream(req.url().clone()));
+                    cache.revalidate(l, &mut req);
+                    let forward = self.clone();
+                    let lookup = lookup.take();
+                    let span = info_span!("revalidate");
+                    EXECUTOR
+                        .spawn(
+                            async move {
+                                if let Err(e) =
+                                    forward.fetch(req, lookup, &host, prefix.as_deref()).await
+                                {
+                                    error!("can not refresh stale response: {}", e);
+                                }
+                            }
+                            .instrument(span),
+                        )
+                        .detach();
+                    return Ok(resp);
+                }
+                Ok(None) => cache.revalidate(l, &mut req),
+                Err(e) => error!("can not read cache: {}", e),
+            }
+        }
+        if let Some(lookup) = lookup {
+            req.ext_mut().insert(lookup);
+        }
 
+        next.run(req).await
+    }
+
+    /// send the request upstream and rewrite the response for the mirror
+    /// `host`, which serves the origin under the path `prefix` if any
+    async fn fetch(
+        &self,
+        mut req: Request,
+        lookup: Option<Lookup>,
+        host: &str,
+        prefix: Option<&str>,
+    ) -> http_types::Result<Response> {
+        #[cfg(feature = "lua")]
+        if let Some(lua) = &self.lua {
+            if let Some(resp) = lua.on_request(&mut req)? {
+                return Ok(resp);
+            }
+        }
+        #[cfg(feature = "wasm")]
+        for plugin in self.plugins.iter().filter(|i| i.applies(host)) {
+            if let Some(status) = plugin.filter(req.url().as_str())? {
+                info!("plugin answered {} for {}", status, req.url());
+                return Ok(Response::new(status));
+            }
+        }
+        let service_worker = self.config.service_worker(host);
+        let worker_script = is_service_worker(&req) && self.config.rewrites_bodies(host);
+        let range = self.config.range.mode(host);
+        if range == RangeMode::Strip {
+            req.remove_header("Range");
+            req.remove_header("If-Range");
+        }
+        let upstream = Upstream(req.url().clone());
+        let policy = &self.config.upstream_policy;
+        let origin = req.url().host_str().unwrap_or_default();
+        if policy.mapped_only && !self.config.is_origin(origin) {
+            info!("upstream {} refused, no mapping has it", origin);
+            return Self::forbidden();
+        }
+        // anyone may ask for any host there, so not for internal addresses
+        let catch_all = self.config.is_catch_all(host);
+        if catch_all {
+            let literal = match req.url().host() {
+                Some(Host::Ipv4(ip)) => Some(IpAddr::V4(ip)),
+                Some(Host::Ipv6(ip)) => Some(IpAddr::V6(ip)),
+                _ => None,
+            };
+            let allowed = |ip| {
+                self.config
+                    .catch_all_allow
+                    .iter()
+                    .any(|net| net.contains(&ip))
+            };
+            if literal.is_some_and(|ip| !allowed(ip)) {
+                info!(
+                    "upstream {} refused, catch-all hosts are no addresses",
+                    origin
+                );
+                return Self::forbidden();
+            }
+        }
+        // checked before the circuit, which may let this request through
+        let unavailable = if self.balancer.is_down(origin) {
+            Some((format!("every backend of {} is down", origin), None))
+        } else {
+            match self.circuits.as_ref().map(|i| i.allow(origin)) {
+                Some(Err(left)) => {
+                    let reason = format!("{} is failing, it is tried again later", origin);
+                    Some((reason, Some(left)))
+                }
+                _ => None,
+            }
+        };
+        if let Some((reason, retry_after)) = unavailable {
+            let stale = match (&self.cache, &lookup) {
+                (Some(cache), Some(lookup)) => cache.stale(lookup)?,
+                _ => None,
+            };
+            return Ok(stale.unwrap_or_else(|| {
+                let mut resp =
+                    self.error_pages
+                        .response(StatusCode::ServiceUnavailable, &reason, req.url());
+                if let Some(left) = retry_after {
+                    resp.insert_header("Retry-After", (left.as_secs() + 1).to_string());
+                }
+                resp
+            }));
+        }
+        let timeouts = self.config.timeouts.get(host);
+        let backend = self.balancer.pick(origin);
+        let circuit = self.circuits.as_ref().map(|i| (i, origin.to_string()));
         let mut resp = match req.url().scheme() {
-            "https" => {
-                let stream = async_native_tls::connect(req.url(), stream).await?;
-                async_h1::connect(stream, req).await?
+            "https" | "http" => {
+                let address = backend.as_ref().map(|i| i.address());
+                let retry = &self.config.retry;
+                let allowed = |ip: &IpAddr| {
+                    policy.allows(ip) && (!catch_all || self.config.catch_all_allows(ip))
+                };
+                let sent = client::send_to(req, allowed, timeouts, retry, address);
+                let sent = sent.await;
+                // refused requests never reached the origin
+                let failed = match &sent {
+                    Ok(resp) => Some(is_unavailable(resp.status())),
+                    Err(e) if e.status() == StatusCode::Forbidden => None,
+                    Err(_) => Some(true),
+                };
+                match (&backend, failed) {
+                    (Some(backend), Some(true)) => backend.failure(),
+                    (Some(backend), Some(false)) => backend.success(),
+                    _ => (),
+                }
+                match (&circuit, failed) {
+                    (Some((circuits, origin)), Some(true)) => circuits.failure(origin),
+                    (Some((circuits, origin)), _) => circuits.success(origin),
+                    _ => (),
+                }
+                match sent {
+                    Ok(resp) => resp,
+                    Err(e) if e.status() == StatusCode::Forbidden => {
+                        info!("{}", e);
+                        return Self::forbidden();
+                    }
+                    Err(mut e) => {
+                        e.set_status(upstream_status(&e));
+                        return Err(e);
+                    }
+                }
             }
-            "http" => async_h1::connect(stream, req).await?,
             s => return Self::http_error(&format!("unsupported scheme: {}", s)),
         };
+        resp.ext_mut().insert(upstream);
+        if let Some(backend) = backend {
+            // counted as active until the response is sent
+            resp.ext_mut().insert(Arc::new(backend));
+        }
+        resp.append_header("via", format!("1.1 {}", self.config.via_name));
 
-        self.replace_header(&mut resp);
-
-        if resp.status() == StatusCode::NotModified {
-            return Ok(resp);
+        self.replace_header(&mut resp, host, prefix);
+        #[cfg(feature = "lua")]
+        if let (Some(lua), Some(Upstream(url))) = (&self.lua, resp.ext().get().cloned()) {
+            lua.on_response_headers(&mut resp, &url)?;
         }
 
-        if let Some(content_type) = resp.content_type() {
-            match content_type.essence() {
-                "text/html"
-                | "text/plain"
-                | "text/javascript"
-                | "application/json"
-                | "application/manifest+json"
-                | "application/x-www-form-urlencoded" => {
-                    Coder::De.code(&mut resp);
-                    match resp.body_string().await {
-                        Ok(body) => {
-                            let body = self.replace_domain(body.into(), true);
-                            resp.set_body(body);
+        if resp.status() == StatusCode::NotModified {
+            if let (Some(cache), Some(lookup)) = (&self.cache, lookup) {
+                match cache.refresh(lookup, &resp) {
+                    Ok(Some(mut cached)) => {
+                        if let Some(upstream) = resp.ext().get::<Upstream>() {
+                            cached.ext_mut().insert(upstream.clone());
                         }
-                        Err(_) => error!("can not convert body to utf-8 string"),
+                        return Ok(cached);
                     }
-                    Coder::En.code(&mut resp);
+                    Ok(None) => (),
+                    Err(e) => error!("can not refresh cached response: {}", e),
                 }
-                _ => (),
+            }
+            return Ok(resp);
+        }
+        if self.config.passthrough(host) {
+            return Ok(resp);
+        }
+        match range {
+            // rewriting it would change the offsets of the part
+            RangeMode::Pass if resp.status() == StatusCode::PartialContent => return Ok(resp),
+            RangeMode::Strip => {
+                resp.remove_header("Accept-Ranges");
+            }
+            _ => (),
+        }
+
+        // any other body is passed through, read from upstream straight to
+        // the client without being buffered
+        let rewrite = resp
+            .content_type()
+            .is_some_and(|content_type| self.config.rewrites(host, content_type.essence()));
+        // event streams never end, and bodies sent in pieces may take long
+        // to, so they are rewritten as they arrive, and not cached
+        let content_type = resp.content_type();
+        let event_stream = content_type
+            .as_ref()
+            .is_some_and(|i| i.essence() == "text/event-stream");
+        let utf8 = content_type
+            .as_ref()
+            .and_then(|i| i.param("charset"))
+            .is_none_or(|i| i.as_str().eq_ignore_ascii_case("utf-8"));
+        let pieces = self.config.stream_rewrite && resp.len().is_none() && utf8;
+        if event_stream || (rewrite && pieces && !worker_script) {
+            if rewrite {
+                Coder::De.code(&mut resp, None);
+                resp.remove_header("Content-Encoding");
+                streaming::rewrite(&mut resp, self.replace_domain.clone());
+            }
+            return Ok(resp);
+        }
+        if rewrite || worker_script {
+            let unregister = service_worker == ServiceWorker::Unregister;
+            self.rewrite_body(&mut resp, host, unregister, prefix)
+                .instrument(info_span!("rewrite_response"))
+                .await?;
+        }
+        if let (Some(cache), Some(lookup)) = (&self.cache, lookup) {
+            if let Err(e) = cache.store(lookup, &mut resp).await {
+                error!("can not store response in cache: {}", e);
             }
         }
         Ok(resp)
     }
 
-    async fn login(&self, mut req: Request, domain: &str) -> http_types::Result<Response> {
-        if let Some(account_list) = &CONFIG.authorization.account {
-            let account: Account = req.body_json().await?;
-            if account_list.contains(&account) {
-                use time::{Duration, OffsetDateTime};
-
-                use uuid::Uuid;
-                let token = Uuid::new_v4().to_string();
+    /// replace domains in the response body, the original body is sent
+    /// as is, still compressed, if no domain occurs in it or it is larger
+    /// than `max_rewrite_body_size`, html pages get a script unregistering
+    /// service workers with `unregister`, root relative urls are put under
+    /// the path `prefix`, and the `inject` snippets of the mirror `host`
+    async fn rewrite_body(
+        &self,
+        resp: &mut Response,
+        host: &str,
+        unregister: bool,
+        prefix: Option<&str>,
+    ) -> http_types::Result<()> {
+        let limit = self.config.max_rewrite_body_size.unwrap_or(u64::MAX);
+        if resp.len().is_some_and(|len| len as u64 > limit) {
+            return self.oversized(resp);
+        }
+        let mut body = resp.take_body();
+        let mut raw = Vec::new();
+        (&mut body)
+            .take(limit.saturating_add(1))
+            .read_to_end(&mut raw)
+            .await?;
+        if raw.len() as u64 > limit {
+            let body = Cursor::new(raw).chain(body);
+            resp.remove_header(CONTENT_LENGTH);
+            resp.set_body(Body::from_reader(BufReader::new(body), None));
+            return self.oversized(resp);
+        }
 
-                let write_txn = self.db.begin_write()?;
-                {
-                    let mut table = write_txn.open_table(TOKENS)?;
-                    table.insert(token.clone(), ())?;
+        resp.set_body(raw.clone());
+        Coder::De.code(resp, None);
+        let mut decoded = Vec::new();
+        let read = resp
+            .take_body()
+            .take(limit.saturating_add(1))
+            .read_to_end(&mut decoded)
+            .await;
+        resp.set_body(raw);
+        if let Err(e) = read {
+            error!("can not decode body: {}", e);
+            return Ok(());
+        }
+        if decoded.len() as u64 > limit {
+            return self.oversized(resp);
+        }
+        let content_type = resp.content_type();
+        let encoding = charset::detect(content_type.as_ref(), &decoded);
+        let body = match encoding.decode_without_bom_handling_and_without_replacement(&decoded) {
+            Some(body) => body,
+            None => {
+                error!("can not decode body as {}", encoding.name());
+                return Ok(());
+            }
+        };
+        let essence = content_type.as_ref().map(|i| i.essence());
+        let unregister = unregister && essence == Some("text/html");
+        let injections: Vec<_> = match essence {
+            Some("text/html") => self
+                .injections
+                .iter()
+                .filter(|(inject, _)| inject.applies(host))
+                .collect(),
+            _ => Vec::new(),
+        };
+        let remove = match essence {
+            Some("text/html") => self.config.remove_elements(host).unwrap_or_default(),
+            _ => &[],
+        };
+        let substitutions: Vec<_> = self
+            .substitutions
+            .iter()
+            .filter(|(substitute, _)| substitute.applies(host))
+            .collect();
+        #[cfg(feature = "lua")]
+        let hooked = self.lua.as_ref().is_some_and(|i| i.has_body_hook());
+        #[cfg(not(feature = "lua"))]
+        let hooked = false;
+        #[cfg(feature = "wasm")]
+        let hooked = hooked || self.plugins.iter().any(|i| i.applies(host) && i.rewrites());
+        if !unregister
+            && !hooked
+            && injections.is_empty()
+            && remove.is_empty()
+            && substitutions.iter().all(|(_, find)| !find.is_match(&body))
+            && prefix.is_none()
+            && !self.replace_domain.is_match(&body)
+        {
+            return Ok(());
+        }
+        let mut body = match essence {
+            Some("text/html") if self.config.html_rewriter => html::rewrite(
+                &body,
+                &self.replace_domain,
+                &self.restore_domain,
+                &self.config.html_url_attributes,
+                self.config.js_rewriter,
+                prefix,
+                remove,
+            )
+            .unwrap_or_else(|e| {
+                error!("can not rewrite html: {}", e);
+                self.replace_domain(body, true)
+            }),
+            Some("text/html") => {
+                let body = self.replace_domain(body, true);
+                let body = html::strip_integrity(&body, &self.restore_domain).unwrap_or_else(|e| {
+                    error!("can not strip integrity of html: {}", e);
+                    body
+                });
+                if remove.is_empty() {
+                    body
+                } else {
+                    html::remove(&body, remove).unwrap_or_else(|e| {
+                        error!("can not remove elements of html: {}", e);
+                        body
+                    })
                 }
-                write_txn.commit()?;
-
-                let mut expires = OffsetDateTime::now_utc();
-                expires += Duration::days(3650);
-                let cookie = Cookie::build(COOKIE_NAME, &token)
-                    .domain(domain)
-                    .expires(expires)
-                    .secure(true)
-                    .http_only(true)
-                    .finish();
-                let cookie: HeaderValue = cookie.into();
-                let mut resp = Self::result(true)?;
-                resp.append_header("Set-Cookie", cookie);
-                Ok(resp)
+            }
+            Some("text/css") => css::rewrite(&body, &self.replace_domain, prefix),
+            Some("application/manifest+json") => manifest::rewrite(&body, &self.replace_domain)
+                .unwrap_or_else(|e| {
+                    error!("can not rewrite manifest: {}", e);
+                    self.replace_domain(body, true)
+                }),
+            Some("application/vnd.apple.mpegurl" | "application/x-mpegurl" | "audio/mpegurl") => {
+                m3u8::rewrite(&body, &self.replace_domain)
+            }
+            Some("application/dash+xml") => xml::rewrite(&body, &self.replace_domain, &xml::DASH),
+            Some(
+                "application/rss+xml" | "application/atom+xml" | "application/xml" | "text/xml",
+            ) => xml::rewrite(&body, &self.replace_domain, &xml::FEED),
+            Some("image/svg+xml") => xml::rewrite(&body, &self.replace_domain, &xml::SVG),
+            Some("text/javascript" | "application/javascript") if self.config.js_rewriter => {
+                js::rewrite(&body, &self.replace_domain)
+            }
+            _ => self.replace_domain(body, true),
+        };
+        for (substitute, find) in substitutions {
+            body = if substitute.regex {
+                find.replace_all(&body, substitute.replace.as_str())
             } else {
-                Self::result(false)
+                find.replace_all(&body, NoExpand(&substitute.replace))
             }
+            .into_owned();
+        }
+        #[cfg(feature = "lua")]
+        if let Some(lua) = &self.lua {
+            let essence = essence.unwrap_or_default();
+            body = lua.on_body_chunk(body, host, essence)?;
+        }
+        #[cfg(feature = "wasm")]
+        for plugin in self.plugins.iter().filter(|i| i.applies(host)) {
+            body = plugin.rewrite(body)?;
+        }
+        if unregister {
+            body = inject(&body, UNREGISTER_SERVICE_WORKERS, InjectPosition::Head);
+        }
+        for (config, snippet) in injections {
+            body = inject(&body, snippet, config.position);
+        }
+        // encodings which can't be written, like utf-16, are sent as utf-8
+        let (body, output, _) = encoding.encode(&body);
+        if output != encoding {
+            if let Some(content_type) = content_type {
+                resp.insert_header(
+                    "Content-Type",
+                    format!("{}; charset=utf-8", content_type.essence()),
+                );
+            }
+        }
+        resp.set_body(body.into_owned());
+        if self.config.recompress {
+            Coder::En.code(resp, self.config.recompression_level);
+            // buffered like the rewritten body, so it is sent with its length
+            // instead of chunked
+            let body = resp.take_body().into_bytes().await?;
+            resp.set_body(body);
         } else {
-            Self::result(false)
+            resp.remove_header("Content-Encoding");
+        }
+        Ok(())
+    }
+
+    /// a body too large to rewrite is passed through as is, or rejected
+    fn oversized(&self, resp: &mut Response) -> http_types::Result<()> {
+        if self.config.oversized_body == Oversized::Reject {
+            let mut rejected = Response::new(StatusCode::BadGateway);
+            rejected.set_content_type(http_types::mime::PLAIN);
+            rejected.set_body("response too large to rewrite");
+            *resp = rejected;
+        }
+        Ok(())
+    }
+
+    async fn login(
+        &self,
+        mut req: Request,
+        domain: &str,
+        policy: Option<&Policy>,
+    ) -> http_types::Result<Response> {
+        let next = login_next(&req);
+        if self.config.authorization.backend == Backend::Oidc {
+            return self.oidc_login(req, domain, policy).await;
+        }
+        if req.method() == Method::Get {
+            return self.show_login_page();
+        }
+        let LoginForm {
+            account,
+            csrf_token,
+        } = req.body_json().await?;
+        if !csrf_check(&req, &csrf_token) {
+            info!("login rejected by csrf check on {}", domain);
+            return Self::forbidden();
+        }
+        let user = match self.check_account(account).await {
+            Ok(user) => user,
+            Err(e) => return Self::http_error(&e.to_string()),
+        };
+        match user {
+            Some(user) if self.config.authorization.allows(policy, &user) => {
+                let mut resp = Self::login_result(true, Some(&next))?;
+                resp.append_header("Set-Cookie", self.session_cookie(domain, &user)?);
+                Ok(resp)
+            }
+            _ => Self::login_result(false, None),
         }
     }
 
-    fn authorization(&self, req: &Request) -> Result<bool> {
-        let cookies_header = match req.header("Cookie") {
-            Some(c) => c,
-            None => return Ok(false),
+    async fn oidc_login(
+        &self,
+        req: Request,
+        domain: &str,
+        policy: Option<&Policy>,
+    ) -> http_types::Result<Response> {
+        let oidc = match &self.config.authorization.oidc {
+            Some(oidc) => oidc,
+            None => return Self::http_error("missing oidc config"),
+        };
+        let scheme =
+            forwarded_scheme(&self.config, &req).unwrap_or_else(|| req.url().scheme().to_string());
+        let redirect_uri = match req.url().host_str() {
+            Some(host) => format!("{}://{}{}", scheme, host, LOGIN_URL_PATH),
+            None => return Self::http_error("missing domain in request"),
         };
 
-        let token = cookies_header.iter().find_map(|cookie| {
-            cookie.as_str().split("; ").find_map(|item| {
-                let values: Vec<_> = item.split('=').collect();
-                if values.len() == 2 && values[0] == COOKIE_NAME {
-                    Some(values[1])
-                } else {
-                    None
-                }
-            })
-        });
+        if !req.url().query_pairs().any(|(k, _)| k == "state") {
+            let next = login_next(&req);
+            let secure = self.config.authorization.cookie.secure;
+            return Ok(self
+                .oidc
+                .authorize(oidc, &redirect_uri, &next, secure)
+                .await?);
+        }
+        match self.oidc.callback(oidc, &req, &redirect_uri).await {
+            Ok((user, next)) if self.config.authorization.allows(policy, &user) => {
+                info!("oidc user logged in: {}", user);
+                let mut resp = Self::redirect(&safe_next(&next))?;
+                resp.append_header("Set-Cookie", self.session_cookie(domain, &user)?);
+                Ok(resp)
+            }
+            Ok((user, _)) => {
+                info!("oidc user not allowed on {}: {}", domain, user);
+                Self::forbidden()
+            }
+            Err(e) => {
+                error!("oidc login failed: {}", e);
+                let mut resp = Response::new(StatusCode::Forbidden);
+                resp.set_content_type(http_types::mime::PLAIN);
+                resp.set_body("sign in failed");
+                Ok(resp)
+            }
+        }
+    }
+
+    /// create a new session token for `user` and the cookie carrying it
+    fn session_cookie(&self, domain: &str, user: &str) -> Result<HeaderValue> {
+        use time::{Duration, OffsetDateTime};
+
+        let token = session::create(&self.db, user)?;
+
+        let config = &self.config.authorization.cookie;
+        let mut expires = OffsetDateTime::now_utc();
+        expires += Duration::days(config.lifetime_days);
+        let mut cookie = Cookie::build(config.name.as_str(), &token)
+            .domain(domain)
+            .expires(expires)
+            .secure(config.secure)
+            .http_only(config.http_only);
+        if let Some(same_site) = config.same_site {
+            cookie = cookie.same_site(match same_site {
+                SameSite::Strict => cookies::SameSite::Strict,
+                SameSite::Lax => cookies::SameSite::Lax,
+                SameSite::None => cookies::SameSite::None,
+            });
+        }
+        Ok(cookie.finish().into())
+    }
 
-        Ok(match token {
-            Some(token) => {
-                let read_txn = self.db.begin_read()?;
-                let table = read_txn.open_table(TOKENS)?;
-                table.get(token.to_string())?.is_some()
+    /// user of the session in the request
+    /// the user of `account`, if the backend accepts its password
+    async fn check_account(&self, account: Account) -> Result<Option<String>> {
+        Ok(match self.config.authorization.backend {
+            Backend::Account => match &self.config.authorization.account {
+                Some(account_list) => account_list.contains(&account).then_some(account.username),
+                None => None,
+            },
+            Backend::Oidc => None,
+            Backend::Ldap => {
+                let Some(ldap) = &self.config.authorization.ldap else {
+                    anyhow::bail!("missing ldap config");
+                };
+                match ldap::bind(ldap, &account.username, &account.password).await {
+                    Ok(true) => Some(account.username),
+                    Ok(false) => None,
+                    Err(e) => {
+                        error!("ldap bind failed: {}", e);
+                        None
+                    }
+                }
             }
-            None => false,
         })
     }
 
+    /// the answer refusing a `CONNECT` tunnel of the client `ip` to `host`,
+    /// checked like requests for its mirror domain, with login the client
+    /// gives an account, or a session token as bearer, in
+    /// `Proxy-Authorization`
+    pub(crate) async fn refuse_tunnel(
+        &self,
+        host: &str,
+        authorization: Option<&str>,
+        ip: IpAddr,
+    ) -> Result<Option<Response>> {
+        let mirror = self.replace_domain.replace(host);
+        let access = self.config.access_control.check(&mirror, &ip);
+        if access == Access::Deny {
+            return Ok(Some(Response::new(StatusCode::Forbidden)));
+        }
+        if let Some(resp) = self.maintenance.response(&mirror) {
+            return Ok(Some(resp));
+        }
+        if let Some(limiter) = &self.rate_limiter {
+            if let Err(wait) = limiter.check(&mirror, &ip.to_string()) {
+                return Ok(Some(too_many_requests(wait)));
+            }
+        }
+        if let Some(methods) = self.config.allowed_methods(&mirror) {
+            if !methods.iter().any(|i| i.eq_ignore_ascii_case("CONNECT")) {
+                return Ok(Some(Response::new(StatusCode::MethodNotAllowed)));
+            }
+        }
+        if !self.config.authorization.enabled || access == Access::Allow {
+            return Ok(None);
+        }
+        let user = match authorization.and_then(|i| i.trim().split_once(' ')) {
+            Some((scheme, token)) if scheme.eq_ignore_ascii_case("bearer") => {
+                session::user(&self.db, token.trim())?
+            }
+            Some((scheme, credentials)) if scheme.eq_ignore_ascii_case("basic") => {
+                match basic_account(credentials.trim()) {
+                    Some(account) => self.check_account(account).await?,
+                    None => None,
+                }
+            }
+            _ => None,
+        };
+        let Some(user) = user else {
+            let mut resp = Response::new(StatusCode::ProxyAuthenticationRequired);
+            resp.insert_header("Proxy-Authenticate", "Basic realm=\"web-jingzi\"");
+            return Ok(Some(resp));
+        };
+        let policy = self.config.authorization.protected_domain(&mirror);
+        if !self
+            .config
+            .authorization
+            .allows(policy.and_then(|(_, i)| i), &user)
+        {
+            info!("tunnel of {} to {} not allowed", user, host);
+            return Ok(Some(Response::new(StatusCode::Forbidden)));
+        }
+        match &self.traffic {
+            Some(traffic) => traffic.exceeded(&self.db, &user),
+            None => Ok(None),
+        }
+    }
+
+    fn authorization(&self, req: &Request) -> Result<Option<String>> {
+        let token = match cookie(req, &self.config.authorization.cookie.name) {
+            Some(token) => token,
+            None => return Ok(None),
+        };
+        session::user(&self.db, token)
+    }
+
+    /// turn the mirror `url` into the upstream one, returning the path prefix
+    /// its origin is served under, if any, which may also be the one of the
+    /// `referer` page, for root relative urls rewriting missed; the scheme
+    /// of the client, `forwarded` by a proxy, is kept unless the origin needs
+    /// another one
+    pub(crate) fn upstream_url(
+        &self,
+        url: &mut Url,
+        referer: Option<&Url>,
+        forwarded: Option<String>,
+    ) -> Result<Option<String>> {
+        let host = url.host_str().unwrap_or_default().to_string();
+        let path = url.path().to_string();
+        let routed = self
+            .config
+            .path_prefix(&host, &path)
+            .map(|(origin, prefix)| (origin, prefix, &path[prefix.len()..]))
+            .or_else(|| {
+                let (origin, prefix) = self.config.path_prefix(&host, referer?.path())?;
+                Some((origin, prefix, path.as_str()))
+            });
+        let prefix = match routed {
+            Some((origin, prefix, path)) => {
+                url.set_host(Some(origin))?;
+                url.set_path(if path.is_empty() { "/" } else { path });
+                Some(prefix.to_string())
+            }
+            None => None,
+        };
+        let query: Vec<_> = url
+            .query_pairs()
+            .map(|(q, v)| {
+                let s = self.replace_domain(v, false);
+                format!("{}={}", q, s)
+            })
+            .collect();
+        let query = query.join("&");
+        let scheme = match url.domain() {
+            Some(domain) if self.config.use_https(domain) => Some("https".to_string()),
+            Some(_) => forwarded,
+            None => anyhow::bail!("missing domain in request"),
+        };
+        let path = self.replace_domain(url.path().into(), false);
+        if !query.is_empty() {
+            url.set_query(Some(&query));
+        }
+        if let Some(scheme) = scheme {
+            if url.set_scheme(&scheme).is_err() {
+                anyhow::bail!("invalid request");
+            }
+        }
+        url.set_path(&path);
+        // the scheme and port of the origin, if its mapping has them
+        if let Some(origin) = self.config.origin(&host).filter(|_| prefix.is_none()) {
+            if let Some(scheme) = origin.scheme {
+                if url.set_scheme(scheme).is_err() || url.set_port(None).is_err() {
+                    anyhow::bail!("invalid request");
+                }
+            }
+            if origin.port.is_some() && url.set_port(origin.port).is_err() {
+                anyhow::bail!("invalid request");
+            }
+        }
+        if let Some(upstream) = url.host_str() {
+            let upstream = self.replace_domain(upstream.into(), false);
+            url.set_host(Some(&upstream))?;
+        }
+        Ok(prefix)
+    }
+
     /// replace or restore domain
     fn replace_domain(&self, text: Cow<str>, is_replace: bool) -> String {
-        let regex_domain = if is_replace {
-            &self.replace_domain
+        if is_replace {
+            self.replace_domain.replace(&text)
         } else {
-            &self.restore_domain
-        };
-        let mut result = text.into_owned();
-        for (regex, rep) in regex_domain {
-            result = regex.replace_all(&result, rep).to_string();
+            self.restore_domain.replace(&text)
         }
-        result
     }
 
-    fn replace_header(&self, req: &mut Response) {
+    /// rewrite the response headers for the mirror `host`, serving the
+    /// origin under the path `prefix` if any
+    fn replace_header(&self, req: &mut Response, host: &str, prefix: Option<&str>) {
         const HEADERS: &[&str] = &[
             "location",
-            "set-cookie",
+            "refresh",
             "access-control-allow-origin",
-            "content-security-policy",
             "x-frame-options",
         ];
 
@@ -274,6 +1508,94 @@ impl Forward {
                 req.insert_header(*i, h);
             }
         }
+        if let Some(location) = req.header("location").filter(|_| prefix.is_some()) {
+            let location = location.as_str();
+            let location =
+                self.replace_domain
+                    .replace_urls(location, iter::once(0..location.len()), prefix);
+            req.insert_header("location", location);
+        }
+
+        for name in &self.config.strip_response_headers {
+            req.remove_header(name.as_str());
+        }
+
+        let mode = self.config.csp.mode(host);
+        for name in [
+            "content-security-policy",
+            "content-security-policy-report-only",
+        ] {
+            if let Some(policies) = req.remove_header(name) {
+                if mode == CspMode::Remove {
+                    continue;
+                }
+                for policy in &policies {
+                    let policy = csp::rewrite(
+                        policy.as_str(),
+                        &self.replace_domain,
+                        mode == CspMode::Relax,
+                        self.config.csp.drop_reports,
+                    );
+                    req.append_header(name, policy);
+                }
+            }
+        }
+
+        if let Some(cookies) = req.remove_header("set-cookie") {
+            for cookie in &cookies {
+                req.append_header(
+                    "set-cookie",
+                    self.replace_cookie_domain(cookie.as_str(), prefix),
+                );
+            }
+        }
+
+        // only the urls of `<url>; rel=preload, <url>; rel=next`
+        if let Some(links) = req.remove_header("link") {
+            for link in &links {
+                let link = link.as_str();
+                let mut urls = Vec::new();
+                let mut i = 0;
+                while let Some(start) = link[i..].find('<').map(|start| i + start + 1) {
+                    let end = match link[start..].find('>') {
+                        Some(end) => start + end,
+                        None => break,
+                    };
+                    urls.push(start..end);
+                    i = end;
+                }
+                req.append_header("link", self.replace_domain.replace_ranges(link, urls));
+            }
+        }
+
+        apply_header_rules(&self.config, req, HeaderTarget::Response, host);
+    }
+
+    /// map the `Domain` attribute of a `Set-Cookie` value, leaving its value
+    /// and other attributes untouched, a domain of no mirror is dropped so the
+    /// cookie is kept for the mirror host instead of being rejected, a `Path`
+    /// is put under the path `prefix`
+    fn replace_cookie_domain(&self, cookie: &str, prefix: Option<&str>) -> String {
+        let mut parts = cookie.split(';');
+        let mut replaced: Vec<_> = parts.next().into_iter().map(String::from).collect();
+        for attribute in parts {
+            let (name, value) = attribute.split_once('=').unwrap_or((attribute, ""));
+            if let Some(prefix) = prefix.filter(|_| name.trim().eq_ignore_ascii_case("path")) {
+                let path = value.trim().trim_end_matches('/');
+                replaced.push(format!("{}={}{}", name, prefix, path));
+                continue;
+            }
+            if !name.trim().eq_ignore_ascii_case("domain") {
+                replaced.push(attribute.to_string());
+                continue;
+            }
+            let domain = value.trim();
+            let mapped = self.replace_domain(domain.trim_start_matches('.').into(), true);
+            if mapped != domain.trim_start_matches('.') {
+                replaced.push(format!("{}={}", name, mapped));
+            }
+        }
+        replaced.join(";")
     }
 
     fn restore_header(&self, req: &mut Request) {
@@ -287,11 +1609,99 @@ impl Forward {
         }
     }
 
-    async fn resolve<T: AsyncToSocketAddrs>(s: T) -> Result<SocketAddr> {
-        Ok(*resolve(s)
-            .await?
-            .first()
-            .ok_or_else(|| anyhow!("invalid address"))?)
+    /// whether the client may ask for the readiness checks, being a trusted
+    /// proxy or allowed by `readiness.allow`
+    fn may_check_ready(&self, req: &Request) -> bool {
+        let Some(ip) = client_ip(&self.config, req) else {
+            return false;
+        };
+        is_trusted_proxy(&self.config, &ip)
+            || self
+                .config
+                .readiness
+                .as_ref()
+                .is_some_and(|i| i.allow.iter().any(|net| net.contains(&ip)))
+    }
+
+    /// the result of the readiness checks, run again once `READY_CACHE` has
+    /// passed
+    pub(crate) async fn readiness(&self) -> (bool, serde_json::Value) {
+        let cached = match self.readiness.lock() {
+            Ok(i) => i.clone(),
+            Err(e) => e.into_inner().clone(),
+        };
+        if let Some((at, ok, checks)) = cached {
+            if at.elapsed() < READY_CACHE {
+                return (ok, checks);
+            }
+        }
+        let (ok, checks) = self.check_ready().await;
+        let result = Some((Instant::now(), ok, checks.clone()));
+        match self.readiness.lock() {
+            Ok(mut i) => *i = result,
+            Err(e) => *e.into_inner() = result,
+        }
+        (ok, checks)
+    }
+
+    /// the database is writable and all configured probes respond
+    async fn check_ready(&self) -> (bool, serde_json::Value) {
+        let mut checks = serde_json::Map::new();
+        let db = self
+            .db
+            .begin_write()
+            .map_err(anyhow::Error::from)
+            .and_then(|txn| Ok(txn.commit()?));
+        if let Err(e) = &db {
+            error!("database not writable: {}", e);
+        }
+        checks.insert("database".to_string(), db.is_ok().into());
+        let mut ready = db.is_ok();
+
+        if let Some(readiness) = &self.config.readiness {
+            let timeout = Duration::from_secs(readiness.timeout_secs);
+            for probe in &readiness.probes {
+                let ok = match Url::parse(probe) {
+                    Ok(url) => {
+                        let send = async {
+                            match client::send(Request::head(url)).await {
+                                Ok(resp) => !resp.status().is_server_error(),
+                                Err(e) => {
+                                    error!("probe {} failed: {}", probe, e);
+                                    false
+                                }
+                            }
+                        };
+                        send.or(async {
+                            Timer::after(timeout).await;
+                            error!("probe {} timed out", probe);
+                            false
+                        })
+                        .await
+                    }
+                    Err(e) => {
+                        error!("invalid probe url {}: {}", probe, e);
+                        false
+                    }
+                };
+                checks.insert(probe.to_string(), ok.into());
+                ready &= ok;
+            }
+        }
+        (ready, checks.into())
+    }
+
+    fn health(ok: bool, checks: serde_json::Value) -> http_types::Result<Response> {
+        let status = if ok {
+            StatusCode::Ok
+        } else {
+            StatusCode::ServiceUnavailable
+        };
+        let mut resp = Response::new(status);
+        resp.set_content_type(http_types::mime::JSON);
+        resp.insert_header("Cache-Control", "no-store");
+        resp.set_body(serde_json::json!({ "ok": ok, "checks": checks }));
+        Ok(resp)
     }
 
     fn http_error(error: &str) -> http_types::Result<Response> {
@@ -301,27 +1711,393 @@ impl Forward {
         Ok(resp)
     }
 
-    fn show_login_page() -> http_types::Result<Response> {
+    fn forbidden() -> http_types::Result<Response> {
+        let mut resp = Response::new(StatusCode::Forbidden);
+        resp.set_content_type(http_types::mime::PLAIN);
+        resp.set_body("forbidden");
+        Ok(resp)
+    }
+
+    fn show_login_page(&self) -> http_types::Result<Response> {
+        let token = uuid::Uuid::new_v4().to_string();
+        let cookie = Cookie::build(CSRF_COOKIE_NAME, token.clone())
+            .path(LOGIN_URL_PATH)
+            .same_site(cookies::SameSite::Strict)
+            .secure(self.config.authorization.cookie.secure)
+            .http_only(true)
+            .finish();
+        let cookie: HeaderValue = cookie.into();
         let mut resp = Response::new(StatusCode::Ok);
         resp.set_content_type(http_types::mime::HTML);
-        resp.set_body(&include_bytes!("login.html")[..]);
+        resp.set_body(include_str!("login.html").replace("{{csrf_token}}", &token));
+        resp.append_header("Set-Cookie", cookie);
         Ok(resp)
     }
 
-    fn result(success: bool) -> http_types::Result<Response> {
+    fn redirect(location: &str) -> http_types::Result<Response> {
+        let mut resp = Response::new(StatusCode::Found);
+        resp.insert_header("Location", location);
+        Ok(resp)
+    }
+
+    fn login_result(success: bool, redirect: Option<&str>) -> http_types::Result<Response> {
         let mut resp = Response::new(StatusCode::Ok);
         resp.set_content_type(http_types::mime::JSON);
-        resp.set_body(format!("{{\"success\": {}}}", success));
+        resp.set_body(serde_json::json!({ "success": success, "redirect": redirect }));
         Ok(resp)
     }
 }
 
+#[derive(Deserialize)]
+struct LoginForm {
+    #[serde(flatten)]
+    account: Account,
+    csrf_token: String,
+}
+
+/// the login form must echo the token set with the login page, and come
+/// from the same host if the browser tells where it comes from
+fn csrf_check(req: &Request, csrf_token: &str) -> bool {
+    match cookie(req, CSRF_COOKIE_NAME) {
+        Some(token) if !token.is_empty() && token == csrf_token => (),
+        _ => return false,
+    }
+    let source = req
+        .header("Origin")
+        .or_else(|| req.header("Referer"))
+        .map(|i| i.as_str());
+    match source {
+        Some(source) => Url::parse(source)
+            .map(|source| source.host_str() == req.url().host_str())
+            .unwrap_or(false),
+        None => true,
+    }
+}
+
+/// login page url remembering the requested path
+fn login_url(req: &Request) -> String {
+    let mut next = req.url().path().to_string();
+    if let Some(query) = req.url().query() {
+        next.push('?');
+        next.push_str(query);
+    }
+    let mut url = req.url().clone();
+    url.set_query(None);
+    url.query_pairs_mut().append_pair("next", &next);
+    format!("{}?{}", LOGIN_URL_PATH, url.query().unwrap_or_default())
+}
+
+/// where to go after login, from the `next` query parameter
+fn login_next(req: &Request) -> String {
+    let next = req
+        .url()
+        .query_pairs()
+        .find(|(k, _)| k == "next")
+        .map(|(_, v)| v.into_owned());
+    safe_next(next.as_deref().unwrap_or("/"))
+}
+
+/// only allow local paths, so the login can't be used as an open redirect,
+/// `next` is resolved as browsers do, which drop tabs and line breaks, and
+/// only its path, query and fragment are kept
+fn safe_next(next: &str) -> String {
+    if !next.starts_with('/') || next.chars().any(|i| i.is_control() || i.is_whitespace()) {
+        return "/".to_string();
+    }
+    let Ok(base) = Url::parse("http://mirror.invalid/") else {
+        return "/".to_string();
+    };
+    match base.join(next) {
+        Ok(url) if url.origin() == base.origin() => {
+            url[http_types::url::Position::BeforePath..].to_string()
+        }
+        _ => "/".to_string(),
+    }
+}
+
+/// a file of `static_files` for the request, 404 if there is none, `None`
+/// if no directory is served under its path
+async fn static_file(config: &Config, req: &Request, host: &str) -> Option<Response> {
+    let path = req.url().path();
+    let files = config
+        .static_files
+        .iter()
+        .filter(|i| i.domain.as_ref().is_none_or(|i| host.contains(i.as_str())))
+        .filter(|i| path.starts_with(&i.prefix))
+        .max_by_key(|i| i.prefix.len())?;
+    let name = percent_decode_str(&path[files.prefix.len()..]).decode_utf8_lossy();
+    let mut name = name.trim_start_matches('/').to_string();
+    if name.is_empty() || name.ends_with('/') {
+        name.push_str("index.html");
+    }
+    // nothing outside the directory
+    let inside = name.split(['/', '\\']).all(|i| i != "..");
+    let body = match req.method() {
+        Method::Get | Method::Head if inside => {
+            Body::from_file(Path::new(&files.dir).join(name)).await.ok()
+        }
+        _ => None,
+    };
+    let mut resp = match body {
+        Some(body) => {
+            let mut resp = Response::new(StatusCode::Ok);
+            resp.set_body(body);
+            resp
+        }
+        None => {
+            let mut resp = Response::new(StatusCode::NotFound);
+            resp.set_content_type(http_types::mime::PLAIN);
+            resp.set_body("not found");
+            resp
+        }
+    };
+    resp.insert_header("X-Content-Type-Options", "nosniff");
+    Some(resp)
+}
+
+/// the origin answering this failed to handle the request
+fn is_unavailable(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::BadGateway | StatusCode::ServiceUnavailable | StatusCode::GatewayTimeout
+    )
+}
+
+/// 504 if the origin timed out, 502 for the other failures to reach it
+fn upstream_status(e: &http_types::Error) -> StatusCode {
+    match e.downcast_ref::<std::io::Error>() {
+        Some(e) if e.kind() == std::io::ErrorKind::TimedOut => StatusCode::GatewayTimeout,
+        _ => StatusCode::BadGateway,
+    }
+}
+
+/// the address the request was received from
+fn peer_ip(req: &Request) -> Option<IpAddr> {
+    req.peer_addr()?
+        .parse::<SocketAddr>()
+        .ok()
+        .map(|addr| addr.ip())
+}
+
+fn is_trusted_proxy(config: &Config, ip: &IpAddr) -> bool {
+    config.trusted_proxies.iter().any(|net| net.contains(ip))
+}
+
+/// the address of the client, told by trusted proxies in `X-Forwarded-For`
+/// or `X-Real-IP`, otherwise the peer's
+pub(crate) fn client_ip(config: &Config, req: &Request) -> Option<IpAddr> {
+    let peer = peer_ip(req)?;
+    if !is_trusted_proxy(config, &peer) {
+        return Some(peer);
+    }
+    let forwarded: Vec<IpAddr> = req
+        .header("x-forwarded-for")
+        .into_iter()
+        .flatten()
+        .flat_map(|i| i.as_str().split(','))
+        .filter_map(|i| i.trim().parse().ok())
+        .collect();
+    // the last address no trusted proxy added, earlier ones may be forged
+    forwarded
+        .iter()
+        .rev()
+        .find(|ip| !is_trusted_proxy(config, ip))
+        .or(forwarded.first())
+        .copied()
+        .or_else(|| req.header("x-real-ip")?.as_str().trim().parse().ok())
+        .or(Some(peer))
+}
+
+/// the scheme the client requested, told by a trusted proxy in `X-Scheme`
+/// or `X-Forwarded-Proto`
+fn forwarded_scheme(config: &Config, req: &Request) -> Option<String> {
+    if !peer_ip(req).is_some_and(|ip| is_trusted_proxy(config, &ip)) {
+        return None;
+    }
+    req.header("X-Scheme")
+        .or_else(|| req.header("X-Forwarded-Proto"))
+        .map(|i| i.as_str().to_string())
+}
+
+/// where `req` is redirected to, if it was sent over plain http while
+/// `https_redirect` is on, or for an alternate of a canonical host
+fn canonical_url(config: &Config, req: &Request) -> Option<Url> {
+    let canonical = config.canonical_hosts.get(req.url().host_str()?);
+    let scheme = forwarded_scheme(config, req).unwrap_or_else(|| req.url().scheme().to_string());
+    let insecure = config.https_redirect && !scheme.eq_ignore_ascii_case("https");
+    if canonical.is_none() && !insecure {
+        return None;
+    }
+    let mut url = req.url().clone();
+    if let Some(host) = canonical {
+        url.set_host(Some(host)).ok()?;
+    }
+    if insecure {
+        url.set_scheme("https").ok()?;
+        // the port of plain http is not the one of https
+        url.set_port(None).ok()?;
+    } else {
+        url.set_scheme(&scheme.to_ascii_lowercase()).ok()?;
+    }
+    Some(url)
+}
+
+/// value of the named cookie in the request's `Cookie` header
+pub(crate) fn cookie<'a>(req: &'a Request, name: &str) -> Option<&'a str> {
+    req.header("Cookie")?.iter().find_map(|cookie| {
+        cookie.as_str().split("; ").find_map(|item| {
+            let values: Vec<_> = item.split('=').collect();
+            if values.len() == 2 && values[0] == name {
+                Some(values[1])
+            } else {
+                None
+            }
+        })
+    })
+}
+
 macro_rules! set_code {
     ($response: ident, $coder: ident) => {{
         let body = $response.take_body();
         $response.remove_header(CONTENT_LENGTH);
         Self::set_body($response, $coder::new(body))
     }};
+    ($response: ident, $coder: ident, $level: expr) => {{
+        let body = $response.take_body();
+        $response.remove_header(CONTENT_LENGTH);
+        Self::set_body($response, $coder::with_quality(body, $level))
+    }};
+}
+
+/// `/sitemap.xml`, and the likes of `/sitemap_index.xml` or `/sitemaps/sitemap-1.xml`
+fn is_sitemap(path: &str) -> bool {
+    let name = path.rsplit('/').next().unwrap_or_default();
+    name.starts_with("sitemap") && name.ends_with(".xml")
+}
+
+/// request of a service worker script, as sent by browsers registering it
+fn is_service_worker(req: &Request) -> bool {
+    req.header("Service-Worker").is_some_and(|i| i == "script")
+}
+
+/// insert `snippet` at the end of the html head, or at the start if it has
+/// none, or at the end of the body, or of the page if it has none
+fn inject(html: &str, snippet: &str, position: InjectPosition) -> String {
+    let lowercase = html.to_ascii_lowercase();
+    let at = match position {
+        InjectPosition::Head => lowercase.find("</head>").unwrap_or(0),
+        InjectPosition::Body => lowercase.rfind("</body>").unwrap_or(html.len()),
+    };
+    let mut injected = String::with_capacity(html.len() + snippet.len());
+    injected.push_str(&html[..at]);
+    injected.push_str(snippet);
+    injected.push_str(&html[at..]);
+    injected
+}
+
+/// the `header_rules` of `target` for the mirror `host`
+fn apply_header_rules(
+    config: &Config,
+    headers: &mut impl AsMut<Headers>,
+    target: HeaderTarget,
+    host: &str,
+) {
+    let headers = headers.as_mut();
+    for rule in &config.header_rules {
+        if !rule.applies(target, host) {
+            continue;
+        }
+        let name = rule.name.as_str();
+        match &rule.action {
+            HeaderAction::Add { value } => headers.append(name, value.as_str()),
+            HeaderAction::Set { value } => {
+                headers.insert(name, value.as_str());
+            }
+            HeaderAction::Remove => {
+                headers.remove(name);
+            }
+            HeaderAction::Replace {
+                pattern,
+                replacement,
+            } => {
+                if let Some(values) = headers.remove(name) {
+                    for value in &values {
+                        let value = pattern.replace_all(value.as_str(), replacement.as_str());
+                        headers.append(name, value.as_ref());
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// append the address the request came from to `X-Forwarded-For` and
+/// `Forwarded`, with the scheme and host of the mirror it requested, or
+/// remove them all
+fn forwarded_headers(config: &Config, req: &mut Request, scheme: &str, host: Option<&str>) {
+    for name in [
+        "x-forwarded-proto",
+        "x-forwarded-host",
+        "x-real-ip",
+        "x-scheme",
+    ] {
+        req.remove_header(name);
+    }
+    if config.forwarded_headers == ForwardedHeaders::Strip {
+        req.remove_header("x-forwarded-for");
+        req.remove_header("forwarded");
+        return;
+    }
+    let client = peer_ip(req);
+    // only a trusted proxy tells which clients came before it
+    if !client.is_some_and(|i| is_trusted_proxy(config, &i)) {
+        req.remove_header("x-forwarded-for");
+        req.remove_header("forwarded");
+    }
+    if let Some(client) = client {
+        let forwarded_for = match req.header("x-forwarded-for") {
+            Some(i) => format!("{}, {}", i.as_str(), client),
+            None => client.to_string(),
+        };
+        req.insert_header("x-forwarded-for", forwarded_for);
+    }
+    let mut forwarded = match client {
+        Some(IpAddr::V6(client)) => format!("for=\"[{}]\"", client),
+        Some(client) => format!("for={}", client),
+        None => "for=unknown".to_string(),
+    };
+    forwarded.push_str(&format!(";proto={}", scheme));
+    req.insert_header("x-forwarded-proto", scheme);
+    if let Some(host) = host {
+        forwarded.push_str(&format!(";host=\"{}\"", host));
+        req.insert_header("x-forwarded-host", host);
+    }
+    req.append_header("forwarded", forwarded);
+}
+
+/// keep the encodings of `upstream_accept_encoding` the client accepts
+fn limit_accept_encoding(config: &Config, req: &mut Request) {
+    let allowed = match &config.upstream_accept_encoding {
+        Some(allowed) => allowed,
+        None => return,
+    };
+    let accepted: Vec<_> = req
+        .header("Accept-Encoding")
+        .map(|i| i.as_str())
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|i| {
+            let coding = i.split(';').next().unwrap_or_default().trim();
+            allowed.iter().any(|a| a.eq_ignore_ascii_case(coding))
+        })
+        .map(String::from)
+        .collect();
+    if accepted.is_empty() {
+        req.insert_header("Accept-Encoding", "identity");
+    } else {
+        req.insert_header("Accept-Encoding", accepted.join(", "));
+    }
 }
 
 enum Coder {
@@ -339,18 +2115,25 @@ impl Coder {
         resp.set_body(body);
     }
 
-    fn code(&self, resp: &mut Response) {
-        use async_compression::futures::bufread::{
-            BrotliDecoder, BrotliEncoder, DeflateDecoder, DeflateEncoder, GzipDecoder, GzipEncoder,
+    /// `level` of compression, the default of the encoding if omitted
+    fn code(&self, resp: &mut Response, level: Option<i32>) {
+        use async_compression::{
+            futures::bufread::{
+                BrotliDecoder, BrotliEncoder, DeflateDecoder, DeflateEncoder, GzipDecoder,
+                GzipEncoder,
+            },
+            Level,
         };
 
+        let level = level.map_or(Level::Default, Level::Precise);
+
         if let Some(encoding) = resp.header("content-encoding") {
             let encoding = encoding.as_str();
             match self {
                 Coder::En => match encoding {
-                    "gzip" => set_code!(resp, GzipEncoder),
-                    "br" => set_code!(resp, BrotliEncoder),
-                    "deflate" => set_code!(resp, DeflateEncoder),
+                    "gzip" => set_code!(resp, GzipEncoder, level),
+                    "br" => set_code!(resp, BrotliEncoder, level),
+                    "deflate" => set_code!(resp, DeflateEncoder, level),
                     e => error!("unhandled encoding: {}", e),
                 },
                 Coder::De => match encoding {
@@ -364,27 +2147,238 @@ impl Coder {
     }
 }
 
-pub fn run() -> Result<()> {
-    let executor = Executor::new();
-    block_on(executor.run(async {
-        CONFIG.check_domain()?;
-        let listen_address: SocketAddr = CONFIG.listen_address.parse()?;
-        let listener = Async::<TcpListener>::bind(listen_address)?;
-        let forward = Arc::new(Forward::new()?);
-        loop {
-            let (stream, _) = listener.accept().await?;
-            let forward = forward.clone();
-            executor
-                .spawn(async move {
-                    if let Err(err) = async_h1::accept(async_dup::Arc::new(stream), |req| async {
-                        forward.forward(req).await
+/// serve `listener` with the mirror, or the admin api
+async fn listen(listener: Async<TcpListener>, current: Current, admin: bool) -> Result<()> {
+    loop {
+        let (stream, peer_addr) = listener.accept().await?;
+        let current = current.clone();
+        // requests are answered with the config of the time they come
+        let forward = current.get();
+        EXECUTOR
+            .spawn(
+                async move {
+                    let _permit = match (&forward.connections, admin) {
+                        (Some(connections), false) => match connections.try_acquire_arc() {
+                            Some(permit) => Some(permit),
+                            None => {
+                                let mut stream = stream;
+                                stream.write_all(OVERLOADED).await.ok();
+                                return;
+                            }
+                        },
+                        _ => None,
+                    };
+                    let mut stream = async_dup::Arc::new(stream);
+                    let mut peer_addr = peer_addr;
+                    let limits = forward.config.request_head.clone();
+                    if forward.config.proxy_protocol && !admin {
+                        let header = within(limits.timeout(), proxy_protocol::read(&mut stream));
+                        match header.await {
+                            Some(Ok(Some(client))) => peer_addr = client,
+                            Some(Ok(None)) => (),
+                            Some(Err(err)) => {
+                                error!("PROXY protocol error from {}: {}", peer_addr, err);
+                                return;
+                            }
+                            None => {
+                                info!("PROXY protocol header from {} timed out", peer_addr);
+                                return;
+                            }
+                        }
+                    }
+                    if forward.config.forward_proxy && !admin {
+                        match within(limits.timeout(), tunnel::is_connect(&stream))
+                            .await
+                            .flatten()
+                        {
+                            Some(true) => {
+                                if let Err(err) = tunnel::serve(&forward, stream, peer_addr).await {
+                                    error!("tunnel error: {}", err);
+                                }
+                                return;
+                            }
+                            Some(false) => (),
+                            None => {
+                                info!("request line from {} timed out", peer_addr);
+                                return;
+                            }
+                        }
+                    }
+                    let slot = trailers::Slot::default();
+                    let stream = TrailerWriter::new(stream, slot.clone());
+                    let stream =
+                        async_dup::Arc::new(async_dup::Mutex::new(HeadGuard::new(stream, limits)));
+                    if let Err(err) = async_h1::accept(stream, |mut req| {
+                        req.set_peer_addr(Some(peer_addr));
+                        let current = current.clone();
+                        let slot = slot.clone();
+                        async move {
+                            let mut resp = current.answer(req, admin).await;
+                            trailers::forward(&mut resp, &slot);
+                            Ok(resp)
+                        }
                     })
                     .await
                     {
                         error!("Connection error: {:#?}", err);
                     }
-                })
-                .detach();
+                }
+                .instrument(info_span!("accept", peer = %peer_addr, admin)),
+            )
+            .detach();
+    }
+}
+
+/// the answer to connections over `max_connections`
+pub(crate) const OVERLOADED: &[u8] = b"HTTP/1.1 503 Service Unavailable\r\n\
+    Retry-After: 1\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
+/// the answer to requests over `max_concurrent_requests`
+/// the account of `Basic` credentials, `username:password` in base64
+fn basic_account(credentials: &str) -> Option<Account> {
+    let credentials = String::from_utf8(base64::decode(credentials).ok()?).ok()?;
+    let (username, password) = credentials.split_once(':')?;
+    Some(Account {
+        username: username.to_string(),
+        password: password.to_string(),
+    })
+}
+
+/// the answer to clients over the rate limit, who may retry after `wait`
+fn too_many_requests(wait: Duration) -> Response {
+    let mut resp = Response::new(StatusCode::TooManyRequests);
+    resp.insert_header("Retry-After", (wait.as_secs() + 1).to_string());
+    resp.insert_header("Cache-Control", "no-store");
+    resp.set_content_type(http_types::mime::PLAIN);
+    resp.set_body("too many requests");
+    resp
+}
+
+fn overloaded() -> Response {
+    let mut resp = Response::new(StatusCode::ServiceUnavailable);
+    resp.insert_header("Retry-After", "1");
+    resp.insert_header("Cache-Control", "no-store");
+    resp.set_content_type(http_types::mime::PLAIN);
+    resp.set_body("too many requests in progress");
+    resp
+}
+
+/// the answer to requests with bodies over `max_request_body_size`
+pub(crate) fn payload_too_large() -> Response {
+    let mut resp = Response::new(StatusCode::PayloadTooLarge);
+    resp.insert_header("Cache-Control", "no-store");
+    resp.set_content_type(http_types::mime::PLAIN);
+    resp.set_body("request body too large");
+    resp
+}
+
+/// replace the body of `req`, of unknown length, with one failing once it
+/// reads over `limit` bytes, which sets the flag returned
+fn limit_body(req: &mut Request, limit: u64) -> Arc<AtomicBool> {
+    let cut_off = Arc::new(AtomicBool::new(false));
+    let body = req.take_body();
+    let mime = body.mime().clone();
+    let reader = LimitedBody {
+        inner: body,
+        left: limit,
+        cut_off: cut_off.clone(),
+    };
+    let mut body = Body::from_reader(BufReader::new(reader), None);
+    body.set_mime(mime);
+    req.swap_body(&mut body);
+    cut_off
+}
+
+struct LimitedBody<R> {
+    inner: R,
+    left: u64,
+    cut_off: Arc<AtomicBool>,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for LimitedBody<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        // a byte more than left tells whether the body goes over
+        let max = buf
+            .len()
+            .min(self.left.saturating_add(1).try_into().unwrap_or(usize::MAX));
+        let n = ready!(Pin::new(&mut self.inner).poll_read(cx, &mut buf[..max]))?;
+        if n as u64 > self.left {
+            self.cut_off.store(true, Ordering::Relaxed);
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::FileTooLarge,
+                "request body too large",
+            )));
         }
-    }))
+        self.left -= n as u64;
+        Poll::Ready(Ok(n))
+    }
+}
+
+/// serve the config of `CONFIG_FILE`
+pub fn run() -> Result<()> {
+    let config = Config::from_env()?;
+    #[cfg(feature = "otlp")]
+    if let Some(config) = &config.otlp {
+        otlp::init(config)?;
+    }
+    let proxy = Proxy::new(config)?;
+    let listener = TcpListener::bind(proxy.config().listen_address.as_str())?;
+    block_on(proxy.serve(listener))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn safe_next_keeps_local_paths() {
+        assert_eq!(safe_next("/"), "/");
+        assert_eq!(safe_next("/a/b?c=d#e"), "/a/b?c=d#e");
+        assert_eq!(safe_next("/a/../b"), "/b");
+    }
+
+    #[test]
+    fn safe_next_refuses_other_origins() {
+        for next in [
+            "",
+            "a",
+            "//evil.com",
+            "/\\evil.com",
+            "/\t/evil.com",
+            "/\r\n/evil.com",
+            "/ /evil.com",
+            "https://evil.com/",
+            "javascript:alert(1)",
+        ] {
+            assert_eq!(safe_next(next), "/", "{:?}", next);
+        }
+    }
+
+    #[test]
+    fn proxy_answers_without_a_listener() {
+        let dir = std::env::temp_dir().join(format!("web-jingzi-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = Config::from_toml(&format!(
+            r#"
+            listen_address = "127.0.0.1:3003"
+            data_dir = {:?}
+            [domain_name]
+            "x.com" = "www.google.com"
+            [authorization]
+            enabled = false
+            "#,
+            dir.to_str().unwrap()
+        ))
+        .unwrap();
+        let proxy = Proxy::new(config).unwrap();
+        let req = Request::get(Url::parse("http://x.com/__wj__health").unwrap());
+        let resp = block_on(proxy.handle(req));
+        std::fs::remove_dir_all(&dir).ok();
+        assert_eq!(resp.status(), StatusCode::Ok);
+        assert_eq!(proxy.config().domain_name["x.com"], "www.google.com");
+    }
 }
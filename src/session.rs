@@ -0,0 +1,85 @@
+use anyhow::Result;
+use redb::{Database, ReadableTable, TableDefinition, TableError};
+use uuid::Uuid;
+
+/// tokens issued before sessions recorded their user
+const TOKENS: TableDefinition<String, ()> = TableDefinition::new("tokens");
+const SESSIONS: TableDefinition<String, String> = TableDefinition::new("sessions");
+
+/// create a session for `user`, returning its token
+pub fn create(db: &Database, user: &str) -> Result<String> {
+    let token = Uuid::new_v4().to_string();
+    let write_txn = db.begin_write()?;
+    {
+        let mut table = write_txn.open_table(SESSIONS)?;
+        table.insert(token.clone(), user.to_string())?;
+    }
+    write_txn.commit()?;
+    Ok(token)
+}
+
+/// user of the session, old tokens are still valid but have an empty user
+pub fn user(db: &Database, token: &str) -> Result<Option<String>> {
+    let read_txn = db.begin_read()?;
+    match read_txn.open_table(SESSIONS) {
+        Ok(table) => {
+            if let Some(user) = table.get(token.to_string())? {
+                return Ok(Some(user.value()));
+            }
+        }
+        Err(TableError::TableDoesNotExist(_)) => (),
+        Err(e) => return Err(e.into()),
+    }
+    match read_txn.open_table(TOKENS) {
+        Ok(table) => Ok(table.get(token.to_string())?.map(|_| String::new())),
+        Err(TableError::TableDoesNotExist(_)) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// all sessions as (token, user)
+pub fn list(db: &Database) -> Result<Vec<(String, String)>> {
+    let read_txn = db.begin_read()?;
+    let mut sessions = Vec::new();
+    match read_txn.open_table(SESSIONS) {
+        Ok(table) => {
+            for i in table.iter()? {
+                let (token, user) = i?;
+                sessions.push((token.value(), user.value()));
+            }
+        }
+        Err(TableError::TableDoesNotExist(_)) => (),
+        Err(e) => return Err(e.into()),
+    }
+    match read_txn.open_table(TOKENS) {
+        Ok(table) => {
+            for i in table.iter()? {
+                let (token, _) = i?;
+                sessions.push((token.value(), String::new()));
+            }
+        }
+        Err(TableError::TableDoesNotExist(_)) => (),
+        Err(e) => return Err(e.into()),
+    }
+    Ok(sessions)
+}
+
+/// remove sessions whose token or user matches, returning how many were removed
+pub fn remove(db: &Database, token: Option<&str>, user: Option<&str>) -> Result<usize> {
+    let remove: Vec<_> = list(db)?
+        .into_iter()
+        .filter(|(t, u)| Some(t.as_str()) == token || Some(u.as_str()) == user)
+        .map(|(t, _)| t)
+        .collect();
+    let write_txn = db.begin_write()?;
+    {
+        let mut sessions = write_txn.open_table(SESSIONS)?;
+        let mut tokens = write_txn.open_table(TOKENS)?;
+        for token in &remove {
+            sessions.remove(token.clone())?;
+            tokens.remove(token.clone())?;
+        }
+    }
+    write_txn.commit()?;
+    Ok(remove.len())
+}
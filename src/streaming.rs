@@ -0,0 +1,100 @@
+use std::{
+    io,
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+};
+
+use futures_lite::io::{AsyncRead, BufReader};
+use http_types::{headers::CONTENT_LENGTH, Body, Response};
+
+use crate::replace::Replacer;
+
+/// text received after the last boundary is held back for at most this many
+/// bytes, in case a domain is split across chunks
+const MAX_CARRY: usize = 4096;
+
+/// replace domains in the body of `resp` as it is received, in pieces
+/// ending where no domain may continue, rather than once it is read whole
+pub fn rewrite(resp: &mut Response, replacer: Arc<Replacer>) {
+    let body = resp.take_body();
+    let mime = body.mime().clone();
+    let reader = Rewritten {
+        inner: body,
+        replacer,
+        carry: Vec::new(),
+        out: Vec::new(),
+        sent: 0,
+        eof: false,
+    };
+    let mut body = Body::from_reader(BufReader::new(reader), None);
+    body.set_mime(mime);
+    resp.remove_header(CONTENT_LENGTH);
+    resp.swap_body(&mut body);
+}
+
+/// bytes no domain, url or escaped form of them contains
+fn is_boundary(byte: u8) -> bool {
+    byte.is_ascii_whitespace() || b"\"'`<>()[]{},;".contains(&byte)
+}
+
+struct Rewritten<R> {
+    inner: R,
+    replacer: Arc<Replacer>,
+    /// received but not yet rewritten
+    carry: Vec<u8>,
+    /// rewritten, and the bytes of it sent
+    out: Vec<u8>,
+    sent: usize,
+    eof: bool,
+}
+
+impl<R> Rewritten<R> {
+    /// rewrite the received bytes up to `end`, text which isn't utf-8 is
+    /// left as is
+    fn rewrite(&mut self, end: usize) {
+        let piece: Vec<u8> = self.carry.drain(..end).collect();
+        self.out = match std::str::from_utf8(&piece) {
+            Ok(text) => self.replacer.replace(text).into_bytes(),
+            Err(_) => piece,
+        };
+        self.sent = 0;
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Rewritten<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        loop {
+            if this.sent < this.out.len() {
+                let n = buf.len().min(this.out.len() - this.sent);
+                buf[..n].copy_from_slice(&this.out[this.sent..this.sent + n]);
+                this.sent += n;
+                return Poll::Ready(Ok(n));
+            }
+            if this.eof {
+                if this.carry.is_empty() {
+                    return Poll::Ready(Ok(0));
+                }
+                this.rewrite(this.carry.len());
+                continue;
+            }
+            let mut chunk = [0; 8192];
+            let n = ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk))?;
+            if n == 0 {
+                this.eof = true;
+                continue;
+            }
+            this.carry.extend_from_slice(&chunk[..n]);
+            match this.carry.iter().rposition(|i| is_boundary(*i)) {
+                Some(end) => this.rewrite(end + 1),
+                None if this.carry.len() > MAX_CARRY => this.rewrite(this.carry.len()),
+                None => (),
+            }
+        }
+    }
+}
@@ -0,0 +1,174 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    net::IpAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use async_io::Timer;
+use futures_lite::io::{AsyncRead, BufReader};
+use http_types::{Body, Response};
+
+use crate::config::Bandwidth;
+
+/// buckets kept before those of finished responses are dropped
+const MAX_BUCKETS: usize = 10_000;
+
+type Buckets<K> = Mutex<HashMap<K, Arc<Mutex<Bucket>>>>;
+
+/// byte rates of responses, shared by those of a client or a mirror domain
+#[derive(Debug)]
+pub struct Throttle {
+    config: Bandwidth,
+    domains: Buckets<String>,
+    clients: Buckets<(String, IpAddr)>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// bytes per second, and at most as many sent at once
+    rate: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(rate: u64) -> Bucket {
+        Bucket {
+            rate: rate as f64,
+            tokens: rate as f64,
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.updated = now;
+    }
+}
+
+impl Throttle {
+    pub fn new(config: Bandwidth) -> Throttle {
+        Throttle {
+            config,
+            domains: Mutex::new(HashMap::new()),
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// limit the body of `resp` for the mirror `host` to the rates of it and
+    /// `client`, if any
+    pub fn apply(&self, resp: &mut Response, host: &str, client: Option<IpAddr>) {
+        let rates = self.config.get(host);
+        let mut buckets = Vec::new();
+        if let Some(rate) = rates.per_domain.filter(|i| *i > 0) {
+            buckets.push(bucket(&self.domains, host.to_string(), rate));
+        }
+        if let (Some(rate), Some(client)) = (rates.per_client.filter(|i| *i > 0), client) {
+            buckets.push(bucket(&self.clients, (host.to_string(), client), rate));
+        }
+        if buckets.is_empty() {
+            return;
+        }
+        let body = resp.take_body();
+        let len = body.len();
+        let mime = body.mime().clone();
+        let reader = Throttled {
+            inner: body,
+            buckets,
+            timer: None,
+        };
+        let mut body = Body::from_reader(BufReader::new(reader), len);
+        body.set_mime(mime);
+        resp.swap_body(&mut body);
+    }
+}
+
+/// the bucket of `key`, shared while responses use it
+fn bucket<K: std::hash::Hash + Eq>(buckets: &Buckets<K>, key: K, rate: u64) -> Arc<Mutex<Bucket>> {
+    let mut buckets = match buckets.lock() {
+        Ok(buckets) => buckets,
+        Err(e) => e.into_inner(),
+    };
+    if buckets.len() >= MAX_BUCKETS {
+        buckets.retain(|_, i| Arc::strong_count(i) > 1);
+    }
+    buckets
+        .entry(key)
+        .or_insert_with(|| Arc::new(Mutex::new(Bucket::new(rate))))
+        .clone()
+}
+
+/// a body read no faster than its buckets allow
+struct Throttled<R> {
+    inner: R,
+    buckets: Vec<Arc<Mutex<Bucket>>>,
+    timer: Option<Timer>,
+}
+
+impl<R> Throttled<R> {
+    /// bytes which may be read now, or how long until any may
+    fn allowed(&self, max: usize) -> Result<usize, Duration> {
+        let now = Instant::now();
+        let mut allowed = max;
+        let mut wait = Duration::ZERO;
+        for bucket in &self.buckets {
+            let mut bucket = match bucket.lock() {
+                Ok(bucket) => bucket,
+                Err(e) => e.into_inner(),
+            };
+            bucket.refill(now);
+            if bucket.tokens < 1.0 {
+                wait = wait.max(Duration::from_secs_f64((1.0 - bucket.tokens) / bucket.rate));
+            } else {
+                allowed = allowed.min(bucket.tokens as usize);
+            }
+        }
+        match wait.is_zero() {
+            true => Ok(allowed),
+            false => Err(wait),
+        }
+    }
+
+    fn take(&self, bytes: usize) {
+        for bucket in &self.buckets {
+            let mut bucket = match bucket.lock() {
+                Ok(bucket) => bucket,
+                Err(e) => e.into_inner(),
+            };
+            bucket.tokens -= bytes as f64;
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Throttled<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            if let Some(timer) = &mut self.timer {
+                if Pin::new(timer).poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+                self.timer = None;
+            }
+            match self.allowed(buf.len()) {
+                Ok(allowed) => {
+                    let read = Pin::new(&mut self.inner).poll_read(cx, &mut buf[..allowed]);
+                    if let Poll::Ready(Ok(n)) = read {
+                        self.take(n);
+                    }
+                    return read;
+                }
+                Err(wait) => self.timer = Some(Timer::after(wait)),
+            }
+        }
+    }
+}
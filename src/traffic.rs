@@ -0,0 +1,235 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use futures_lite::io::{AsyncRead, BufReader};
+use http_types::{Body, Response, StatusCode};
+use redb::{Database, ReadableTable, TableDefinition, TableError};
+use serde_json::{json, Value};
+use time::OffsetDateTime;
+use tracing::{error, info};
+
+use crate::{config::Quota, error_page};
+
+/// bytes sent to users, by `{period}\t{user}`, periods are like
+/// `day:2024-01-31` and `month:2024-01`
+const TRAFFIC: TableDefinition<String, u64> = TableDefinition::new("traffic");
+
+/// counted bytes are written at most this often
+const FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+type Pending = Arc<Mutex<HashMap<String, u64>>>;
+
+/// bytes of the responses sent to each user, and their quotas
+#[derive(Debug)]
+pub struct Traffic {
+    daily_bytes: Option<u64>,
+    monthly_bytes: Option<u64>,
+    page: Option<String>,
+    /// counted but not yet written
+    pending: Pending,
+    flushed: Mutex<Instant>,
+}
+
+impl Traffic {
+    pub fn new(config: &Quota) -> Result<Traffic> {
+        let page = match &config.page {
+            Some(file) => Some(std::fs::read_to_string(file)?),
+            None => None,
+        };
+        Ok(Traffic {
+            daily_bytes: config.daily_bytes,
+            monthly_bytes: config.monthly_bytes,
+            page,
+            pending: Arc::new(Mutex::new(HashMap::new())),
+            flushed: Mutex::new(Instant::now()),
+        })
+    }
+
+    /// the quotas of `config`, with the bytes counted here
+    pub fn reload(&self, config: &Quota) -> Result<Traffic> {
+        Ok(Traffic {
+            pending: self.pending.clone(),
+            ..Traffic::new(config)?
+        })
+    }
+
+    /// the response for `user` if a quota of theirs is used up
+    pub fn exceeded(&self, db: &Database, user: &str) -> Result<Option<Response>> {
+        self.flush_if_due(db);
+        let (day, month) = periods();
+        let read_txn = db.begin_read()?;
+        let table = match read_txn.open_table(TRAFFIC) {
+            Ok(table) => Some(table),
+            Err(TableError::TableDoesNotExist(_)) => None,
+            Err(e) => return Err(e.into()),
+        };
+        let pending = lock(&self.pending).get(user).copied().unwrap_or(0);
+        for (period, quota, name) in [
+            (day, self.daily_bytes, "daily"),
+            (month, self.monthly_bytes, "monthly"),
+        ] {
+            let Some(quota) = quota else {
+                continue;
+            };
+            let used = match &table {
+                Some(table) => table
+                    .get(format!("{}\t{}", period, user))?
+                    .map_or(0, |i| i.value()),
+                None => 0,
+            };
+            if used + pending >= quota {
+                info!("{} quota of {} exceeded", name, user);
+                return Ok(Some(self.response(user, name)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// count the bytes of the body of `resp` sent to `user`
+    pub fn count(&self, resp: &mut Response, user: &str) {
+        let body = resp.take_body();
+        let len = body.len();
+        let mime = body.mime().clone();
+        let reader = Counted {
+            inner: body,
+            bytes: 0,
+            user: user.to_string(),
+            pending: self.pending.clone(),
+        };
+        let mut body = Body::from_reader(BufReader::new(reader), len);
+        body.set_mime(mime);
+        resp.swap_body(&mut body);
+    }
+
+    /// bytes sent to each user by period, the pending ones included
+    pub fn to_json(&self, db: &Database) -> Result<Value> {
+        self.flush(db)?;
+        let mut periods: BTreeMap<String, BTreeMap<String, u64>> = BTreeMap::new();
+        let read_txn = db.begin_read()?;
+        let table = match read_txn.open_table(TRAFFIC) {
+            Ok(table) => table,
+            Err(TableError::TableDoesNotExist(_)) => return Ok(json!({})),
+            Err(e) => return Err(e.into()),
+        };
+        for i in table.iter()? {
+            let (key, bytes) = i?;
+            if let Some((period, user)) = key.value().split_once('\t') {
+                periods
+                    .entry(period.to_string())
+                    .or_default()
+                    .insert(user.to_string(), bytes.value());
+            }
+        }
+        Ok(json!(periods))
+    }
+
+    fn response(&self, user: &str, period: &str) -> Response {
+        let mut resp = Response::new(StatusCode::TooManyRequests);
+        resp.insert_header("Cache-Control", "no-store");
+        match &self.page {
+            Some(template) => {
+                resp.set_content_type(http_types::mime::HTML);
+                resp.set_body(error_page::render(
+                    template,
+                    &[("user", user), ("period", period)],
+                ));
+            }
+            None => {
+                resp.set_content_type(http_types::mime::PLAIN);
+                resp.set_body(format!("{} traffic quota exceeded", period));
+            }
+        }
+        resp
+    }
+
+    fn flush_if_due(&self, db: &Database) {
+        {
+            let mut flushed = lock(&self.flushed);
+            if flushed.elapsed() < FLUSH_INTERVAL {
+                return;
+            }
+            *flushed = Instant::now();
+        }
+        if let Err(e) = self.flush(db) {
+            error!("can not record traffic: {}", e);
+        }
+    }
+
+    /// add the pending bytes to those of the current day and month
+    fn flush(&self, db: &Database) -> Result<()> {
+        let pending = std::mem::take(&mut *lock(&self.pending));
+        if pending.is_empty() {
+            return Ok(());
+        }
+        let (day, month) = periods();
+        let write_txn = db.begin_write()?;
+        {
+            let mut table = write_txn.open_table(TRAFFIC)?;
+            for (user, bytes) in pending {
+                for period in [&day, &month] {
+                    let key = format!("{}\t{}", period, user);
+                    let used = table.get(key.clone())?.map_or(0, |i| i.value());
+                    table.insert(key, used + bytes)?;
+                }
+            }
+        }
+        write_txn.commit()?;
+        Ok(())
+    }
+}
+
+/// the current day and month
+fn periods() -> (String, String) {
+    let now = OffsetDateTime::now_utc();
+    (
+        format!("day:{}", now.format("%Y-%m-%d")),
+        format!("month:{}", now.format("%Y-%m")),
+    )
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(e) => e.into_inner(),
+    }
+}
+
+/// a body whose bytes read are added to those pending of `user` once it
+/// is dropped
+struct Counted<R> {
+    inner: R,
+    bytes: u64,
+    user: String,
+    pending: Pending,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Counted<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let read = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(n)) = read {
+            self.bytes += n as u64;
+        }
+        read
+    }
+}
+
+impl<R> Drop for Counted<R> {
+    fn drop(&mut self) {
+        if self.bytes > 0 {
+            *lock(&self.pending)
+                .entry(std::mem::take(&mut self.user))
+                .or_default() += self.bytes;
+        }
+    }
+}
@@ -0,0 +1,149 @@
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{ready, Context, Poll},
+};
+
+use futures_lite::io::{AsyncRead, AsyncWrite, BufReader};
+use http_types::{
+    trailers::{Receiver, Trailers},
+    Body, Response,
+};
+
+/// the last chunk of a chunked body, as async-h1 writes it on its own
+const LAST_CHUNK: &[u8] = b"0\r\n\r\n";
+
+/// trailers of the response being sent on a connection, once its body is
+/// read, async-h1 writing none itself
+pub type Slot = Arc<Mutex<Option<Trailers>>>;
+
+/// send the trailers received after the body of `resp`, if it is sent in
+/// chunks, on the connection writing with `slot`
+pub fn forward(resp: &mut Response, slot: &Slot) {
+    if !resp.has_trailers() || resp.len().is_some() {
+        return;
+    }
+    let receiver = resp.recv_trailers();
+    let body = resp.take_body();
+    let mime = body.mime().clone();
+    let reader = Trailing {
+        inner: body,
+        receiver: Some(receiver),
+        slot: slot.clone(),
+    };
+    let mut body = Body::from_reader(BufReader::new(reader), None);
+    body.set_mime(mime);
+    resp.swap_body(&mut body);
+}
+
+/// a body putting the trailers following it in a slot once it is read
+struct Trailing<R> {
+    inner: R,
+    receiver: Option<Receiver>,
+    slot: Slot,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Trailing<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let n = ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        if n > 0 || buf.is_empty() {
+            return Poll::Ready(Ok(n));
+        }
+        if let Some(receiver) = &mut self.receiver {
+            let trailers = ready!(Pin::new(receiver).poll(cx));
+            self.receiver = None;
+            if let Some(trailers) = trailers.filter(|i| i.iter().next().is_some()) {
+                *lock(&self.slot) = Some(trailers);
+            }
+        }
+        Poll::Ready(Ok(0))
+    }
+}
+
+/// a connection writing the trailers in its slot into the last chunk of a
+/// body, which is written once the body is read
+pub struct TrailerWriter<S> {
+    inner: S,
+    slot: Slot,
+    /// the last chunk with trailers, and the bytes of it written
+    pending: Vec<u8>,
+    written: usize,
+}
+
+impl<S> TrailerWriter<S> {
+    pub fn new(inner: S, slot: Slot) -> TrailerWriter<S> {
+        TrailerWriter {
+            inner,
+            slot,
+            pending: Vec::new(),
+            written: 0,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for TrailerWriter<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for TrailerWriter<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if this.pending.is_empty() && buf == LAST_CHUNK {
+            if let Some(trailers) = lock(&this.slot).take() {
+                this.pending = b"0\r\n".to_vec();
+                for (name, values) in trailers.iter() {
+                    for value in values {
+                        this.pending
+                            .extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+                    }
+                }
+                this.pending.extend_from_slice(b"\r\n");
+                this.written = 0;
+            }
+        }
+        if this.pending.is_empty() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+        while this.written < this.pending.len() {
+            let n =
+                ready!(Pin::new(&mut this.inner).poll_write(cx, &this.pending[this.written..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            this.written += n;
+        }
+        this.pending.clear();
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+fn lock(slot: &Slot) -> std::sync::MutexGuard<'_, Option<Trailers>> {
+    match slot.lock() {
+        Ok(guard) => guard,
+        Err(e) => e.into_inner(),
+    }
+}
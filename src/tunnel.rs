@@ -0,0 +1,144 @@
+use std::{
+    net::{Shutdown, SocketAddr, TcpStream},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use async_io::{Async, Timer};
+use futures_lite::{
+    future,
+    io::{self, AsyncReadExt, AsyncWriteExt},
+};
+use http_types::Response;
+use tracing::info;
+
+use crate::{head_guard::within, server::Forward};
+
+/// request heads of `CONNECT` longer than this are refused
+const MAX_HEAD_SIZE: usize = 8192;
+/// how long the first bytes of a request are waited for, whatever the
+/// limits of request heads
+const MAX_METHOD_WAIT: Duration = Duration::from_secs(10);
+
+type Stream = async_dup::Arc<Async<TcpStream>>;
+
+/// whether the connection starts with a `CONNECT` request, without reading
+/// it, `None` if the method doesn't arrive within `MAX_METHOD_WAIT`
+pub async fn is_connect(stream: &Stream) -> Option<bool> {
+    within(Some(MAX_METHOD_WAIT), starts_with_connect(stream)).await
+}
+
+async fn starts_with_connect(stream: &Stream) -> bool {
+    let mut method = [0; 8];
+    let mut peeked = 0;
+    // the request line may arrive in pieces
+    while peeked < method.len() {
+        match stream.peek(&mut method).await {
+            Ok(0) | Err(_) => return false,
+            Ok(n) if n == peeked => {
+                Timer::after(Duration::from_millis(10)).await;
+            }
+            Ok(n) => peeked = n,
+        }
+        if !b"CONNECT ".starts_with(&method[..peeked]) {
+            return false;
+        }
+    }
+    true
+}
+
+/// serve a `CONNECT` request of a forward proxy client, tunneling its
+/// connection to the target the upstream policy allows, its bytes, often
+/// tls, are passed through as is
+pub async fn serve(forward: &Forward, mut stream: Stream, peer_addr: SocketAddr) -> Result<()> {
+    let config = &forward.config;
+    let head = read_head(&mut stream).await?;
+    let target = head
+        .split_ascii_whitespace()
+        .nth(1)
+        .ok_or_else(|| anyhow!("invalid CONNECT request"))?;
+    let (host, port) = target
+        .rsplit_once(':')
+        .and_then(|(host, port)| Some((host, port.parse::<u16>().ok()?)))
+        .ok_or_else(|| anyhow!("invalid CONNECT target: {}", target))?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+
+    let policy = &config.upstream_policy;
+    if policy.mapped_only && !config.is_origin(host) {
+        info!("tunnel to {} refused", target);
+        return reply(&mut stream, "403 Forbidden").await;
+    }
+    let authorization = header(&head, "proxy-authorization");
+    if let Some(resp) = forward
+        .refuse_tunnel(host, authorization, peer_addr.ip())
+        .await?
+    {
+        info!("tunnel to {} refused with {}", target, resp.status());
+        return refuse(&mut stream, resp).await;
+    }
+    let upstream = match crate::client::connect(host, port, |ip| policy.allows(ip)).await {
+        Ok(upstream) => async_dup::Arc::new(upstream),
+        Err(e) => {
+            info!("tunnel to {} failed: {}", target, e);
+            let status = match e.status() {
+                http_types::StatusCode::Forbidden => "403 Forbidden",
+                _ => "502 Bad Gateway",
+            };
+            return reply(&mut stream, status).await;
+        }
+    };
+    reply(&mut stream, "200 Connection Established").await?;
+
+    let pipe = |mut from: Stream, mut to: Stream| async move {
+        io::copy(&mut from, &mut to).await?;
+        to.get_ref().shutdown(Shutdown::Write)
+    };
+    future::try_zip(
+        pipe(stream.clone(), upstream.clone()),
+        pipe(upstream, stream),
+    )
+    .await?;
+    Ok(())
+}
+
+/// the request line and headers, up to the empty line ending them
+async fn read_head(stream: &mut Stream) -> Result<String> {
+    let mut head = Vec::new();
+    let mut byte = [0];
+    while !head.ends_with(b"\r\n\r\n") {
+        anyhow::ensure!(head.len() < MAX_HEAD_SIZE, "CONNECT request too large");
+        stream.read_exact(&mut byte).await?;
+        head.push(byte[0]);
+    }
+    Ok(String::from_utf8(head)?)
+}
+
+/// the value of the header `name` in a request head
+fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    head.split("\r\n").skip(1).find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.trim().eq_ignore_ascii_case(name).then(|| value.trim())
+    })
+}
+
+/// answer with the status and headers of `resp`, closing the connection
+async fn refuse(stream: &mut Stream, resp: Response) -> Result<()> {
+    let mut head = format!(
+        "HTTP/1.1 {} {}\r\n",
+        resp.status(),
+        resp.status().canonical_reason()
+    );
+    for (name, values) in resp.iter() {
+        head.push_str(&format!("{}: {}\r\n", name, values.last()));
+    }
+    head.push_str("Content-Length: 0\r\nConnection: close\r\n\r\n");
+    stream.write_all(head.as_bytes()).await?;
+    Ok(())
+}
+
+async fn reply(stream: &mut Stream, status: &str) -> Result<()> {
+    stream
+        .write_all(format!("HTTP/1.1 {}\r\n\r\n", status).as_bytes())
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,144 @@
+use anyhow::{anyhow, bail, Result};
+use http_types::StatusCode;
+use wasmi::{Config, Engine, Instance, Linker, Memory, Module, Store};
+
+use crate::config::Wasm;
+
+/// instructions a call may run, so a looping plugin can't hold a request
+const FUEL: u64 = 1_000_000_000;
+
+/// a plugin compiled to webassembly, it imports nothing, so it can't reach
+/// anything but its own memory, and exports:
+///
+/// - `memory`
+/// - `alloc(len: i32) -> i32`, a buffer of `len` bytes inputs are copied to
+/// - `filter(ptr: i32, len: i32) -> i32`, optional, given the upstream url,
+///   returns 0 to send the request, or the status answered instead
+/// - `rewrite(ptr: i32, len: i32) -> i64`, optional, given a rewritten body,
+///   returns `ptr << 32 | len` of the utf-8 replacement, or -1 to keep it
+///
+/// each call runs in a new instance
+pub struct Plugin {
+    config: Wasm,
+    engine: Engine,
+    module: Module,
+}
+
+impl std::fmt::Debug for Plugin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Plugin")
+            .field("config", &self.config)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Plugin {
+    pub fn load(config: &Wasm) -> Result<Plugin> {
+        let mut engine_config = Config::default();
+        engine_config.consume_fuel(true);
+        let engine = Engine::new(&engine_config);
+        let wasm = std::fs::read(&config.module)?;
+        let module = Module::new(&engine, wasm)
+            .map_err(|e| anyhow!("can not load wasm module {}: {}", config.module, e))?;
+        if module.imports().len() > 0 {
+            bail!("wasm module {} must not import anything", config.module);
+        }
+        Ok(Plugin {
+            config: config.clone(),
+            engine,
+            module,
+        })
+    }
+
+    pub fn applies(&self, host: &str) -> bool {
+        self.config.applies(host)
+    }
+
+    pub fn rewrites(&self) -> bool {
+        self.exports("rewrite")
+    }
+
+    /// the status answered instead of sending the request of `url`, if any
+    pub fn filter(&self, url: &str) -> Result<Option<StatusCode>> {
+        if !self.exports("filter") {
+            return Ok(None);
+        }
+        let (mut store, instance) = self.instantiate()?;
+        let (ptr, len) = self.write(&mut store, &instance, url.as_bytes())?;
+        let status = instance
+            .get_typed_func::<(i32, i32), i32>(&store, "filter")?
+            .call(&mut store, (ptr, len))
+            .map_err(|e| self.error(e))?;
+        match status {
+            0 => Ok(None),
+            status => u16::try_from(status)
+                .ok()
+                .and_then(|i| StatusCode::try_from(i).ok())
+                .map(Some)
+                .ok_or_else(|| anyhow!("invalid status {} of {}", status, self.config.module)),
+        }
+    }
+
+    /// the replacement of a rewritten `body`
+    pub fn rewrite(&self, body: String) -> Result<String> {
+        if !self.rewrites() {
+            return Ok(body);
+        }
+        let (mut store, instance) = self.instantiate()?;
+        let (ptr, len) = self.write(&mut store, &instance, body.as_bytes())?;
+        let result = instance
+            .get_typed_func::<(i32, i32), i64>(&store, "rewrite")?
+            .call(&mut store, (ptr, len))
+            .map_err(|e| self.error(e))?;
+        if result == -1 {
+            return Ok(body);
+        }
+        let (ptr, len) = ((result >> 32) as u32 as usize, result as u32 as usize);
+        let data = memory(&store, &instance)?.data(&store);
+        let replaced = data
+            .get(ptr..ptr + len)
+            .ok_or_else(|| anyhow!("{} returned a body out of its memory", self.config.module))?;
+        Ok(String::from_utf8(replaced.to_vec())?)
+    }
+
+    fn exports(&self, name: &str) -> bool {
+        self.module.exports().any(|i| i.name() == name)
+    }
+
+    fn instantiate(&self) -> Result<(Store<()>, Instance)> {
+        let mut store = Store::new(&self.engine, ());
+        store.set_fuel(FUEL)?;
+        let instance = Linker::new(&self.engine)
+            .instantiate_and_start(&mut store, &self.module)
+            .map_err(|e| self.error(e))?;
+        Ok((store, instance))
+    }
+
+    /// copy `bytes` to a buffer of the plugin
+    fn write(
+        &self,
+        store: &mut Store<()>,
+        instance: &Instance,
+        bytes: &[u8],
+    ) -> Result<(i32, i32)> {
+        let len = i32::try_from(bytes.len())?;
+        let ptr = instance
+            .get_typed_func::<i32, i32>(&*store, "alloc")?
+            .call(&mut *store, len)
+            .map_err(|e| self.error(e))?;
+        memory(store, instance)?
+            .write(store, ptr as u32 as usize, bytes)
+            .map_err(|e| self.error(e))?;
+        Ok((ptr, len))
+    }
+
+    fn error(&self, e: impl std::fmt::Display) -> anyhow::Error {
+        anyhow!("wasm plugin {} failed: {}", self.config.module, e)
+    }
+}
+
+fn memory(store: &Store<()>, instance: &Instance) -> Result<Memory> {
+    instance
+        .get_memory(store, "memory")
+        .ok_or_else(|| anyhow!("wasm module exports no memory"))
+}
@@ -0,0 +1,113 @@
+use std::ops::Range;
+
+use crate::{replace::Replacer, scan::find};
+
+/// where urls are in a kind of xml document, names are local ones, without
+/// a namespace prefix
+pub struct Urls {
+    /// elements whose text is a url
+    pub elements: &'static [&'static str],
+    pub attributes: &'static [&'static str],
+}
+
+/// MPEG-DASH manifests
+pub const DASH: Urls = Urls {
+    elements: &["BaseURL", "Location"],
+    attributes: &["media", "initialization", "sourceURL"],
+};
+
+/// RSS and Atom feeds, sitemaps and other xml documents
+pub const FEED: Urls = Urls {
+    elements: &["link", "guid", "comments", "url", "icon", "logo", "loc"],
+    attributes: &["href", "src", "url", "base"],
+};
+
+/// SVG images, `href` also matches `xlink:href`
+pub const SVG: Urls = Urls {
+    elements: &["style"],
+    attributes: &["href", "src"],
+};
+
+/// replace domains only where `urls` are in the document
+pub fn rewrite(xml: &str, replacer: &Replacer, urls: &Urls) -> String {
+    replacer.replace_ranges(xml, ranges(xml.as_bytes(), urls))
+}
+
+fn ranges(xml: &[u8], urls: &Urls) -> Vec<Range<usize>> {
+    let matches = |names: &[&str], name: &[u8]| {
+        let local = name.rsplit(|&i| i == b':').next().unwrap_or(name);
+        names
+            .iter()
+            .any(|i| i.as_bytes().eq_ignore_ascii_case(local))
+    };
+    let mut ranges = Vec::new();
+    // inside an element whose text is a url
+    let mut text = false;
+    let mut i = 0;
+    while let Some(open) = find(xml, i, b"<") {
+        if text {
+            ranges.push(i..open);
+        }
+        let rest = &xml[open..];
+        if rest.starts_with(b"<!--") {
+            i = find(xml, open + 4, b"-->").map_or(xml.len(), |end| end + 3);
+            continue;
+        }
+        if rest.starts_with(b"<![CDATA[") {
+            let end = find(xml, open + 9, b"]]>").unwrap_or(xml.len());
+            if text {
+                ranges.push(open + 9..end);
+            }
+            i = (end + 3).min(xml.len());
+            continue;
+        }
+        if rest.starts_with(b"<?") || rest.starts_with(b"<!") {
+            i = find(xml, open, b">").map_or(xml.len(), |end| end + 1);
+            continue;
+        }
+
+        let closing = rest.get(1) == Some(&b'/');
+        let mut j = open + 1 + closing as usize;
+        let start = j;
+        while j < xml.len() && !is_delimiter(xml[j]) {
+            j += 1;
+        }
+        let name = &xml[start..j];
+        let mut empty = false;
+        // attributes, until the end of the tag
+        while j < xml.len() {
+            match xml[j] {
+                b'>' => break,
+                b'/' => empty = true,
+                c if c.is_ascii_whitespace() => (),
+                _ => {
+                    let start = j;
+                    while j < xml.len() && !is_delimiter(xml[j]) && xml[j] != b'=' {
+                        j += 1;
+                    }
+                    let attribute = &xml[start..j];
+                    while j < xml.len() && (xml[j].is_ascii_whitespace() || xml[j] == b'=') {
+                        j += 1;
+                    }
+                    if let Some(&quote @ (b'"' | b'\'')) = xml.get(j) {
+                        let end = find(xml, j + 1, &[quote]).unwrap_or(xml.len());
+                        if matches(urls.attributes, attribute) {
+                            ranges.push(j + 1..end);
+                        }
+                        j = end;
+                    } else {
+                        continue;
+                    }
+                }
+            }
+            j += 1;
+        }
+        text = !closing && !empty && matches(urls.elements, name);
+        i = (j + 1).min(xml.len());
+    }
+    ranges
+}
+
+fn is_delimiter(c: u8) -> bool {
+    c.is_ascii_whitespace() || c == b'>' || c == b'/'
+}